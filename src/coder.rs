@@ -0,0 +1,285 @@
+use crate::error::{Result, ZparsError};
+
+/// Source of per-bit probabilities for the arithmetic coder.
+pub trait BitPredictor {
+    /// Probability that the next bit is 1, scaled to 15 bits (0..=32767).
+    fn predict(&mut self) -> u32;
+
+    /// Train on the bit that was actually coded.
+    fn update(&mut self, y: u32) -> Result<()>;
+}
+
+/// Byte input of the decoder: an in-memory slice or a streaming reader.
+pub trait ByteSource {
+    /// Next input byte; running out is a `Corrupt(what)` error.
+    fn next_byte(&mut self, what: &'static str) -> Result<u8>;
+}
+
+impl ByteSource for &[u8] {
+    fn next_byte(&mut self, what: &'static str) -> Result<u8> {
+        let (&b, rest) = self.split_first().ok_or(ZparsError::Corrupt(what))?;
+        *self = rest;
+        Ok(b)
+    }
+}
+
+/// ZPAQ Level 2 binary arithmetic decoder.
+///
+/// Modeled blocks keep a 32-bit `low..=high` range split by 16-bit
+/// probabilities; unmodeled blocks reuse `curr` as the remaining length of the
+/// current stored chunk.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    low: u32,
+    high: u32,
+    curr: u32,
+}
+
+impl Decoder {
+    pub fn new(modeled: bool) -> Self {
+        if modeled {
+            Self {
+                low: 1,
+                high: u32::MAX,
+                curr: 0,
+            }
+        } else {
+            Self {
+                low: 0,
+                high: 0,
+                curr: 0,
+            }
+        }
+    }
+
+    /// Decode one bit whose probability of being 1 is `p / 65536`.
+    pub fn decode<S: ByteSource>(&mut self, src: &mut S, p: u32) -> Result<u32> {
+        if self.curr < self.low || self.curr > self.high {
+            return Err(ZparsError::Corrupt("arithmetic decoder out of range"));
+        }
+        let range = u64::from(self.high - self.low);
+        let mid = self.low + ((range * u64::from(p)) >> 16) as u32;
+        let y = if self.curr <= mid {
+            self.high = mid;
+            1
+        } else {
+            self.low = mid + 1;
+            0
+        };
+        while (self.high ^ self.low) < 0x0100_0000 {
+            self.high = (self.high << 8) | 255;
+            self.low <<= 8;
+            self.low += u32::from(self.low == 0);
+            let c = src.next_byte("arithmetic coded payload")?;
+            self.curr = (self.curr << 8) | u32::from(c);
+        }
+        Ok(y)
+    }
+
+    /// Decode one byte of a modeled segment, or -1 at end of segment.
+    pub fn decompress_modeled<S: ByteSource, P: BitPredictor>(
+        &mut self,
+        src: &mut S,
+        pr: &mut P,
+    ) -> Result<i32> {
+        if self.curr == 0 {
+            for _ in 0..4 {
+                let c = src.next_byte("arithmetic coder start")?;
+                self.curr = (self.curr << 8) | u32::from(c);
+            }
+        }
+
+        if self.decode(src, 0)? == 1 {
+            if self.curr != 0 {
+                return Err(ZparsError::Corrupt("decoding end of stream"));
+            }
+            return Ok(-1);
+        }
+
+        let mut c = 1u32;
+        while c < 256 {
+            let p = pr.predict() * 2 + 1;
+            c = (c << 1) | self.decode(src, p)?;
+            pr.update(c & 1)?;
+        }
+        Ok((c - 256) as i32)
+    }
+
+    /// Decode one byte of a stored segment: big-endian length-prefixed chunks,
+    /// terminated by a zero length.
+    pub fn decompress_stored<S: ByteSource>(&mut self, src: &mut S) -> Result<i32> {
+        if self.curr == 0 {
+            for _ in 0..4 {
+                let c = src.next_byte("stored chunk length")?;
+                self.curr = (self.curr << 8) | u32::from(c);
+            }
+            if self.curr == 0 {
+                return Ok(-1);
+            }
+        }
+
+        self.curr -= 1;
+        let b = src.next_byte("compressed payload")?;
+        Ok(i32::from(b))
+    }
+}
+
+/// ZPAQ Level 2 binary arithmetic encoder, the mirror of `Decoder`.
+///
+/// Modeled blocks only; stored blocks bypass the coder entirely. The encoder
+/// state carries over between segments of a block.
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    low: u32,
+    high: u32,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self {
+            low: 1,
+            high: u32::MAX,
+        }
+    }
+
+    /// Encode bit `y` whose probability of being 1 is `p / 65536`.
+    pub fn encode(&mut self, out: &mut Vec<u8>, y: u32, p: u32) {
+        let range = u64::from(self.high - self.low);
+        let mid = self.low + ((range * u64::from(p)) >> 16) as u32;
+        if y == 1 {
+            self.high = mid;
+        } else {
+            self.low = mid + 1;
+        }
+        while (self.high ^ self.low) < 0x0100_0000 {
+            out.push((self.high >> 24) as u8);
+            self.high = (self.high << 8) | 255;
+            self.low <<= 8;
+            self.low += u32::from(self.low == 0);
+        }
+    }
+
+    /// Encode one byte of a modeled segment, or the end of segment for `None`.
+    ///
+    /// After the end of segment the caller writes four zero bytes, which the
+    /// decoder consumes as its final `curr`.
+    pub fn compress_modeled<P: BitPredictor>(
+        &mut self,
+        out: &mut Vec<u8>,
+        c: Option<u8>,
+        pr: &mut P,
+    ) -> Result<()> {
+        let Some(c) = c else {
+            self.encode(out, 1, 0);
+            return Ok(());
+        };
+        self.encode(out, 0, 0);
+        for i in (0..8).rev() {
+            let y = u32::from(c >> i) & 1;
+            let p = pr.predict() * 2 + 1;
+            self.encode(out, y, p);
+            pr.update(y)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Order-0 bitwise model with a simple adaptive probability per partial byte.
+    struct Order0 {
+        p: Vec<u32>,
+        c: usize,
+    }
+
+    impl Order0 {
+        fn new() -> Self {
+            Self {
+                p: vec![16384; 256],
+                c: 1,
+            }
+        }
+    }
+
+    impl BitPredictor for Order0 {
+        fn predict(&mut self) -> u32 {
+            self.p[self.c]
+        }
+
+        fn update(&mut self, y: u32) -> Result<()> {
+            let p = &mut self.p[self.c];
+            if y == 1 {
+                *p += (32768 - *p) >> 4;
+            } else {
+                *p -= *p >> 4;
+            }
+            self.c = self.c * 2 + y as usize;
+            if self.c >= 256 {
+                self.c = 1;
+            }
+            Ok(())
+        }
+    }
+
+    /// Code `input` as one modeled segment, including the EOS bit and the
+    /// four zero bytes that precede the end-of-segment marker.
+    pub(crate) fn compress_with<P: BitPredictor>(pr: &mut P, input: &[u8]) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        let mut out = Vec::new();
+        for c in input.iter().map(Some).chain([None]) {
+            enc.compress_modeled(&mut out, c.copied(), pr)
+                .expect("encode");
+        }
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        out
+    }
+
+    fn compress(input: &[u8]) -> Vec<u8> {
+        compress_with(&mut Order0::new(), input)
+    }
+
+    #[test]
+    fn modeled_roundtrip() {
+        let input = b"arithmetic coding arithmetic coding aaaaaaaaaaaaaaaaaaaaaaa\x00\xff";
+        let coded = compress(input);
+
+        let mut dec = Decoder::new(true);
+        let mut pr = Order0::new();
+        let mut src = coded.as_slice();
+        let mut restored = Vec::new();
+        loop {
+            let c = dec.decompress_modeled(&mut src, &mut pr).expect("decode");
+            if c < 0 {
+                break;
+            }
+            restored.push(c as u8);
+        }
+        assert_eq!(restored, input);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn truncated_modeled_payload_is_corrupt() {
+        let coded = compress(b"some bytes to truncate");
+        let cut = &coded[..coded.len() / 2];
+        let mut dec = Decoder::new(true);
+        let mut pr = Order0::new();
+        let mut src = cut;
+        let err = loop {
+            match dec.decompress_modeled(&mut src, &mut pr) {
+                Ok(c) if c >= 0 => continue,
+                Ok(_) => panic!("unexpected end of segment"),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(err, ZparsError::Corrupt(_)));
+    }
+}
@@ -0,0 +1,542 @@
+use crate::error::{Result, ZparsError};
+use crate::zpaq::{COMP_NAMES, COMP_SIZE, ZpaqBlockHeader};
+use crate::zpaql::mnemonic;
+use std::collections::HashMap;
+
+const JT: u8 = 39;
+const JF: u8 = 47;
+const JMP: u8 = 63;
+const LJ: u8 = 255;
+
+/// A model compiled from a zpaqd-style config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZpaqModel {
+    pub hh: u8,
+    pub hm: u8,
+    pub ph: u8,
+    pub pm: u8,
+    pub n_components: u8,
+    /// Component list bytes (without COMP END).
+    pub comp: Vec<u8>,
+    /// HCOMP bytecode including its trailing END byte.
+    pub hcomp: Vec<u8>,
+    /// PCOMP bytecode including END, or `None` for PASS.
+    pub pcomp: Option<Vec<u8>>,
+    /// External preprocessor command given between `pcomp` and `;`.
+    pub preprocessor: String,
+}
+
+impl ZpaqModel {
+    /// Size of the header after the 2-byte `hsize` field itself.
+    pub fn hsize(&self) -> usize {
+        5 + self.comp.len() + 1 + self.hcomp.len()
+    }
+
+    /// `hsize hh hm ph pm n COMP 0 HCOMP`, the bytes following `zPQ level 1`
+    /// in a block.
+    pub fn header_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.hsize() + 2);
+        out.extend_from_slice(&(self.hsize() as u16).to_le_bytes());
+        out.extend_from_slice(&[self.hh, self.hm, self.ph, self.pm, self.n_components]);
+        out.extend_from_slice(&self.comp);
+        out.push(0);
+        out.extend_from_slice(&self.hcomp);
+        out
+    }
+
+    /// Parsed-header view of the model, as `parse_block_header` would return
+    /// it for a block starting at offset 0.
+    pub fn block_header(&self) -> ZpaqBlockHeader {
+        let comp_bytes = self.comp.len() + 6;
+        ZpaqBlockHeader {
+            start_offset: 0,
+            level: 1,
+            zpaql_type: 1,
+            hsize: self.hsize() as u16,
+            hh: self.hh,
+            hm: self.hm,
+            ph: self.ph,
+            pm: self.pm,
+            n_components: self.n_components,
+            comp_bytes,
+            hcomp_bytes: self.hcomp.len(),
+            segment_offset: 0,
+            comp: self.comp.clone(),
+            hcomp: self.hcomp.clone(),
+        }
+    }
+
+    /// Postprocessor description coded at the start of a block's first
+    /// segment: `0` for PASS, or `1 len_lo len_hi PCOMP`.
+    pub fn postprocessor_prefix(&self) -> Vec<u8> {
+        match &self.pcomp {
+            None => vec![0],
+            Some(prog) => {
+                let mut out = vec![1];
+                out.extend_from_slice(&(prog.len() as u16).to_le_bytes());
+                out.extend_from_slice(prog);
+                out
+            }
+        }
+    }
+}
+
+/// Compile a config file with every `$N` argument set to 0.
+pub fn compile(src: &str) -> Result<ZpaqModel> {
+    compile_with_args(src, &[])
+}
+
+/// Compile a config file (`comp ... hcomp ... [pcomp cmd ; ...] end`).
+///
+/// `$N` and `$N+M` tokens take the value of `args[N - 1]` (plus `M`); missing
+/// arguments are 0, as in zpaqd. Comments are parenthesized and may nest.
+pub fn compile_with_args(src: &str, args: &[i64]) -> Result<ZpaqModel> {
+    let mut p = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+        args,
+        opcodes: (0..=255u8)
+            .filter_map(|op| mnemonic(op).map(|(name, operands)| (name, (op, operands))))
+            .collect(),
+    };
+
+    p.keyword("comp")?;
+    let hh = p.number(0, 255)? as u8;
+    let hm = p.number(0, 255)? as u8;
+    let ph = p.number(0, 255)? as u8;
+    let pm = p.number(0, 255)? as u8;
+    let n_components = p.number(0, 255)? as u8;
+
+    let mut comp = Vec::new();
+    for i in 0..i64::from(n_components) {
+        p.number(i, i)?;
+        let tok = p.next()?;
+        let Some(t) = COMP_NAMES
+            .iter()
+            .position(|&name| !name.is_empty() && name == tok.text)
+        else {
+            return Err(tok.error("unknown component type"));
+        };
+        comp.push(t as u8);
+        for _ in 1..COMP_SIZE[t] {
+            comp.push(p.number(0, 255)? as u8);
+        }
+    }
+
+    p.keyword("hcomp")?;
+    let (hcomp, term) = p.program(&["pcomp", "post", "end"])?;
+
+    let mut preprocessor = String::new();
+    let pcomp = match term.text.as_str() {
+        "post" => {
+            p.number(0, 0)?;
+            p.keyword("end")?;
+            None
+        }
+        "pcomp" => {
+            let mut words = Vec::new();
+            loop {
+                let tok = p.next()?;
+                if tok.text == ";" {
+                    break;
+                }
+                if let Some(word) = tok.text.strip_suffix(';') {
+                    words.push(word.to_string());
+                    break;
+                }
+                words.push(tok.text);
+            }
+            preprocessor = words.join(" ");
+            let (prog, _) = p.program(&["end"])?;
+            if prog.len() > usize::from(u16::MAX) {
+                return Err(term.error("PCOMP too large"));
+            }
+            Some(prog)
+        }
+        _ => None,
+    };
+
+    let model = ZpaqModel {
+        hh,
+        hm,
+        ph,
+        pm,
+        n_components,
+        comp,
+        hcomp,
+        pcomp,
+        preprocessor,
+    };
+    if model.hsize() > usize::from(u16::MAX) {
+        return Err(term.error("model header too large"));
+    }
+    Ok(model)
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+impl Token {
+    fn error(&self, msg: &'static str) -> ZparsError {
+        ZparsError::Config {
+            line: self.line,
+            msg,
+            token: self.text.clone(),
+        }
+    }
+}
+
+/// Split `src` into lowercase whitespace-separated tokens, dropping comments.
+fn tokenize(src: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut line = 1usize;
+    let mut depth = 0usize;
+    let mut comment_line = 0usize;
+    let mut cur = String::new();
+
+    for ch in src.chars() {
+        if depth > 0 || ch == '(' {
+            match ch {
+                '(' => {
+                    if depth == 0 {
+                        comment_line = line;
+                    }
+                    depth += 1;
+                }
+                ')' => depth -= 1,
+                _ => {}
+            }
+        } else if ch == ')' {
+            return Err(ZparsError::Config {
+                line,
+                msg: "unbalanced comment",
+                token: ")".into(),
+            });
+        } else if !ch.is_whitespace() {
+            cur.extend(ch.to_lowercase());
+            continue;
+        }
+
+        if !cur.is_empty() {
+            tokens.push(Token {
+                text: std::mem::take(&mut cur),
+                line,
+            });
+        }
+        if ch == '\n' {
+            line += 1;
+        }
+    }
+
+    if depth > 0 {
+        return Err(ZparsError::Config {
+            line: comment_line,
+            msg: "unterminated comment",
+            token: "(".into(),
+        });
+    }
+    if !cur.is_empty() {
+        tokens.push(Token { text: cur, line });
+    }
+    Ok(tokens)
+}
+
+/// Pending forward jump of an `if`/`else`; `long` ones are patched as `LJ`.
+struct Branch {
+    at: usize,
+    long: bool,
+    tok: Token,
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    args: &'a [i64],
+    opcodes: HashMap<String, (u8, usize)>,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Result<Token> {
+        let Some(tok) = self.tokens.get(self.pos) else {
+            return Err(ZparsError::Config {
+                line: self.tokens.last().map_or(1, |t| t.line),
+                msg: "unexpected end of config",
+                token: "EOF".into(),
+            });
+        };
+        self.pos += 1;
+        Ok(tok.clone())
+    }
+
+    fn keyword(&mut self, kw: &str) -> Result<()> {
+        let tok = self.next()?;
+        if tok.text != kw {
+            return Err(tok.error(match kw {
+                "comp" => "expected comp",
+                "hcomp" => "expected hcomp",
+                _ => "expected end",
+            }));
+        }
+        Ok(())
+    }
+
+    fn number(&mut self, lo: i64, hi: i64) -> Result<i64> {
+        let tok = self.next()?;
+        let v = if let Some(arg) = tok.text.strip_prefix('$') {
+            let (n, add) = arg.split_once('+').unwrap_or((arg, "0"));
+            match (n.parse::<usize>(), add.parse::<i64>()) {
+                (Ok(n @ 1..=9), Ok(add)) => self.args.get(n - 1).copied().unwrap_or(0) + add,
+                _ => return Err(tok.error("invalid argument reference")),
+            }
+        } else {
+            tok.text
+                .parse::<i64>()
+                .map_err(|_| tok.error("expected a number"))?
+        };
+        if v < lo || v > hi {
+            return Err(tok.error("number out of range"));
+        }
+        Ok(v)
+    }
+
+    /// Compile instructions until one of `terminators`, returning the program
+    /// with END appended and the terminating token.
+    fn program(&mut self, terminators: &[&str]) -> Result<(Vec<u8>, Token)> {
+        let mut prog = Vec::new();
+        let mut branches: Vec<Branch> = Vec::new();
+        let mut loops: Vec<(usize, Token)> = Vec::new();
+        let mut jumps: Vec<(Token, i64)> = Vec::new();
+
+        let term = loop {
+            let tok = self.next()?;
+            if terminators.contains(&tok.text.as_str()) {
+                break tok;
+            }
+            match tok.text.as_str() {
+                "if" | "ifnot" => {
+                    prog.extend_from_slice(&[if tok.text == "if" { JF } else { JT }, 0]);
+                    branches.push(Branch {
+                        at: prog.len() - 1,
+                        long: false,
+                        tok,
+                    });
+                }
+                "ifl" | "ifnotl" => {
+                    let op = if tok.text == "ifl" { JT } else { JF };
+                    prog.extend_from_slice(&[op, 3, LJ, 0, 0]);
+                    branches.push(Branch {
+                        at: prog.len() - 2,
+                        long: true,
+                        tok,
+                    });
+                }
+                "else" | "elsel" => {
+                    let Some(open) = branches.pop() else {
+                        return Err(tok.error("else without if"));
+                    };
+                    let long = tok.text == "elsel";
+                    if long {
+                        prog.extend_from_slice(&[LJ, 0, 0]);
+                    } else {
+                        prog.extend_from_slice(&[JMP, 0]);
+                    }
+                    let at = if long { prog.len() - 2 } else { prog.len() - 1 };
+                    patch(&mut prog, &open)?;
+                    branches.push(Branch { at, long, tok });
+                }
+                "endif" => {
+                    let Some(open) = branches.pop() else {
+                        return Err(tok.error("endif without if"));
+                    };
+                    patch(&mut prog, &open)?;
+                }
+                "do" => loops.push((prog.len(), tok)),
+                "while" | "until" | "forever" => {
+                    let Some((start, _)) = loops.pop() else {
+                        return Err(tok.error("loop end without do"));
+                    };
+                    let back = start as i64 - (prog.len() as i64 + 2);
+                    if back >= -128 {
+                        let op = match tok.text.as_str() {
+                            "while" => JT,
+                            "until" => JF,
+                            _ => JMP,
+                        };
+                        prog.extend_from_slice(&[op, back as i8 as u8]);
+                    } else {
+                        match tok.text.as_str() {
+                            "while" => prog.extend_from_slice(&[JF, 3]),
+                            "until" => prog.extend_from_slice(&[JT, 3]),
+                            _ => {}
+                        }
+                        prog.extend_from_slice(&[LJ, start as u8, (start >> 8) as u8]);
+                    }
+                }
+                name => {
+                    let Some(&(op, operands)) = self.opcodes.get(name) else {
+                        return Err(tok.error("unknown opcode"));
+                    };
+                    prog.push(op);
+                    match op {
+                        JT | JF | JMP => {
+                            let n = self.number(-128, 127)?;
+                            prog.push(n as i8 as u8);
+                            jumps.push((tok, prog.len() as i64 + n));
+                        }
+                        LJ => {
+                            let n = self.number(0, 65535)?;
+                            prog.extend_from_slice(&(n as u16).to_le_bytes());
+                            jumps.push((tok, n));
+                        }
+                        _ if operands == 1 => prog.push(self.number(0, 255)? as u8),
+                        _ => {}
+                    }
+                }
+            }
+        };
+
+        if let Some(open) = branches.pop() {
+            return Err(open.tok.error("if without endif"));
+        }
+        if let Some((_, tok)) = loops.pop() {
+            return Err(tok.error("do without while, until or forever"));
+        }
+        prog.push(0);
+        if let Some((tok, _)) = jumps
+            .iter()
+            .find(|(_, target)| *target < 0 || *target >= prog.len() as i64)
+        {
+            return Err(tok.error("jump target outside program"));
+        }
+        Ok((prog, term))
+    }
+}
+
+/// Point a pending `if`/`else` jump at the end of `prog`.
+fn patch(prog: &mut [u8], branch: &Branch) -> Result<()> {
+    let target = prog.len();
+    if branch.long {
+        if target > usize::from(u16::MAX) {
+            return Err(branch.tok.error("jump target outside program"));
+        }
+        prog[branch.at] = target as u8;
+        prog[branch.at + 1] = (target >> 8) as u8;
+    } else {
+        let offset = target - (branch.at + 1);
+        if offset > 127 {
+            return Err(branch.tok.error("jump too far, use ifl/elsel"));
+        }
+        prog[branch.at] = offset as u8;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zpaql::{Zpaql, disassemble_config};
+
+    const MID_CFG: &str = "\
+comp 3 3 0 0 8 (hh hm ph pm n)
+  0 icm 5        (order 0...5 chain)
+  1 isse 13 0
+  2 isse $1+17 1
+  3 isse 18 2
+  4 isse 18 3
+  5 isse 19 4
+  6 match 22 24  (order 7)
+  7 mix 16 0 7 24 255  (order 1)
+hcomp
+  c++ *c=a b=c a=0 (save in rotating buffer M)
+  d= 1 hash *d=a   (orders 1...5 for isse)
+  b-- d++ hash *d=a
+  b-- d++ hash *d=a
+  b-- d++ hash *d=a
+  b-- d++ hash *d=a
+  b-- d++ hash b-- hash *d=a (order 7 for match)
+  d++ a=*c a<<= 8 *d=a       (order 1 for mix)
+  halt
+post
+  0
+end
+";
+
+    fn run(hcomp_body: &str, input: u32) -> Vec<u8> {
+        let model = compile(&format!("comp 0 0 0 0 0 hcomp {hcomp_body} end")).expect("compile");
+        let mut z = Zpaql::new(&model.hcomp, 0, 0).expect("vm");
+        z.run(input).expect("run");
+        z.take_output()
+    }
+
+    #[test]
+    fn compiles_mid_config() {
+        let model = compile(&MID_CFG.replace("$1+17", "17")).expect("compile");
+        assert_eq!((model.hh, model.hm, model.n_components), (3, 3, 8));
+        assert_eq!(&model.comp[..5], &[3, 5, 8, 13, 0]);
+        assert_eq!(&model.hcomp[..4], &[17, 104, 74, 4]);
+        assert_eq!(model.hcomp.last(), Some(&0));
+        assert_eq!(model.pcomp, None);
+        assert_eq!(compile_with_args(MID_CFG, &[0]).expect("args"), model);
+
+        let header = model.header_bytes();
+        assert_eq!(
+            usize::from(u16::from_le_bytes([header[0], header[1]])),
+            model.hsize()
+        );
+        assert_eq!(header.len(), model.hsize() + 2);
+
+        // The disassembly is itself a valid config for the same model.
+        let listing = disassemble_config(&model.block_header(), None);
+        assert_eq!(compile(&listing).expect("recompile"), model);
+    }
+
+    #[test]
+    fn compiles_pcomp_section() {
+        let src = "comp 0 0 0 0 0 hcomp halt pcomp ./pre 1 ; a> 255 ifnot out endif halt end";
+        let model = compile(src).expect("compile");
+        assert_eq!(model.preprocessor, "./pre 1");
+        let pcomp = model.pcomp.clone().expect("pcomp");
+        assert_eq!(pcomp, [239, 255, 39, 1, 57, 56, 0]);
+        assert_eq!(&model.postprocessor_prefix()[..3], &[1, 7, 0]);
+    }
+
+    #[test]
+    fn structured_control_flow() {
+        assert_eq!(run("a> 10 if a= 1 else a= 2 endif out halt", 20), [1]);
+        assert_eq!(run("a> 10 if a= 1 else a= 2 endif out halt", 5), [2]);
+        assert_eq!(run("a> 10 ifl a= 1 elsel a= 2 endif out halt", 5), [2]);
+        assert_eq!(run("c= 3 do out c-- a=c a> 0 while halt", 9), [9, 2, 1]);
+        assert_eq!(run("do out a-- a== 0 until halt", 3), [3, 2, 1]);
+
+        // A loop body longer than a short jump falls back to LJ.
+        let long_body = "a++ ".repeat(200);
+        let out = run(&format!("do {long_body} b++ a< 250 while a=b out halt"), 0);
+        assert_eq!(out, [2]);
+    }
+
+    #[test]
+    fn reports_line_numbers() {
+        let err = compile("comp 0 0 0 0 0\nhcomp\n  a++\n  a=+ 3\nend").unwrap_err();
+        assert!(matches!(err, ZparsError::Config { line: 4, .. }), "{err}");
+        assert!(err.to_string().contains("unknown opcode: a=+"));
+
+        let err = compile("comp 0 0 0 0 0\nhcomp\n  jmp 20\n  halt\nend").unwrap_err();
+        assert!(matches!(err, ZparsError::Config { line: 3, .. }), "{err}");
+
+        let err = compile("comp 0 0 0 0 0\nhcomp\n  jt 300\nend").unwrap_err();
+        assert!(err.to_string().contains("number out of range"));
+
+        let err = compile("comp 0 0 0 0 0 hcomp\n(open\n\nendif end").unwrap_err();
+        assert!(matches!(err, ZparsError::Config { line: 2, .. }), "{err}");
+
+        let err = compile("comp 0 0 0 0 1\n 0 lstm 3\nhcomp end").unwrap_err();
+        assert!(err.to_string().contains("unknown component type"));
+
+        let body = "a++ ".repeat(200);
+        let err = compile(&format!("comp 0 0 0 0 0\nhcomp\nif {body} endif end")).unwrap_err();
+        assert!(matches!(err, ZparsError::Config { line: 3, .. }), "{err}");
+    }
+}
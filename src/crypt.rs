@@ -0,0 +1,292 @@
+use crate::error::{Result, ZparsError};
+use crate::zpaq::MAGIC_16;
+use aes::Aes256;
+use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use zeroize::Zeroizing;
+
+/// Length of the random salt that starts an encrypted archive.
+pub const SALT_LEN: usize = 32;
+
+pub(crate) type Aes256Ctr = ctr::Ctr64BE<Aes256>;
+
+/// zpaq's archive key: scrypt (N = 16384, r = 8, p = 1) of the password's
+/// SHA-256, salted with the archive's salt. The key and the intermediate
+/// hash are zeroized when dropped.
+pub fn derive_key(password: &[u8], salt: &[u8; SALT_LEN]) -> Zeroizing<[u8; 32]> {
+    let params = scrypt::Params::new(14, 8, 1, 32).expect("valid scrypt parameters");
+    let hashed = Zeroizing::new(<[u8; 32]>::from(Sha256::digest(password)));
+    let mut key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(hashed.as_ref(), salt, &params, key.as_mut()).expect("32-byte scrypt output");
+    key
+}
+
+/// AES-256 in CTR mode as zpaq applies it to the whole archive file. The
+/// counter block is the first 8 salt bytes followed by the big-endian index
+/// of the 16-byte block, so the keystream position is the file offset
+/// (including the salt). The cipher starts at `offset`.
+pub(crate) fn cipher(key: &[u8; 32], salt: &[u8; SALT_LEN], offset: u64) -> Aes256Ctr {
+    let mut iv = [0u8; 16];
+    iv[..8].copy_from_slice(&salt[..8]);
+    let mut cipher = Aes256Ctr::new(key.into(), &iv.into());
+    cipher.seek(offset);
+    cipher
+}
+
+/// Reads an encrypted archive as the plain archive that follows its salt.
+pub struct DecryptReader<R> {
+    inner: R,
+    cipher: Aes256Ctr,
+    /// Bytes decrypted to check the password and not yet returned.
+    head: Vec<u8>,
+    head_pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Read the salt, derive the key and check it: like zpaq, the first
+    /// bytes must decrypt to a block tag, or the password is wrong.
+    pub fn new(mut inner: R, password: &[u8]) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        inner.read_exact(&mut salt).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                ZparsError::InvalidFormat("encrypted archive is shorter than its salt")
+            }
+            _ => e.into(),
+        })?;
+        let mut cipher = cipher(&derive_key(password, &salt), &salt, SALT_LEN as u64);
+
+        let mut head = Vec::with_capacity(4);
+        inner.by_ref().take(4).read_to_end(&mut head)?;
+        cipher.apply_keystream(&mut head);
+        match head.len() {
+            // Only a salt: an empty archive.
+            0 => {}
+            4 if head == MAGIC_16[..4] || (head[..3] == *b"zPQ" && matches!(head[3], 1 | 2)) => {}
+            4 => return Err(ZparsError::WrongPassword),
+            _ => return Err(ZparsError::Corrupt("truncated encrypted archive")),
+        }
+        Ok(Self {
+            inner,
+            cipher,
+            head,
+            head_pos: 0,
+        })
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.head_pos < self.head.len() {
+            let n = buf.len().min(self.head.len() - self.head_pos);
+            buf[..n].copy_from_slice(&self.head[self.head_pos..self.head_pos + n]);
+            self.head_pos += n;
+            return Ok(n);
+        }
+        let n = self.inner.read(buf)?;
+        self.cipher.apply_keystream(&mut buf[..n]);
+        Ok(n)
+    }
+}
+
+/// A random salt for a new encrypted archive.
+pub fn new_salt() -> Result<[u8; SALT_LEN]> {
+    let mut salt = [0u8; SALT_LEN];
+    getrandom::fill(&mut salt).map_err(io::Error::other)?;
+    Ok(salt)
+}
+
+/// Salt of the index-only archive of an archive salted with `salt`, and the
+/// reverse: zpaq flips the first byte with `0x4d`, so the two never share a
+/// keystream.
+pub fn index_salt(salt: &[u8; SALT_LEN]) -> [u8; SALT_LEN] {
+    let mut out = *salt;
+    out[0] ^= 0x4d;
+    out
+}
+
+/// Writes an encrypted archive. Bytes written at file offset `n` are
+/// encrypted with the keystream at `n`, so the writer can seek back and
+/// overwrite, as `TransactionWriter` does to commit a transaction.
+pub struct EncryptWriter<W> {
+    inner: W,
+    cipher: Aes256Ctr,
+    /// Archive offset of `inner`'s position 0; nonzero for a later part of
+    /// a multi-part archive.
+    base: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write + Seek> EncryptWriter<W> {
+    /// Start a new encrypted archive at the beginning of `inner`: write a
+    /// random salt, then encrypt everything written after it.
+    pub fn create(inner: W, password: &[u8]) -> Result<Self> {
+        Self::create_with_salt(inner, password, &new_salt()?)
+    }
+
+    /// `create` with a given salt, such as an index's salt derived from its
+    /// archive's (see `index_salt`).
+    pub fn create_with_salt(mut inner: W, password: &[u8], salt: &[u8; SALT_LEN]) -> Result<Self> {
+        inner.seek(SeekFrom::Start(0))?;
+        inner.write_all(salt)?;
+        Self::append(inner, password, salt)
+    }
+
+    /// Continue an encrypted archive whose first bytes are `salt`, at the
+    /// current position of `inner`. The password is not checked here; open
+    /// the archive with `DecryptReader` first.
+    pub fn append(inner: W, password: &[u8], salt: &[u8; SALT_LEN]) -> Result<Self> {
+        Self::append_part(inner, password, salt, 0)
+    }
+
+    /// Like `append`, for a part of a multi-part archive. The keystream runs
+    /// over the parts as one stream, so `inner` starts at archive offset
+    /// `base`, the total size of the earlier parts.
+    pub fn append_part(
+        mut inner: W,
+        password: &[u8],
+        salt: &[u8; SALT_LEN],
+        base: u64,
+    ) -> Result<Self> {
+        let at = base + inner.stream_position()?;
+        if at < SALT_LEN as u64 {
+            return Err(ZparsError::InvalidOption(
+                "encrypted data cannot overlap the salt",
+            ));
+        }
+        Ok(Self {
+            inner,
+            cipher: cipher(&derive_key(password, salt), salt, at),
+            base,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.clear();
+        self.buf.extend_from_slice(data);
+        self.cipher.apply_keystream(&mut self.buf);
+        // All or nothing, so the keystream stays in step with the file.
+        self.inner.write_all(&self.buf)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for EncryptWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let at = self.inner.seek(pos)?;
+        self.cipher.seek(self.base + at);
+        Ok(at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::one_block;
+    use crate::zpaq::{extract_bytes, inspect_bytes};
+    use aes::cipher::{BlockEncrypt, KeyInit};
+
+    fn encrypt(plain: &[u8], password: &[u8], salt: [u8; SALT_LEN]) -> Vec<u8> {
+        let mut out = salt.to_vec();
+        let mut data = plain.to_vec();
+        cipher(&derive_key(password, &salt), &salt, SALT_LEN as u64).apply_keystream(&mut data);
+        out.extend(data);
+        out
+    }
+
+    #[test]
+    fn keystream_counts_file_offsets_after_the_salt_prefix() {
+        let salt: [u8; SALT_LEN] = std::array::from_fn(|i| i as u8 * 7);
+        let key = [0x42u8; 32];
+        let mut stream = [0u8; 20];
+        cipher(&key, &salt, 40).apply_keystream(&mut stream);
+
+        // Offset 40 is byte 8 of counter block 2.
+        let aes = Aes256::new(&key.into());
+        let mut expected = Vec::new();
+        for i in 2u64..=3 {
+            let mut block = [0u8; 16];
+            block[..8].copy_from_slice(&salt[..8]);
+            block[8..].copy_from_slice(&i.to_be_bytes());
+            let mut block = block.into();
+            aes.encrypt_block(&mut block);
+            expected.extend_from_slice(&block);
+        }
+        assert_eq!(stream, expected[8..28]);
+    }
+
+    #[test]
+    fn decrypts_archives_and_rejects_wrong_passwords() {
+        let plain = one_block(0, "secret.txt", b"encrypted payload");
+        let archive = encrypt(&plain, b"hunter2", [9; SALT_LEN]);
+        // Without the key no block tag can be found.
+        assert!(inspect_bytes(&archive).expect("scan").is_empty());
+
+        let mut decrypted = Vec::new();
+        DecryptReader::new(archive.as_slice(), b"hunter2")
+            .expect("password")
+            .read_to_end(&mut decrypted)
+            .expect("read");
+        assert_eq!(decrypted, plain);
+        assert_eq!(
+            extract_bytes(&decrypted).expect("extract")[0].data,
+            b"encrypted payload"
+        );
+
+        assert!(matches!(
+            DecryptReader::new(archive.as_slice(), b"hunter3"),
+            Err(ZparsError::WrongPassword)
+        ));
+        assert!(matches!(
+            DecryptReader::new(&archive[..10], b"hunter2"),
+            Err(ZparsError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn encrypted_transactions_append_and_commit() {
+        use crate::journal::{Journal, JournalEntry};
+        use crate::transaction::TransactionWriter;
+        use std::io::Cursor;
+
+        let entry = |name: &str, ptrs| JournalEntry {
+            name: name.into(),
+            date: 20240101000000,
+            attr: Vec::new(),
+            ptrs,
+        };
+        let w = EncryptWriter::create(Cursor::new(Vec::new()), b"pw").expect("create");
+        let mut tx = TransactionWriter::new(w, 20240102000000, 1, 1).expect("tx");
+        let ptrs = tx.add_file_data(&b"first"[..]).expect("data");
+        tx.add_entry(&entry("a.txt", ptrs)).expect("entry");
+        let mut archive = tx.finish().expect("finish").into_inner().into_inner();
+        let salt: [u8; SALT_LEN] = archive[..SALT_LEN].try_into().unwrap();
+
+        let mut out = Cursor::new(std::mem::take(&mut archive));
+        out.seek(SeekFrom::End(0)).expect("seek");
+        let w = EncryptWriter::append(out, b"pw", &salt).expect("append");
+        let mut tx = TransactionWriter::new(w, 20240103000000, 2, 0).expect("tx");
+        let ptrs = tx.add_file_data(&b"second"[..]).expect("data");
+        tx.add_entry(&entry("b.txt", ptrs)).expect("entry");
+        let archive = tx.finish().expect("finish").into_inner().into_inner();
+
+        assert!(inspect_bytes(&archive).expect("scan").is_empty());
+        let reader = DecryptReader::new(archive.as_slice(), b"pw").expect("password");
+        let journal = Journal::read_with_data(reader).expect("journal");
+        assert_eq!(journal.versions.len(), 2);
+        let files = journal.files();
+        assert_eq!(journal.file_data(files["a.txt"]).expect("a"), b"first");
+        assert_eq!(journal.file_data(files["b.txt"]).expect("b"), b"second");
+    }
+}
@@ -18,4 +18,23 @@ pub enum ZparsError {
 
     #[error("invalid option: {0}")]
     InvalidOption(&'static str),
+
+    #[error("unsupported feature: {0}")]
+    Unsupported(&'static str),
+
+    #[error("wrong password: the archive does not decrypt to ZPAQ data")]
+    WrongPassword,
+
+    #[error("SHA-1 mismatch in block {block_index}, segment {filename:?}")]
+    ChecksumMismatch {
+        block_index: usize,
+        filename: String,
+    },
+
+    #[error("config line {line}: {msg}: {token}")]
+    Config {
+        line: usize,
+        msg: &'static str,
+        token: String,
+    },
 }
@@ -0,0 +1,1144 @@
+use crate::error::{Result, ZparsError};
+use crate::reader::ZpaqReader;
+use crate::zpaq::ZpaqExtractedSegment;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io::Read;
+use std::ops::Range;
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+/// File tree, versions and fragment table of a journaling (zpaq 7) archive.
+///
+/// Each transaction is a `c` block (its header) followed by `d` blocks of
+/// fragment data, `h` blocks of fragment hashes and sizes, and `i` blocks
+/// updating the file index. Every block holds one segment named
+/// `jDC<date><type><number>`. Segments with other names (streaming-format
+/// files) are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    pub versions: Vec<JournalVersion>,
+    /// Fragment table indexed by fragment ID; ID 0 is unused.
+    pub fragments: Vec<JournalFragment>,
+    /// Block index of a trailing transaction whose `c` block was never
+    /// completed (csize -1). It and everything after it are ignored, and a
+    /// new transaction should overwrite it.
+    pub incomplete: Option<usize>,
+}
+
+/// One transaction (an `add` of the reference tool).
+#[derive(Debug, Clone)]
+pub struct JournalVersion {
+    /// Transaction date as decimal `YYYYMMDDHHMMSS` (UTC).
+    pub date: u64,
+    /// Block index of the `c` block.
+    pub block_index: usize,
+    /// ID of the first fragment added by this transaction.
+    pub first_fragment: u32,
+    /// Size of the transaction's `d` blocks, which follow its `c` block.
+    pub csize: u64,
+    /// Index updates in archive order; deletions have date 0.
+    pub entries: Vec<JournalEntry>,
+}
+
+/// A file index record from an `i` block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub name: String,
+    /// Modification date as decimal `YYYYMMDDHHMMSS`, or 0 for a deletion.
+    pub date: u64,
+    /// Raw attribute bytes (`u` or `w` followed by the mode or attributes).
+    pub attr: Vec<u8>,
+    /// Fragment IDs whose data, concatenated, is the file's content.
+    pub ptrs: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JournalFragment {
+    pub sha1: [u8; 20],
+    pub size: u32,
+    /// First fragment ID of the `d` block holding this fragment, the number
+    /// in its `jDC...d` name.
+    pub data_block: u32,
+    /// Decoded data, when read with `Journal::read_with_data`.
+    pub data: Option<Vec<u8>>,
+}
+
+/// Rebuilds the files of a journal's current tree (`Journal::assemble_files`)
+/// from its `d` blocks as they are decoded, one block at a time.
+///
+/// A file is ready once all its fragments are loaded, and a fragment's data
+/// is dropped once every file that needs it has been taken, so only the
+/// fragments of unfinished files are held.
+#[derive(Debug, Default)]
+pub struct JournalFiles {
+    /// Files in name order; `None` once taken.
+    files: Vec<Option<(String, JournalEntry)>>,
+    /// Number of distinct fragments each file still waits for.
+    missing: Vec<usize>,
+    fragments: HashMap<u32, NeededFragment>,
+    /// Files waiting for each fragment that is not loaded yet.
+    waiting: HashMap<u32, Vec<usize>>,
+    ready: VecDeque<usize>,
+}
+
+#[derive(Debug)]
+struct NeededFragment {
+    sha1: [u8; 20],
+    size: u32,
+    data: Option<Vec<u8>>,
+    /// Files not taken yet that use the fragment.
+    files: usize,
+}
+
+/// Version selector for rolling a journal back, like zpaq's `-until`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalUntil {
+    /// Keep the first N transactions.
+    Version(usize),
+    /// Keep transactions dated at or before this `YYYYMMDDHHMMSS` date.
+    Date(u64),
+}
+
+impl FromStr for JournalUntil {
+    type Err = ZparsError;
+
+    /// A plain number below 8 digits is a version; anything else is a date
+    /// `YYYY-MM-DD HH:MM:SS` with optional separators, where a missing time
+    /// or part of it means the end of that day, hour or minute.
+    fn from_str(s: &str) -> Result<Self> {
+        let digits: String = s.chars().filter(char::is_ascii_digit).collect();
+        let invalid = s
+            .chars()
+            .any(|c| !c.is_ascii_digit() && !matches!(c, '-' | ':' | ' ' | 'T' | '/'));
+        if digits.is_empty() || invalid {
+            return Err(ZparsError::InvalidOption(
+                "--until expects a version number or a date YYYY-MM-DD HH:MM:SS",
+            ));
+        }
+        if digits.len() < 8 && digits.len() == s.len() {
+            return Ok(Self::Version(digits.parse().expect("digits")));
+        }
+        let padded = match digits.len() {
+            8 => digits + "235959",
+            10 => digits + "5959",
+            12 => digits + "59",
+            14 => digits,
+            _ => {
+                return Err(ZparsError::InvalidOption(
+                    "--until date must have 8, 10, 12 or 14 digits",
+                ));
+            }
+        };
+        Ok(Self::Date(padded.parse().expect("digits")))
+    }
+}
+
+/// Format a decimal `YYYYMMDDHHMMSS` date as `YYYY-MM-DD HH:MM:SS`.
+pub fn format_date(date: u64) -> String {
+    let d = format!("{date:014}");
+    format!(
+        "{}-{}-{} {}:{}:{}",
+        &d[..4],
+        &d[4..6],
+        &d[6..8],
+        &d[8..10],
+        &d[10..12],
+        &d[12..14]
+    )
+}
+
+/// Decimal `YYYYMMDDHHMMSS` date of a Unix timestamp (UTC).
+pub fn date_from_unix(secs: i64) -> u64 {
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u64);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = (yoe + era * 400 + i64::from(month <= 2)) as u64;
+    ((year * 100 + month) * 100 + day) * 1_000_000
+        + rem / 3600 * 10_000
+        + rem % 3600 / 60 * 100
+        + rem % 60
+}
+
+/// Unix timestamp (UTC) of a decimal `YYYYMMDDHHMMSS` date.
+pub fn unix_from_date(date: u64) -> i64 {
+    let part = |div: u64, modulo: u64| ((date / div) % modulo) as i64;
+    let (year, month, day) = (
+        date as i64 / 10_000_000_000,
+        part(100_000_000, 100),
+        part(1_000_000, 100),
+    );
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    days * 86400 + part(10_000, 100) * 3600 + part(100, 100) * 60 + part(1, 100)
+}
+
+impl JournalFragment {
+    /// Whether the fragment's data is loaded and matches its size and hash.
+    pub fn is_valid(&self) -> bool {
+        self.data.as_deref().is_some_and(|data| {
+            data.len() == self.size as usize && Sha1::digest(data)[..] == self.sha1
+        })
+    }
+}
+
+impl JournalEntry {
+    pub fn is_deleted(&self) -> bool {
+        self.date == 0
+    }
+
+    /// Whether the entry names a directory (`name/`).
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+/// Parsed `jDC<date:14><type><number:10>` segment name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct JdcName {
+    pub date: u64,
+    pub kind: u8,
+    pub number: u32,
+}
+
+impl JdcName {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        let b = name.as_bytes();
+        if b.len() != 28 || !b.starts_with(b"jDC") || !b"cdhi".contains(&b[17]) {
+            return None;
+        }
+        let digits = |s: &[u8]| -> Option<u64> {
+            s.iter().try_fold(0u64, |n, &c| {
+                c.is_ascii_digit().then(|| n * 10 + u64::from(c - b'0'))
+            })
+        };
+        Some(Self {
+            date: digits(&b[3..17])?,
+            kind: b[17],
+            number: u32::try_from(digits(&b[18..])?).ok()?,
+        })
+    }
+}
+
+impl Journal {
+    /// Read versions, fragment hashes and the file index, skipping `d` blocks.
+    pub fn read<R: Read>(inner: R) -> Result<Self> {
+        Self::read_blocks(inner, false)
+    }
+
+    /// Like `read`, also decoding fragment data and checking it against the
+    /// `h` blocks.
+    pub fn read_with_data<R: Read>(inner: R) -> Result<Self> {
+        Self::read_blocks(inner, true)
+    }
+
+    /// Build the journal from already extracted segments, with fragment data.
+    pub fn from_segments(segments: &[ZpaqExtractedSegment]) -> Result<Self> {
+        let mut b = Builder::default();
+        for seg in segments {
+            if let Some(name) = JdcName::parse(&seg.filename)
+                && !b.add(seg.block_index, &seg.filename, name, &seg.data)?
+            {
+                break;
+            }
+        }
+        b.finish()
+    }
+
+    /// Rebuild what the segments recovered by `salvage_*` allow: segments
+    /// that do not parse are skipped, and fragment data that does not match
+    /// its hash is dropped, so `file_data` fails only for files that lost
+    /// data.
+    pub fn from_salvaged(segments: &[ZpaqExtractedSegment]) -> Self {
+        let mut b = Builder::default();
+        for seg in segments {
+            let Some(name) = JdcName::parse(&seg.filename) else {
+                continue;
+            };
+            match b.add(seg.block_index, &seg.filename, name, &seg.data) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => warn!(
+                    block = seg.block_index,
+                    segment = %seg.filename,
+                    error = %e,
+                    "skipping unusable journal segment"
+                ),
+            }
+        }
+        let mut journal = b.journal;
+        for f in &mut journal.fragments {
+            if f.data.is_some() && !f.is_valid() {
+                f.data = None;
+            }
+        }
+        journal
+    }
+
+    fn read_blocks<R: Read>(inner: R, with_data: bool) -> Result<Self> {
+        let mut reader = ZpaqReader::new(inner);
+        let mut b = Builder::default();
+        // Offset where the `d` blocks of the transaction whose `c` block was
+        // just read end.
+        let mut data_end = None;
+        'blocks: while reader.next_block()?.is_some() {
+            let mut c_block = false;
+            while let Some(seg) = reader.next_segment()? {
+                let Some(name) = JdcName::parse(&seg.filename) else {
+                    continue;
+                };
+                if name.kind == b'd' && !with_data {
+                    // Like zpaq, pass over the d blocks by their size instead
+                    // of decoding them or scanning them for tags.
+                    let at = reader.offset() as u64;
+                    match data_end.take() {
+                        Some(end) if end > at => {
+                            reader.skip_input(end - at)?;
+                            continue 'blocks;
+                        }
+                        _ => continue,
+                    }
+                }
+                let mut data = Vec::new();
+                reader.read_segment(&mut data)?;
+                if !b.add(seg.block_index, &seg.filename, name, &data)? {
+                    break 'blocks;
+                }
+                c_block = name.kind == b'c';
+            }
+            data_end = c_block
+                .then(|| b.journal.versions.last())
+                .flatten()
+                .map(|v| reader.offset() as u64 + v.csize);
+        }
+        b.finish()
+    }
+
+    /// File index after applying the first `versions` transactions.
+    pub fn files_at(&self, versions: usize) -> BTreeMap<&str, &JournalEntry> {
+        let mut files = BTreeMap::new();
+        for e in self.versions.iter().take(versions).flat_map(|v| &v.entries) {
+            if e.is_deleted() {
+                files.remove(e.name.as_str());
+            } else {
+                files.insert(e.name.as_str(), e);
+            }
+        }
+        files
+    }
+
+    /// Number of transactions selected by `until`.
+    pub fn versions_until(&self, until: JournalUntil) -> usize {
+        match until {
+            JournalUntil::Version(n) => n.min(self.versions.len()),
+            JournalUntil::Date(date) => self.versions.iter().take_while(|v| v.date <= date).count(),
+        }
+    }
+
+    /// Drop the transactions after those selected by `until`, so `files`
+    /// returns the file tree as of that version.
+    pub fn roll_back(&mut self, until: JournalUntil) {
+        let n = self.versions_until(until);
+        self.versions.truncate(n);
+    }
+
+    /// Fragment IDs referenced by the current file tree.
+    pub fn needed_fragments(&self) -> BTreeSet<u32> {
+        self.files()
+            .values()
+            .flat_map(|e| e.ptrs.iter().copied())
+            .collect()
+    }
+
+    /// Decode the data of fragments `ids` from the archive, reading only the
+    /// `d` blocks that hold them. Each loaded fragment is checked against its
+    /// hash.
+    pub fn load_fragments<R: Read>(&mut self, inner: R, ids: &BTreeSet<u32>) -> Result<()> {
+        let fragments = &mut self.fragments;
+        read_data_blocks(
+            &self.versions,
+            inner,
+            blocks_of(fragments, ids),
+            |seg| -> Result<()> {
+                let (first, sizes) = parse_data_trailer(&seg.data)?;
+                let mut at = 0usize;
+                for (i, &size) in sizes.iter().enumerate() {
+                    let end = at + size as usize;
+                    let id = first + i;
+                    if ids.contains(&(id as u32)) && id < fragments.len() {
+                        let f = &mut fragments[id];
+                        f.data = Some(seg.data[at..end].to_vec());
+                        if !f.is_valid() {
+                            return Err(ZparsError::ChecksumMismatch {
+                                block_index: seg.block_index,
+                                filename: seg.filename,
+                            });
+                        }
+                    }
+                    at = end;
+                }
+                Ok(())
+            },
+        )?;
+        debug!(fragments = ids.len(), "loaded fragment data");
+        Ok(())
+    }
+
+    /// Decode the `d` blocks holding fragments `ids` one at a time, passing
+    /// each to `f`, and pass over the other `d` blocks without decoding them.
+    pub fn read_data_blocks<R, E, F>(
+        &self,
+        inner: R,
+        ids: &BTreeSet<u32>,
+        f: F,
+    ) -> std::result::Result<(), E>
+    where
+        R: Read,
+        E: From<ZparsError>,
+        F: FnMut(ZpaqExtractedSegment) -> std::result::Result<(), E>,
+    {
+        read_data_blocks(&self.versions, inner, blocks_of(&self.fragments, ids), f)
+    }
+
+    /// Start rebuilding the current file tree from `d` blocks; see
+    /// `JournalFiles`.
+    pub fn assemble_files(&self) -> JournalFiles {
+        let mut out = JournalFiles::default();
+        for (name, entry) in self.files() {
+            let index = out.files.len();
+            let ids: BTreeSet<u32> = entry.ptrs.iter().copied().collect();
+            for &id in &ids {
+                let (sha1, size) = self
+                    .fragments
+                    .get(id as usize)
+                    .map_or(([0; 20], 0), |f| (f.sha1, f.size));
+                let needed = out.fragments.entry(id).or_insert(NeededFragment {
+                    sha1,
+                    size,
+                    data: None,
+                    files: 0,
+                });
+                needed.files += 1;
+                out.waiting.entry(id).or_default().push(index);
+            }
+            if ids.is_empty() {
+                out.ready.push_back(index);
+            }
+            out.missing.push(ids.len());
+            out.files.push(Some((name.to_string(), entry.clone())));
+        }
+        out
+    }
+
+    /// File index after the last transaction.
+    pub fn files(&self) -> BTreeMap<&str, &JournalEntry> {
+        self.files_at(self.versions.len())
+    }
+
+    /// Size of `entry`'s content according to the fragment table.
+    pub fn file_size(&self, entry: &JournalEntry) -> u64 {
+        entry
+            .ptrs
+            .iter()
+            .filter_map(|&id| self.fragments.get(id as usize))
+            .map(|f| u64::from(f.size))
+            .sum()
+    }
+
+    /// Concatenate `entry`'s fragments; requires a journal read with data.
+    pub fn file_data(&self, entry: &JournalEntry) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(self.file_size(entry) as usize);
+        for &id in &entry.ptrs {
+            let data = self
+                .fragments
+                .get(id as usize)
+                .and_then(|f| f.data.as_deref())
+                .ok_or(ZparsError::Corrupt("fragment data missing from archive"))?;
+            out.extend_from_slice(data);
+        }
+        Ok(out)
+    }
+}
+
+impl JournalFiles {
+    /// Load the fragments files are waiting for from a decoded `d` segment,
+    /// checking each against its hash. Other segments are ignored.
+    pub fn add_segment(&mut self, seg: &ZpaqExtractedSegment) -> Result<()> {
+        if JdcName::parse(&seg.filename).is_none_or(|name| name.kind != b'd') {
+            return Ok(());
+        }
+        let (first, sizes) = parse_data_trailer(&seg.data)?;
+        let mut at = 0usize;
+        for (i, &size) in sizes.iter().enumerate() {
+            let end = at + size as usize;
+            let id = u32::try_from(first + i).unwrap_or(0);
+            if let Some(files) = self.waiting.remove(&id) {
+                let data = &seg.data[at..end];
+                let f = self.fragments.get_mut(&id).expect("needed fragment");
+                if data.len() != f.size as usize || Sha1::digest(data)[..] != f.sha1 {
+                    return Err(ZparsError::ChecksumMismatch {
+                        block_index: seg.block_index,
+                        filename: seg.filename.clone(),
+                    });
+                }
+                f.data = Some(data.to_vec());
+                for file in files {
+                    self.missing[file] -= 1;
+                    if self.missing[file] == 0 {
+                        self.ready.push_back(file);
+                    }
+                }
+            }
+            at = end;
+        }
+        Ok(())
+    }
+
+    /// Take the next file whose fragments are all loaded, with its content.
+    /// Directories and empty files are ready from the start.
+    pub fn next_ready(&mut self) -> Option<(String, JournalEntry, Vec<u8>)> {
+        let index = self.ready.pop_front()?;
+        let (name, entry) = self.files[index].take()?;
+        let mut data = Vec::new();
+        for id in &entry.ptrs {
+            data.extend_from_slice(self.fragments[id].data.as_deref().unwrap_or_default());
+        }
+        for id in entry.ptrs.iter().collect::<BTreeSet<_>>() {
+            let f = self.fragments.get_mut(id).expect("needed fragment");
+            f.files -= 1;
+            if f.files == 0 {
+                self.fragments.remove(id);
+            }
+        }
+        Some((name, entry, data))
+    }
+
+    /// Names of the files not taken yet, in particular those still missing
+    /// fragment data.
+    pub fn remaining(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().flatten().map(|(name, _)| name.as_str())
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    journal: Journal,
+    /// `d` segments to check against the hash table: block, name, first ID, count.
+    data_blocks: Vec<(usize, String, usize, usize)>,
+}
+
+impl Builder {
+    /// Apply one `jDC` segment. Returns false at an incomplete transaction,
+    /// which ends the usable part of the archive.
+    fn add(
+        &mut self,
+        block_index: usize,
+        filename: &str,
+        name: JdcName,
+        data: &[u8],
+    ) -> Result<bool> {
+        if name.kind == b'c' {
+            let csize = parse_csize(data)?;
+            if csize == u64::MAX {
+                warn!(block = block_index, "ignoring incomplete transaction");
+                self.journal.incomplete = Some(block_index);
+                return Ok(false);
+            }
+            self.journal.versions.push(JournalVersion {
+                date: name.date,
+                block_index,
+                first_fragment: name.number,
+                csize,
+                entries: Vec::new(),
+            });
+            return Ok(true);
+        }
+        let Some(version) = self.journal.versions.last_mut() else {
+            return Err(ZparsError::Corrupt(
+                "journaling block before first transaction header",
+            ));
+        };
+
+        match name.kind {
+            b'd' => {
+                let (first, sizes) = parse_data_trailer(data)?;
+                if first != name.number as usize {
+                    return Err(ZparsError::Corrupt("data block fragment ID mismatch"));
+                }
+                let frags = self.fragments_mut(first, sizes.len())?;
+                let mut at = 0usize;
+                for (f, size) in frags.iter_mut().zip(&sizes) {
+                    let end = at + *size as usize;
+                    f.data = Some(data[at..end].to_vec());
+                    f.data_block = name.number;
+                    at = end;
+                }
+                self.data_blocks
+                    .push((block_index, filename.to_string(), first, sizes.len()));
+            }
+            b'h' => {
+                let table = data
+                    .get(4..)
+                    .filter(|t| t.len() % 24 == 0)
+                    .ok_or(ZparsError::Corrupt("malformed fragment hash table"))?;
+                let first = name.number as usize;
+                let frags = self.fragments_mut(first, table.len() / 24)?;
+                for (f, rec) in frags.iter_mut().zip(table.chunks_exact(24)) {
+                    f.sha1.copy_from_slice(&rec[..20]);
+                    f.size = u32::from_le_bytes(rec[20..].try_into().expect("4 bytes"));
+                    f.data_block = name.number;
+                }
+            }
+            _ => version.entries.extend(parse_index(data)?),
+        }
+        Ok(true)
+    }
+
+    /// Fragments `first..first + n`, added to the table if new. A block's
+    /// fragments follow those already known; a `first` past them is corrupt
+    /// and rejected before anything is allocated for it.
+    fn fragments_mut(&mut self, first: usize, n: usize) -> Result<&mut [JournalFragment]> {
+        let frags = &mut self.journal.fragments;
+        if first == 0 || first > frags.len().max(1) {
+            return Err(ZparsError::Corrupt("fragment ID out of range"));
+        }
+        if frags.len() < first + n {
+            frags.resize(first + n, JournalFragment::default());
+        }
+        Ok(&mut frags[first..first + n])
+    }
+
+    fn finish(self) -> Result<Journal> {
+        let journal = self.journal;
+        for (block_index, filename, first, n) in self.data_blocks {
+            for f in &journal.fragments[first..first + n] {
+                if !f.is_valid() {
+                    return Err(ZparsError::ChecksumMismatch {
+                        block_index,
+                        filename,
+                    });
+                }
+            }
+        }
+        let known = journal.fragments.len();
+        let entries = journal.versions.iter().flat_map(|v| &v.entries);
+        if entries
+            .flat_map(|e| &e.ptrs)
+            .any(|&id| id == 0 || id as usize >= known)
+        {
+            return Err(ZparsError::Corrupt(
+                "file index references an unknown fragment",
+            ));
+        }
+        debug!(
+            versions = journal.versions.len(),
+            fragments = known.saturating_sub(1),
+            "read journaling archive"
+        );
+        Ok(journal)
+    }
+}
+
+/// Numbers of the `d` blocks holding fragments `ids`.
+fn blocks_of(fragments: &[JournalFragment], ids: &BTreeSet<u32>) -> BTreeSet<u32> {
+    ids.iter()
+        .filter_map(|&id| fragments.get(id as usize))
+        .map(|f| f.data_block)
+        .collect()
+}
+
+/// Decode the `d` blocks numbered `blocks` in archive order, passing each to
+/// `f`. Transactions holding none of them are skipped by their csize, and so
+/// are a transaction's remaining `d` blocks once none of them is wanted;
+/// other blocks are decoded to their end rather than scanned for tags.
+fn read_data_blocks<R, E, F>(
+    versions: &[JournalVersion],
+    inner: R,
+    mut blocks: BTreeSet<u32>,
+    mut f: F,
+) -> std::result::Result<(), E>
+where
+    R: Read,
+    E: From<ZparsError>,
+    F: FnMut(ZpaqExtractedSegment) -> std::result::Result<(), E>,
+{
+    let mut reader = ZpaqReader::new(inner);
+    // End offset and fragment IDs of the current transaction's d blocks.
+    let mut transaction: Option<(u64, Range<u32>)> = None;
+    while !blocks.is_empty() && reader.next_block()?.is_some() {
+        let Some(seg) = reader.next_segment()? else {
+            continue;
+        };
+        match JdcName::parse(&seg.filename) {
+            Some(name) if name.kind == b'c' => {
+                let mut data = Vec::new();
+                reader.read_segment(&mut data)?;
+                let csize = parse_csize(&data)?;
+                if csize == u64::MAX {
+                    break;
+                }
+                while reader.next_segment()?.is_some() {}
+                let next = versions
+                    .iter()
+                    .map(|v| v.first_fragment)
+                    .find(|&first| first > name.number)
+                    .unwrap_or(u32::MAX);
+                transaction = Some((reader.offset() as u64 + csize, name.number..next));
+            }
+            Some(name) if name.kind == b'd' && blocks.remove(&name.number) => {
+                let mut data = Vec::new();
+                let sha1 = reader.read_segment(&mut data)?;
+                while reader.next_segment()?.is_some() {}
+                f(ZpaqExtractedSegment {
+                    block_index: seg.block_index,
+                    filename: seg.filename,
+                    comment: seg.comment,
+                    data,
+                    sha1,
+                })?;
+            }
+            _ => while reader.next_segment()?.is_some() {},
+        }
+        // Pass over the rest of the transaction's d blocks once none of them
+        // is needed.
+        if let Some((end, ref numbers)) = transaction
+            && blocks.range(numbers.clone()).next().is_none()
+        {
+            let at = reader.offset() as u64;
+            if end > at {
+                reader.skip_input(end - at)?;
+            }
+            transaction = None;
+        }
+    }
+    if !blocks.is_empty() {
+        debug!(missing = blocks.len(), "data blocks not found in archive");
+    }
+    Ok(())
+}
+
+/// The `c` block's csize: the size of the transaction's `d` blocks, or
+/// `u64::MAX` while it is incomplete.
+fn parse_csize(data: &[u8]) -> Result<u64> {
+    data.get(..8)
+        .map(|b| u64::from_le_bytes(b.try_into().expect("8 bytes")))
+        .ok_or(ZparsError::Corrupt("transaction header too short"))
+}
+
+/// Split a `d` block's trailer: fragment sizes, then first ID and count.
+fn parse_data_trailer(data: &[u8]) -> Result<(usize, Vec<u32>)> {
+    let bad = ZparsError::Corrupt("malformed data block trailer");
+    let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().expect("4 bytes"));
+    if data.len() < 8 {
+        return Err(bad);
+    }
+    let first = u32_at(data.len() - 8) as usize;
+    let n = u32_at(data.len() - 4) as usize;
+    let table = n
+        .checked_mul(4)
+        .and_then(|t| (data.len() - 8).checked_sub(t))
+        .ok_or(bad)?;
+    let sizes: Vec<u32> = (0..n).map(|i| u32_at(table + i * 4)).collect();
+    if sizes.iter().map(|&s| s as usize).sum::<usize>() != table {
+        return Err(ZparsError::Corrupt("data block size mismatch"));
+    }
+    Ok((first, sizes))
+}
+
+/// Append `entry` as an `i` block record, the inverse of `parse_index`.
+pub(crate) fn encode_entry(entry: &JournalEntry, out: &mut Vec<u8>) {
+    out.extend_from_slice(&entry.date.to_le_bytes());
+    out.extend_from_slice(entry.name.as_bytes());
+    out.push(0);
+    if !entry.is_deleted() {
+        out.extend_from_slice(&(entry.attr.len() as u32).to_le_bytes());
+        out.extend_from_slice(&entry.attr);
+        out.extend_from_slice(&(entry.ptrs.len() as u32).to_le_bytes());
+        for p in &entry.ptrs {
+            out.extend_from_slice(&p.to_le_bytes());
+        }
+    }
+}
+
+/// Parse `i` block records: `date[8] name\0`, then for live files
+/// `na[4] attr[na] ni[4] ptr[ni][4]`.
+fn parse_index(mut data: &[u8]) -> Result<Vec<JournalEntry>> {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+        if data.len() < n {
+            return Err(ZparsError::Corrupt("truncated index record"));
+        }
+        let (head, tail) = data.split_at(n);
+        *data = tail;
+        Ok(head)
+    }
+    fn take_u32(data: &mut &[u8]) -> Result<usize> {
+        Ok(u32::from_le_bytes(take(data, 4)?.try_into().expect("4 bytes")) as usize)
+    }
+
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let date = u64::from_le_bytes(take(&mut data, 8)?.try_into().expect("8 bytes"));
+        let len = data
+            .iter()
+            .position(|&c| c == 0)
+            .ok_or(ZparsError::Corrupt("unterminated index filename"))?;
+        let name = String::from_utf8_lossy(take(&mut data, len)?).into_owned();
+        take(&mut data, 1)?;
+        let mut entry = JournalEntry {
+            name,
+            date,
+            attr: Vec::new(),
+            ptrs: Vec::new(),
+        };
+        if date != 0 {
+            let na = take_u32(&mut data)?;
+            entry.attr = take(&mut data, na)?.to_vec();
+            let ni = take_u32(&mut data)?;
+            for _ in 0..ni {
+                entry.ptrs.push(take_u32(&mut data)? as u32);
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::writer::ZpaqWriter;
+    use crate::writer::tests::{one_block, write_block};
+    use crate::zpaq::extract_bytes;
+
+    pub(crate) fn jdc(date: u64, kind: char, number: usize) -> String {
+        format!("jDC{date:014}{kind}{number:010}")
+    }
+
+    /// Append one stored transaction adding `frags` (IDs from `first`) and
+    /// the index records `files` (`date` 0 deletes).
+    pub(crate) fn transaction(
+        w: &mut ZpaqWriter<Vec<u8>>,
+        date: u64,
+        first: usize,
+        frags: &[&[u8]],
+        files: &[(&str, u64, &[u32])],
+    ) {
+        if frags.is_empty() {
+            write_block(w, 0, &jdc(date, 'c', first), &0u64.to_le_bytes());
+        } else {
+            let mut d = frags.concat();
+            for f in frags {
+                d.extend_from_slice(&(f.len() as u32).to_le_bytes());
+            }
+            d.extend_from_slice(&(first as u32).to_le_bytes());
+            d.extend_from_slice(&(frags.len() as u32).to_le_bytes());
+            let bsize = one_block(0, &jdc(date, 'd', first), &d).len();
+            let mut h = (bsize as u32).to_le_bytes().to_vec();
+            for f in frags {
+                h.extend_from_slice(&Sha1::digest(f));
+                h.extend_from_slice(&(f.len() as u32).to_le_bytes());
+            }
+            write_block(w, 0, &jdc(date, 'c', first), &(bsize as u64).to_le_bytes());
+            write_block(w, 0, &jdc(date, 'd', first), &d);
+            write_block(w, 0, &jdc(date, 'h', first), &h);
+        }
+        let mut i = Vec::new();
+        for (name, fdate, ptrs) in files {
+            let entry = JournalEntry {
+                name: name.to_string(),
+                date: *fdate,
+                attr: b"u\xa4\x81".to_vec(),
+                ptrs: ptrs.to_vec(),
+            };
+            encode_entry(&entry, &mut i);
+        }
+        write_block(w, 0, &jdc(date, 'i', 1), &i);
+    }
+
+    pub(crate) fn two_versions() -> Vec<u8> {
+        let mut w = ZpaqWriter::new(Vec::new());
+        transaction(
+            &mut w,
+            20240101120000,
+            1,
+            &[b"alpha ", b"beta"],
+            &[
+                ("a.txt", 20231231000000, &[1]),
+                ("dir/b.txt", 20231231000001, &[2]),
+            ],
+        );
+        transaction(
+            &mut w,
+            20240202120000,
+            3,
+            &[b"gamma "],
+            &[("a.txt", 20240201000000, &[3, 2]), ("dir/b.txt", 0, &[])],
+        );
+        w.finish().expect("finish")
+    }
+
+    #[test]
+    fn parses_jdc_names() {
+        let name = JdcName::parse("jDC20240101120000d0000000042").expect("jdc");
+        assert_eq!(
+            (name.date, name.kind, name.number),
+            (20240101120000, b'd', 42)
+        );
+        assert!(JdcName::parse("jDC20240101120000x0000000042").is_none());
+        assert!(JdcName::parse("jDC2024010112000d0000000042").is_none());
+        assert!(JdcName::parse("notes.txt").is_none());
+    }
+
+    #[test]
+    fn rebuilds_versions_and_file_tree() {
+        let archive = two_versions();
+        let journal = Journal::read_with_data(archive.as_slice()).expect("journal");
+        assert_eq!(journal.versions.len(), 2);
+        assert_eq!(journal.versions[1].date, 20240202120000);
+        assert_eq!(journal.fragments.len(), 4);
+
+        let v1 = journal.files_at(1);
+        assert_eq!(
+            v1.keys().copied().collect::<Vec<_>>(),
+            ["a.txt", "dir/b.txt"]
+        );
+        assert_eq!(journal.file_data(v1["a.txt"]).expect("data"), b"alpha ");
+
+        let files = journal.files();
+        assert_eq!(files.keys().copied().collect::<Vec<_>>(), ["a.txt"]);
+        let a = files["a.txt"];
+        assert_eq!(journal.file_data(a).expect("data"), b"gamma beta");
+        assert_eq!(journal.file_size(a), 10);
+
+        // Metadata-only reads skip the d blocks but keep sizes and hashes.
+        let meta = Journal::read(archive.as_slice()).expect("metadata");
+        assert_eq!(meta.file_size(meta.files()["a.txt"]), 10);
+        assert!(meta.fragments.iter().all(|f| f.data.is_none()));
+        assert!(meta.file_data(meta.files()["a.txt"]).is_err());
+
+        let segments = extract_bytes(&archive).expect("segments");
+        let again = Journal::from_segments(&segments).expect("from segments");
+        assert_eq!(again.fragments, journal.fragments);
+    }
+
+    #[test]
+    fn skips_archives_stored_in_data_blocks() {
+        let inner = two_versions();
+        let mut w = ZpaqWriter::new(Vec::new());
+        transaction(
+            &mut w,
+            20240303120000,
+            1,
+            &[&inner],
+            &[("inner.zpaq", 20240303000000, &[1])],
+        );
+        let outer = w.finish().expect("finish");
+
+        // The inner archive's blocks are data of the outer d block, not
+        // transactions of the outer archive.
+        let mut journal = Journal::read(outer.as_slice()).expect("metadata");
+        assert_eq!(journal.versions.len(), 1);
+        assert_eq!(journal.fragments.len(), 2);
+        let files = journal.files();
+        assert_eq!(files.keys().copied().collect::<Vec<_>>(), ["inner.zpaq"]);
+
+        let ids = journal.needed_fragments();
+        journal
+            .load_fragments(outer.as_slice(), &ids)
+            .expect("load");
+        assert_eq!(
+            journal
+                .file_data(journal.files()["inner.zpaq"])
+                .expect("data"),
+            inner
+        );
+
+        let full = Journal::read_with_data(outer.as_slice()).expect("journal");
+        assert_eq!(full.versions.len(), 1);
+        assert_eq!(
+            full.file_data(full.files()["inner.zpaq"]).expect("data"),
+            inner
+        );
+    }
+
+    #[test]
+    fn rejects_fragment_ids_past_the_table() {
+        let mut h = 0u32.to_le_bytes().to_vec();
+        h.extend_from_slice(&Sha1::digest(b"x"));
+        h.extend_from_slice(&1u32.to_le_bytes());
+        for number in [0, 2, u32::MAX as usize] {
+            let mut w = ZpaqWriter::new(Vec::new());
+            write_block(&mut w, 0, &jdc(20240101120000, 'c', 1), &0u64.to_le_bytes());
+            write_block(&mut w, 0, &jdc(20240101120000, 'h', number), &h);
+            let archive = w.finish().expect("finish");
+            assert!(matches!(
+                Journal::read(archive.as_slice()),
+                Err(ZparsError::Corrupt("fragment ID out of range"))
+            ));
+        }
+    }
+
+    #[test]
+    fn stops_at_incomplete_transaction() {
+        let mut archive = two_versions();
+        let mut w = ZpaqWriter::new(Vec::new());
+        write_block(
+            &mut w,
+            0,
+            &jdc(20240303120000, 'c', 4),
+            &u64::MAX.to_le_bytes(),
+        );
+        write_block(&mut w, 0, &jdc(20240303120000, 'i', 1), b"junk");
+        archive.extend(w.finish().expect("finish"));
+
+        let journal = Journal::read(archive.as_slice()).expect("journal");
+        assert_eq!(journal.versions.len(), 2);
+        assert_eq!(journal.incomplete, Some(8));
+    }
+
+    #[test]
+    fn detects_fragment_hash_mismatch() {
+        let mut segments = extract_bytes(&two_versions()).expect("segments");
+        segments[1].data[0] = b'A';
+        assert!(matches!(
+            Journal::from_segments(&segments),
+            Err(ZparsError::ChecksumMismatch { block_index: 1, ref filename })
+                if filename == &jdc(20240101120000, 'd', 1)
+        ));
+    }
+
+    #[test]
+    fn parses_until_versions_and_dates() {
+        let until = |s: &str| s.parse::<JournalUntil>();
+        assert_eq!(until("3").expect("version"), JournalUntil::Version(3));
+        assert_eq!(
+            until("2024-02-01").expect("day"),
+            JournalUntil::Date(20240201235959)
+        );
+        assert_eq!(
+            until("2024-02-02 12:00").expect("minute"),
+            JournalUntil::Date(20240202120059)
+        );
+        assert_eq!(
+            until("20240101120000").expect("packed"),
+            JournalUntil::Date(20240101120000)
+        );
+        assert!(until("yesterday").is_err());
+        assert!(until("2024-1").is_err());
+    }
+
+    #[test]
+    fn rolls_back_and_loads_only_needed_fragments() {
+        let archive = two_versions();
+        let mut journal = Journal::read(archive.as_slice()).expect("journal");
+        assert_eq!(
+            journal.versions_until(JournalUntil::Date(20240201000000)),
+            1
+        );
+        assert_eq!(journal.versions_until(JournalUntil::Version(9)), 2);
+
+        journal.roll_back(JournalUntil::Version(1));
+        let needed = journal.needed_fragments();
+        assert_eq!(needed, BTreeSet::from([1, 2]));
+        journal
+            .load_fragments(archive.as_slice(), &needed)
+            .expect("load");
+        assert!(journal.fragments[3].data.is_none());
+
+        let files = journal.files();
+        assert_eq!(journal.file_data(files["a.txt"]).expect("a"), b"alpha ");
+        assert_eq!(journal.file_data(files["dir/b.txt"]).expect("b"), b"beta");
+    }
+
+    #[test]
+    fn assembles_files_one_data_block_at_a_time() {
+        let mut w = ZpaqWriter::new(Vec::new());
+        transaction(
+            &mut w,
+            20240101120000,
+            1,
+            &[b"one ", b"shared"],
+            &[
+                ("a.txt", 20231231000000, &[1, 2]),
+                ("dir/", 20231231000000, &[]),
+            ],
+        );
+        transaction(
+            &mut w,
+            20240102120000,
+            3,
+            &[b"two "],
+            &[("b.txt", 20240101000000, &[3, 2, 2])],
+        );
+        let archive = w.finish().expect("finish");
+        let journal = Journal::read(archive.as_slice()).expect("journal");
+        let data_segments: Vec<_> = extract_bytes(&archive)
+            .expect("segments")
+            .into_iter()
+            .filter(|s| s.filename.as_bytes()[17] == b'd')
+            .collect();
+
+        let mut files = journal.assemble_files();
+        assert_eq!(files.next_ready().map(|f| f.0).as_deref(), Some("dir/"));
+        assert!(files.next_ready().is_none());
+
+        files.add_segment(&data_segments[0]).expect("first block");
+        let (name, _, data) = files.next_ready().expect("a.txt");
+        assert_eq!(
+            (name.as_str(), data.as_slice()),
+            ("a.txt", &b"one shared"[..])
+        );
+        assert!(files.next_ready().is_none());
+        // Only the fragment b.txt still needs is kept.
+        let held: Vec<_> = files
+            .fragments
+            .iter()
+            .filter(|(_, f)| f.data.is_some())
+            .map(|(&id, _)| id)
+            .collect();
+        assert_eq!(held, [2]);
+        assert_eq!(files.remaining().collect::<Vec<_>>(), ["b.txt"]);
+
+        files.add_segment(&data_segments[1]).expect("second block");
+        let (name, _, data) = files.next_ready().expect("b.txt");
+        assert_eq!(
+            (name.as_str(), data.as_slice()),
+            ("b.txt", &b"two sharedshared"[..])
+        );
+        assert!(files.fragments.is_empty());
+        assert_eq!(files.remaining().count(), 0);
+
+        // Data blocks are decoded only for the fragments the files need.
+        let mut files = journal.assemble_files();
+        let mut blocks = Vec::new();
+        journal
+            .read_data_blocks(archive.as_slice(), &BTreeSet::from([3]), |seg| {
+                blocks.push(seg.filename.clone());
+                files.add_segment(&seg)
+            })
+            .expect("read");
+        assert_eq!(blocks, [jdc(20240102120000, 'd', 3)]);
+    }
+
+    #[test]
+    fn converts_unix_time_to_dates() {
+        assert_eq!(date_from_unix(0), 19700101000000);
+        assert_eq!(date_from_unix(951_827_696), 20000229123456);
+        assert_eq!(date_from_unix(-1), 19691231235959);
+        for secs in [0, 951_827_696, 1_709_210_096, 4_102_444_799] {
+            assert_eq!(unix_from_date(date_from_unix(secs)), secs);
+        }
+    }
+}
@@ -1,13 +1,60 @@
 pub mod codec;
+pub mod coder;
+pub mod compiler;
+pub mod crypt;
 pub mod error;
+pub mod journal;
+pub mod methods;
+pub mod parallel;
+pub mod parts;
+pub mod predictor;
+mod preprocess;
+pub mod reader;
+pub mod salvage;
+pub mod statetable;
+pub mod transaction;
+pub mod writer;
 pub mod zpaq;
+pub mod zpaql;
 
 pub use codec::{CompressionOptions, DecompressionOptions, compress, decompress};
+pub use compiler::{
+    ZpaqModel, compile as compile_zpaql, compile_with_args as compile_zpaql_with_args,
+};
+pub use crypt::{
+    DecryptReader, EncryptWriter, SALT_LEN, derive_key as derive_zpaq_key, index_salt, new_salt,
+};
 pub use error::{Result, ZparsError};
+pub use journal::{
+    Journal, JournalEntry, JournalFiles, JournalFragment, JournalUntil, JournalVersion,
+    date_from_unix as journal_date_from_unix, format_date as format_journal_date,
+    unix_from_date as journal_unix_from_date,
+};
+pub use methods::{MAX_METHOD as MAX_ZPAQ_METHOD, Method as ZpaqMethod};
+pub use parallel::{
+    extract_blocks_threaded as extract_zpaq_blocks_threaded,
+    extract_reader_threaded as extract_zpaq_reader_threaded,
+};
+pub use parts::{PartsReader, is_multi_part, part_path, part_paths};
+pub use reader::{
+    Blocks as ZpaqBlocks, SegmentData as ZpaqSegmentData, Segments as ZpaqSegments, ZpaqReader,
+    ZpaqSegmentHeader,
+};
+pub use salvage::{
+    ZpaqDamage, ZpaqSalvage, salvage_bytes as salvage_zpaq_bytes,
+    salvage_file as salvage_zpaq_file, salvage_reader as salvage_zpaq_reader,
+};
+pub use transaction::{DedupStats, TransactionWriter};
+pub use writer::{ZpaqWriter, write_unmodeled_bytes as write_zpaq_unmodeled_bytes};
 pub use zpaq::{
-    ZpaqBlockHeader, ZpaqExtractedSegment,
+    ZpaqBlockHeader, ZpaqExtractedSegment, ZpaqVerifyReport,
     archive_is_fully_unmodeled_file as zpaq_is_fully_unmodeled_file,
-    extract_unmodeled_bytes as extract_zpaq_unmodeled_bytes,
-    extract_unmodeled_file as extract_zpaq_unmodeled_file, inspect_bytes as inspect_zpaq_bytes,
-    inspect_file as inspect_zpaq_file,
+    extract_bytes as extract_zpaq_bytes, extract_file as extract_zpaq_file,
+    extract_reader as extract_zpaq_reader, extract_unmodeled_bytes as extract_zpaq_unmodeled_bytes,
+    extract_unmodeled_file as extract_zpaq_unmodeled_file,
+    extract_unmodeled_reader as extract_zpaq_unmodeled_reader, inspect_bytes as inspect_zpaq_bytes,
+    inspect_file as inspect_zpaq_file, inspect_reader as inspect_zpaq_reader,
+    read_pcomp as read_zpaq_pcomp, verify_bytes as verify_zpaq_bytes,
+    verify_file as verify_zpaq_file, verify_reader as verify_zpaq_reader,
 };
+pub use zpaql::Zpaql;
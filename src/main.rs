@@ -1,15 +1,19 @@
 use anyhow::{Context, Result};
 use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command as ProcessCommand;
 use tar::Archive;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use tracing_subscriber::EnvFilter;
-use zpars::{CompressionOptions, DecompressionOptions};
+use zeroize::Zeroizing;
+use zpars::{CompressionOptions, DecompressionOptions, ZparsError};
 
 const DIR_WRAP_MAGIC: &[u8] = b"ZPARS_DIR_TAR_V1\0";
+/// `compress --format zpaq` starts a new block once a block holds this much.
+const ZPAQ_BLOCK_BYTES: u64 = 1 << 24;
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum LogFormat {
@@ -17,6 +21,12 @@ enum LogFormat {
     Json,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ArchiveFormat {
+    Zpars,
+    Zpaq,
+}
+
 #[derive(Debug, Parser)]
 #[command(
     name = "zpars",
@@ -45,6 +55,11 @@ enum Command {
     InspectZpaq(InspectArgs),
     ExtractZpaqM0(ExtractZpaqM0Args),
     ExtractZpaq(ExtractZpaqArgs),
+    CompileZpaql(CompileZpaqlArgs),
+    VerifyZpaq(VerifyZpaqArgs),
+    Salvage(SalvageArgs),
+    List(ListArgs),
+    Add(AddArgs),
 }
 
 #[derive(Debug, Args)]
@@ -67,6 +82,10 @@ struct CompressArgs {
     #[arg(short, long)]
     output: PathBuf,
 
+    /// `zpars` for the native stream, `zpaq` for an archive readable by `zpaq x`.
+    #[arg(long, value_enum, default_value = "zpars")]
+    format: ArchiveFormat,
+
     #[arg(long, value_parser = clap::value_parser!(u8).range(0..=5))]
     level: Option<u8>,
 
@@ -90,6 +109,13 @@ struct CompressArgs {
 struct InspectArgs {
     #[arg(short, long)]
     input: PathBuf,
+
+    #[command(flatten)]
+    password: PasswordArgs,
+
+    /// Print each block's model as a ZPAQL config (components, HCOMP, PCOMP).
+    #[arg(long, default_value_t = false)]
+    disassemble: bool,
 }
 
 #[derive(Debug, Args)]
@@ -99,6 +125,9 @@ struct ExtractZpaqM0Args {
 
     #[arg(short, long)]
     output_dir: PathBuf,
+
+    #[command(flatten)]
+    password: PasswordArgs,
 }
 
 #[derive(Debug, Args)]
@@ -112,8 +141,148 @@ struct ExtractZpaqArgs {
     #[arg(long, default_value = "tmp/zpaq/zpaq")]
     reference_bin: PathBuf,
 
-    #[arg(long, default_value_t = true)]
+    #[arg(long, default_value_t = false)]
+    reference: bool,
+
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
     allow_reference_fallback: bool,
+
+    /// Restore a journaling archive as of this version number, or this date
+    /// (`YYYY-MM-DD HH:MM:SS`; separators and the time are optional).
+    #[arg(long, value_name = "VERSION|DATE")]
+    until: Option<String>,
+
+    /// Blocks to decode in parallel; 0 uses one thread per CPU core.
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    threads: usize,
+
+    #[command(flatten)]
+    password: PasswordArgs,
+}
+
+impl ExtractZpaqArgs {
+    fn threads(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, usize::from),
+            n => n,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct VerifyZpaqArgs {
+    #[arg(short, long)]
+    input: PathBuf,
+
+    #[command(flatten)]
+    password: PasswordArgs,
+}
+
+#[derive(Debug, Args)]
+struct SalvageArgs {
+    /// Damaged ZPAQ archive.
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Directory for everything that can be recovered.
+    #[arg(short, long)]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    password: PasswordArgs,
+}
+
+#[derive(Debug, Args)]
+struct ListArgs {
+    /// Journaling ZPAQ archive to list.
+    archive: PathBuf,
+
+    /// Print the listing as JSON.
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    #[command(flatten)]
+    password: PasswordArgs,
+}
+
+// Password of an encrypted (`zpaq -key`) archive.
+#[derive(Debug, Args)]
+struct PasswordArgs {
+    /// Password of an encrypted archive.
+    #[arg(long, conflicts_with = "password_file", value_parser = parse_password)]
+    password: Option<Zeroizing<String>>,
+
+    /// Read the password from the first line of this file.
+    #[arg(long, value_name = "PATH")]
+    password_file: Option<PathBuf>,
+}
+
+impl PasswordArgs {
+    /// The password, zeroized when dropped.
+    fn get(&self) -> Result<Option<Zeroizing<String>>> {
+        if let Some(path) = &self.password_file {
+            let text = Zeroizing::new(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("reading password file {}", path.display()))?,
+            );
+            let line = text.lines().next().unwrap_or_default();
+            return Ok(Some(Zeroizing::new(line.to_owned())));
+        }
+        Ok(self.password.clone())
+    }
+
+    fn is_set(&self) -> bool {
+        self.password.is_some() || self.password_file.is_some()
+    }
+}
+
+/// Keep `--password` in a buffer that is zeroized on drop.
+fn parse_password(value: &str) -> Result<Zeroizing<String>, std::convert::Infallible> {
+    Ok(Zeroizing::new(value.to_owned()))
+}
+
+#[derive(Debug, Args)]
+struct AddArgs {
+    /// Journaling ZPAQ archive to append to; created if missing.
+    archive: PathBuf,
+
+    /// Files and directories to add.
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// ZPAQ method level for the data blocks (0 = stored).
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=5))]
+    level: u8,
+
+    /// Encrypt a new archive, or the password of an encrypted one.
+    #[command(flatten)]
+    password: PasswordArgs,
+
+    /// Index-only archive (c/h/i blocks, no data) to read the journal from
+    /// and append to, so the parts can be kept elsewhere. Needs a
+    /// multi-part archive name.
+    #[arg(long, value_name = "PATH")]
+    index: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct CompileZpaqlArgs {
+    /// zpaqd-style model config (`comp ... hcomp ... [pcomp ...] end`).
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Write the compiled header (`hsize .. HCOMP`) to this file.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Write the postprocessor description coded at the start of a block's
+    /// data (`0` for none, or `1 len_lo len_hi PCOMP`) to this file.
+    #[arg(long)]
+    pcomp_output: Option<PathBuf>,
+
+    /// Values for `$1`, `$2`, ... in the config.
+    #[arg(long = "arg", value_name = "N")]
+    args: Vec<i64>,
 }
 
 fn main() -> Result<()> {
@@ -127,10 +296,18 @@ fn main() -> Result<()> {
         Command::InspectZpaq(args) => run_inspect_zpaq(&args),
         Command::ExtractZpaqM0(args) => run_extract_zpaq_m0(&args),
         Command::ExtractZpaq(args) => run_extract_zpaq(&args),
+        Command::CompileZpaql(args) => run_compile_zpaql(&args),
+        Command::VerifyZpaq(args) => run_verify_zpaq(&args),
+        Command::Salvage(args) => run_salvage(&args),
+        Command::List(args) => run_list(&args),
+        Command::Add(args) => run_add(&args),
     }
 }
 
 fn run_compress(args: &CompressArgs) -> Result<()> {
+    if args.format == ArchiveFormat::Zpaq {
+        return run_compress_zpaq(args);
+    }
     let opts = compression_options(args);
     info!(?opts, input = %args.input.display(), output = %args.output.display(), "compression started");
 
@@ -189,6 +366,123 @@ fn run_decompress(args: &IoArgs) -> Result<()> {
     Ok(())
 }
 
+fn run_compress_zpaq(args: &CompressArgs) -> Result<()> {
+    let method = zpars::ZpaqMethod::new(zpaq_level(args))?;
+    info!(level = method.level(), input = %args.input.display(), output = %args.output.display(), "zpaq compression started");
+
+    let files = collect_zpaq_inputs(&args.input)?;
+    let output = File::create(&args.output)
+        .with_context(|| format!("creating output file {}", args.output.display()))?;
+    let mut w = zpars::ZpaqWriter::new(BufWriter::new(output));
+    // Files share a block until it reaches ZPAQ_BLOCK_BYTES, so the model
+    // state is built once per block rather than once per file.
+    let mut block_bytes = None;
+    for (name, path) in &files {
+        let input =
+            File::open(path).with_context(|| format!("opening input file {}", path.display()))?;
+        let size = input.metadata()?.len();
+        if block_bytes.is_some_and(|n| n >= ZPAQ_BLOCK_BYTES) {
+            w.end_block()?;
+            block_bytes = None;
+        }
+        if block_bytes.is_none() {
+            w.start_method_block(&method)?;
+        }
+        let size = write_zpaq_segment(&mut w, name, size, BufReader::new(input))?;
+        *block_bytes.get_or_insert(0) += size;
+        debug!(file = %name, bytes = size, "wrote zpaq segment");
+    }
+    if block_bytes.is_some() {
+        w.end_block()?;
+    }
+    w.finish()?.flush()?;
+
+    info!(files = files.len(), "zpaq compression completed");
+    Ok(())
+}
+
+/// ZPAQ method level for `--format zpaq`; the `.zpars` tuning flags do not
+/// apply to it.
+fn zpaq_level(args: &CompressArgs) -> u8 {
+    if args.block_size.is_some()
+        || args.min_match.is_some()
+        || args.secondary_match.is_some()
+        || args.search_log.is_some()
+        || args.table_log.is_some()
+    {
+        warn!(
+            "--block-size, --min-match, --secondary-match, --search-log and --table-log are ignored with --format zpaq"
+        );
+    }
+    args.level.unwrap_or(1)
+}
+
+/// Write `input` as a segment named `name` of the current block, with `size`
+/// as the comment and a SHA-1 trailer. Returns the number of bytes written.
+fn write_zpaq_segment<W: Write>(
+    w: &mut zpars::ZpaqWriter<W>,
+    name: &str,
+    size: u64,
+    mut input: impl Read,
+) -> Result<u64> {
+    w.start_segment(name, &size.to_string())?;
+    let mut buf = vec![0u8; 1 << 16];
+    let mut written = 0u64;
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        w.write_data(&buf[..n])?;
+        written += n as u64;
+    }
+    w.end_segment()?;
+    Ok(written)
+}
+
+/// Segment names and paths of the regular files under `path`, using
+/// `/`-separated paths relative to a directory input.
+fn collect_zpaq_inputs(path: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("reading input metadata {}", path.display()))?;
+    if metadata.is_file() {
+        let name = path
+            .file_name()
+            .context("input file has no name")?
+            .to_string_lossy()
+            .into_owned();
+        return Ok(vec![(name, path.to_path_buf())]);
+    }
+    if !metadata.is_dir() {
+        anyhow::bail!("input path is neither regular file nor directory");
+    }
+
+    let mut files = Vec::new();
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)
+            .with_context(|| format!("reading directory {}", dir.display()))?
+        {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let entry_path = entry.path();
+            if file_type.is_dir() {
+                pending.push(entry_path);
+            } else if file_type.is_file() {
+                let rel = entry_path.strip_prefix(path)?;
+                let name = rel
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                files.push((name, entry_path));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
 fn run_roundtrip(args: &CompressArgs) -> Result<()> {
     let opts = compression_options(args);
     info!(input = %args.input.display(), output = %args.output.display(), "roundtrip started");
@@ -199,10 +493,24 @@ fn run_roundtrip(args: &CompressArgs) -> Result<()> {
         .read_to_end(&mut raw)?;
 
     let mut compressed = Vec::new();
-    zpars::compress(raw.as_slice(), &mut compressed, &opts)?;
-
     let mut restored = Vec::new();
-    zpars::decompress(compressed.as_slice(), &mut restored, &DecompressionOptions)?;
+    match args.format {
+        ArchiveFormat::Zpars => {
+            zpars::compress(raw.as_slice(), &mut compressed, &opts)?;
+            zpars::decompress(compressed.as_slice(), &mut restored, &DecompressionOptions)?;
+        }
+        ArchiveFormat::Zpaq => {
+            let mut w = zpars::ZpaqWriter::new(&mut compressed);
+            let size = raw.len() as u64;
+            w.start_method_block(&zpars::ZpaqMethod::new(zpaq_level(args))?)?;
+            write_zpaq_segment(&mut w, "roundtrip", size, raw.as_slice())?;
+            w.end_block()?;
+            w.finish()?;
+            for seg in zpars::extract_zpaq_bytes(&compressed)? {
+                restored.extend_from_slice(&seg.data);
+            }
+        }
+    }
 
     if raw != restored {
         anyhow::bail!("roundtrip mismatch");
@@ -360,10 +668,37 @@ fn init_tracing(cli: &Cli) -> Result<()> {
     Ok(())
 }
 
+/// Open an archive for reading, joining the parts of a multi-part archive
+/// and decrypting it when a password is given.
+fn open_archive(path: &Path, password: &PasswordArgs) -> Result<Box<dyn Read>> {
+    let file = zpars::PartsReader::open(path)
+        .with_context(|| format!("opening zpaq archive {}", path.display()))?;
+    let file = BufReader::new(file);
+    match password.get()? {
+        Some(password) => {
+            let reader = zpars::DecryptReader::new(file, password.as_bytes())
+                .with_context(|| format!("decrypting {}", path.display()))?;
+            Ok(Box::new(reader))
+        }
+        None => Ok(Box::new(file)),
+    }
+}
+
+/// An encrypted archive looks like random bytes, so reading it without the
+/// password finds no blocks.
+fn warn_if_no_blocks(path: &Path, found: usize, password: &PasswordArgs) {
+    if found == 0 && !password.is_set() {
+        warn!(
+            archive = %path.display(),
+            "no ZPAQ blocks found; if the archive is encrypted, pass --password or --password-file"
+        );
+    }
+}
+
 fn run_inspect_zpaq(args: &InspectArgs) -> Result<()> {
-    let blocks = zpars::inspect_zpaq_file(&args.input)?;
-    info!(count = blocks.len(), input = %args.input.display(), "zpaq blocks detected");
-    for (idx, b) in blocks.iter().enumerate() {
+    let mut reader = zpars::ZpaqReader::new(open_archive(&args.input, &args.password)?);
+    let mut idx = 0usize;
+    while let Some(b) = reader.next_block()? {
         println!(
             "block={idx} offset={} level={} type={} hsize={} hh={} hm={} ph={} pm={} comps={} comp_bytes={} hcomp_bytes={} segment_offset={}",
             b.start_offset,
@@ -379,12 +714,50 @@ fn run_inspect_zpaq(args: &InspectArgs) -> Result<()> {
             b.hcomp_bytes,
             b.segment_offset
         );
+        if args.disassemble {
+            // PCOMP is coded at the start of the first segment.
+            let has_segment = reader
+                .next_segment()
+                .with_context(|| format!("reading PCOMP of block {idx}"))?
+                .is_some();
+            let pcomp = if has_segment { reader.pcomp()? } else { None };
+            print!("{}", zpars::zpaql::disassemble_config(&b, pcomp));
+        }
+        idx += 1;
+    }
+    info!(count = idx, input = %args.input.display(), "zpaq blocks detected");
+    warn_if_no_blocks(&args.input, idx, &args.password);
+    Ok(())
+}
+
+fn run_compile_zpaql(args: &CompileZpaqlArgs) -> Result<()> {
+    let src = std::fs::read_to_string(&args.input)
+        .with_context(|| format!("reading config {}", args.input.display()))?;
+    let model = zpars::compile_zpaql_with_args(&src, &args.args)
+        .with_context(|| format!("compiling {}", args.input.display()))?;
+    println!(
+        "comps={} hsize={} hcomp_bytes={} pcomp_bytes={}",
+        model.n_components,
+        model.hsize(),
+        model.hcomp.len(),
+        model.pcomp.as_ref().map_or(0, Vec::len)
+    );
+    if let Some(output) = &args.output {
+        std::fs::write(output, model.header_bytes())
+            .with_context(|| format!("writing compiled header {}", output.display()))?;
+        info!(output = %output.display(), "wrote compiled ZPAQL header");
+    }
+    if let Some(output) = &args.pcomp_output {
+        std::fs::write(output, model.postprocessor_prefix())
+            .with_context(|| format!("writing compiled PCOMP {}", output.display()))?;
+        info!(output = %output.display(), "wrote compiled ZPAQL postprocessor");
     }
     Ok(())
 }
 
 fn run_extract_zpaq_m0(args: &ExtractZpaqM0Args) -> Result<()> {
-    let segments = zpars::extract_zpaq_unmodeled_file(&args.input)?;
+    let segments =
+        zpars::extract_zpaq_unmodeled_reader(open_archive(&args.input, &args.password)?)?;
     std::fs::create_dir_all(&args.output_dir).with_context(|| {
         format!(
             "creating output directory for extracted files {}",
@@ -393,12 +766,10 @@ fn run_extract_zpaq_m0(args: &ExtractZpaqM0Args) -> Result<()> {
     })?;
 
     for seg in &segments {
-        let name = if seg.filename.is_empty() {
-            format!("block{}_segment.bin", seg.block_index)
-        } else {
-            seg.filename.clone()
+        let Some(path) = segment_output_path(&args.output_dir, seg) else {
+            warn!(file = %seg.filename, "skipping unsafe segment filename");
+            continue;
         };
-        let path = args.output_dir.join(name);
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)?;
         }
@@ -424,27 +795,796 @@ fn run_extract_zpaq(args: &ExtractZpaqArgs) -> Result<()> {
         )
     })?;
 
-    if args.allow_reference_fallback && args.reference_bin.exists() {
+    if args.reference {
+        if args.password.is_set() {
+            anyhow::bail!(
+                "--reference cannot extract encrypted archives: the password would be visible on its command line"
+            );
+        }
         info!(
             reference = %args.reference_bin.display(),
             mode = "reference",
-            "using reference extractor"
+            "reference extractor requested"
+        );
+        return run_reference_fallback(args);
+    }
+
+    if let Some(until) = &args.until {
+        return run_extract_zpaq_until(args, until.parse()?);
+    }
+
+    // A journaling archive starts with a transaction header. Its index is
+    // read first, so each file is written as soon as the data blocks holding
+    // its fragments are decoded, and fragment data no file still needs is
+    // dropped; other segments are written as each block is decoded.
+    let journal = if starts_with_transaction(open_archive(&args.input, &args.password)?)? {
+        match zpars::Journal::read(open_archive(&args.input, &args.password)?) {
+            Ok(journal) => Some(journal),
+            Err(ZparsError::Unsupported(feature)) if reference_fallback_available(args) => {
+                warn!(feature, "native decoder cannot handle this archive");
+                return run_reference_fallback(args);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    } else {
+        None
+    };
+    let mut journal_files = journal.as_ref().map(zpars::Journal::assemble_files);
+    let mut files = match &mut journal_files {
+        Some(pending) => write_ready_journal_files(pending, &args.output_dir)?,
+        None => 0,
+    };
+    let input = open_archive(&args.input, &args.password)?;
+    let mut segments = 0usize;
+    let decoded =
+        zpars::extract_zpaq_blocks_threaded(input, args.threads(), |block: Vec<_>| -> Result<()> {
+            segments += block.len();
+            let Some(pending) = &mut journal_files else {
+                return write_native_segments(&block, &args.output_dir);
+            };
+            let (journaling, plain): (Vec<_>, Vec<_>) = block
+                .into_iter()
+                .partition(|s| s.filename.starts_with("jDC"));
+            for seg in &journaling {
+                pending.add_segment(seg)?;
+            }
+            files += write_ready_journal_files(pending, &args.output_dir)?;
+            write_native_segments(&plain, &args.output_dir)
+        });
+    if let Err(err) = decoded {
+        return match err.downcast_ref::<ZparsError>() {
+            Some(ZparsError::Unsupported(feature)) if reference_fallback_available(args) => {
+                warn!(feature, "native decoder cannot handle this archive");
+                run_reference_fallback(args)
+            }
+            _ => Err(err),
+        };
+    }
+    warn_if_no_blocks(&args.input, segments, &args.password);
+
+    if let (Some(journal), Some(pending)) = (&journal, &journal_files) {
+        ensure_journal_files_complete(pending)?;
+        info!(
+            versions = journal.versions.len(),
+            files,
+            threads = args.threads(),
+            mode = "native",
+            "zpaq journaling extraction completed"
         );
-        return run_reference_extract(&args.reference_bin, &args.input, &args.output_dir);
+        return Ok(());
     }
 
-    match zpars::extract_zpaq_unmodeled_file(&args.input) {
-        Ok(segments) => {
-            write_native_segments(&segments, &args.output_dir)?;
-            info!(
-                segments = segments.len(),
-                mode = "native-unmodeled",
-                "zpaq extraction completed"
+    info!(
+        segments,
+        threads = args.threads(),
+        mode = "native",
+        "zpaq extraction completed"
+    );
+    Ok(())
+}
+
+/// Whether the archive's first segment is a journaling transaction header.
+fn starts_with_transaction<R: Read>(input: R) -> Result<bool> {
+    let mut reader = zpars::ZpaqReader::new(input);
+    let first = reader.segments().next().transpose()?;
+    Ok(first.is_some_and(|seg| {
+        seg.filename.starts_with("jDC") && seg.filename.as_bytes().get(17) == Some(&b'c')
+    }))
+}
+
+fn run_verify_zpaq(args: &VerifyZpaqArgs) -> Result<()> {
+    let report = zpars::verify_zpaq_reader(open_archive(&args.input, &args.password)?)
+        .with_context(|| format!("verifying {}", args.input.display()))?;
+    warn_if_no_blocks(&args.input, report.segments, &args.password);
+    for (block, filename) in &report.mismatches {
+        println!("block={block} file={filename:?} sha1=mismatch");
+    }
+    info!(
+        segments = report.segments,
+        verified = report.verified,
+        unchecked = report.segments - report.verified - report.mismatches.len(),
+        mismatches = report.mismatches.len(),
+        "zpaq verification completed"
+    );
+    if !report.mismatches.is_empty() {
+        anyhow::bail!(
+            "{} segment(s) failed SHA-1 verification",
+            report.mismatches.len()
+        );
+    }
+    Ok(())
+}
+
+fn run_salvage(args: &SalvageArgs) -> Result<()> {
+    let salvage = zpars::salvage_zpaq_reader(open_archive(&args.input, &args.password)?)
+        .with_context(|| format!("salvaging {}", args.input.display()))?;
+    warn_if_no_blocks(&args.input, salvage.segments.len(), &args.password);
+    for d in &salvage.damage {
+        let block = d.block_index.map_or("none".into(), |b| b.to_string());
+        println!(
+            "damaged bytes={}..{} block={block} segments={:?} error={:?}",
+            d.start, d.end, d.filenames, d.error
+        );
+    }
+    std::fs::create_dir_all(&args.output_dir).with_context(|| {
+        format!(
+            "creating output directory for recovered files {}",
+            args.output_dir.display()
+        )
+    })?;
+
+    // Journaling archives are rebuilt into files; a file is lost when a
+    // fragment of it was in a damaged block.
+    let (recovered, lost) = if salvage
+        .segments
+        .iter()
+        .any(|s| s.filename.starts_with("jDC"))
+    {
+        let journal = zpars::Journal::from_salvaged(&salvage.segments);
+        let mut recovered = 0usize;
+        let mut lost = 0usize;
+        for (name, entry) in journal.files() {
+            let Some(path) = journal_output_path(&args.output_dir, name) else {
+                warn!(file = %name, "skipping unsafe path in archive index");
+                continue;
+            };
+            if entry.is_dir() {
+                std::fs::create_dir_all(&path)?;
+                continue;
+            }
+            let Ok(data) = journal.file_data(entry) else {
+                println!("lost file={name:?}");
+                lost += 1;
+                continue;
+            };
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, data)
+                .with_context(|| format!("writing recovered file {}", path.display()))?;
+            recovered += 1;
+        }
+        (recovered, lost)
+    } else {
+        write_native_segments(&salvage.segments, &args.output_dir)?;
+        for d in &salvage.damage {
+            for name in &d.filenames {
+                println!("lost file={name:?}");
+            }
+        }
+        let lost = salvage.damage.iter().map(|d| d.filenames.len()).sum();
+        (salvage.segments.len(), lost)
+    };
+
+    let skipped: usize = salvage.damage.iter().map(|d| d.end - d.start).sum();
+    info!(
+        recovered,
+        lost,
+        damaged_regions = salvage.damage.len(),
+        skipped_bytes = skipped,
+        "zpaq salvage completed"
+    );
+    Ok(())
+}
+
+fn run_list(args: &ListArgs) -> Result<()> {
+    let input = open_archive(&args.archive, &args.password)?;
+    // Only the stored c/h/i blocks are decoded; data blocks are skipped.
+    let journal = zpars::Journal::read(input)
+        .with_context(|| format!("reading journal of {}", args.archive.display()))?;
+    if journal.versions.is_empty() {
+        warn!(archive = %args.archive.display(), "no journaling index found");
+    }
+
+    let versions: Vec<_> = journal
+        .versions
+        .iter()
+        .map(|v| {
+            let deleted = v.entries.iter().filter(|e| e.is_deleted()).count();
+            (v, v.entries.len() - deleted, deleted)
+        })
+        .collect();
+    let files = journal.files();
+
+    if args.json {
+        let report = serde_json::json!({
+            "versions": versions.iter().enumerate().map(|(i, (v, updated, deleted))| {
+                serde_json::json!({
+                    "version": i + 1,
+                    "date": zpars::format_journal_date(v.date),
+                    "files": updated,
+                    "deleted": deleted,
+                })
+            }).collect::<Vec<_>>(),
+            "files": files.values().map(|e| {
+                serde_json::json!({
+                    "name": e.name,
+                    "size": journal.file_size(e),
+                    "date": zpars::format_journal_date(e.date),
+                    "attr": format_attr(&e.attr),
+                    "fragments": e.ptrs.len(),
+                })
+            }).collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:>7}  {:<19}  {:>6}  {:>7}",
+        "Version", "Date", "Files", "Deleted"
+    );
+    for (i, (v, updated, deleted)) in versions.iter().enumerate() {
+        println!(
+            "{:>7}  {}  {updated:>6}  {deleted:>7}",
+            i + 1,
+            zpars::format_journal_date(v.date)
+        );
+    }
+    println!();
+    println!(
+        "{:<19}  {:<8}  {:>12}  {:>5}  Name",
+        "Date", "Attr", "Size", "Frags"
+    );
+    let mut total = 0u64;
+    for e in files.values() {
+        let size = journal.file_size(e);
+        total += size;
+        println!(
+            "{}  {:<8}  {size:>12}  {:>5}  {}",
+            zpars::format_journal_date(e.date),
+            format_attr(&e.attr),
+            e.ptrs.len(),
+            e.name
+        );
+    }
+    println!("{} files, {total} bytes", files.len());
+    Ok(())
+}
+
+fn run_add(args: &AddArgs) -> Result<()> {
+    let multi = zpars::is_multi_part(&args.archive);
+    if args.index.is_some() && !multi {
+        anyhow::bail!("--index needs a multi-part archive name such as backup??.zpaq");
+    }
+    let mut parts: Vec<(PathBuf, u64)> = zpars::part_paths(&args.archive)
+        .into_iter()
+        .filter_map(|p| std::fs::metadata(&p).ok().map(|m| (p, m.len())))
+        .collect();
+    let existing: u64 = parts.iter().map(|(_, len)| len).sum();
+    let password = args.password.get()?;
+
+    // With an index the journal comes from it, so the archive's parts need
+    // not be present.
+    let source = args.index.as_deref().unwrap_or(&args.archive);
+    let source_len = match &args.index {
+        Some(index) => std::fs::metadata(index).map_or(0, |m| m.len()),
+        None => existing,
+    };
+    if args.index.is_some() && source_len == 0 && existing > 0 {
+        anyhow::bail!(
+            "{} has parts but its index {} is missing; an index must be created by the first add",
+            args.archive.display(),
+            source.display()
+        );
+    }
+    let journal = if source_len > 0 {
+        let input = open_archive(source, &args.password)?;
+        let journal = zpars::Journal::read(input)
+            .with_context(|| format!("reading journal of {}", source.display()))?;
+        if journal.versions.is_empty() && journal.incomplete.is_none() {
+            anyhow::bail!(
+                "{} is not a journaling archive (or is encrypted and needs --password); add only appends to those",
+                source.display()
             );
-            Ok(())
         }
-        Err(err) => Err(err.into()),
+        journal
+    } else {
+        zpars::Journal::default()
+    };
+
+    let archive_paths: Vec<PathBuf> = parts
+        .iter()
+        .map(|(p, _)| p)
+        .chain(&args.index)
+        .filter_map(|p| std::fs::canonicalize(p).ok())
+        .collect();
+    let mut scanned = BTreeMap::new();
+    for input in &args.inputs {
+        scan_add_input(input, &archive_paths, &mut scanned)?;
+    }
+
+    let current = journal.files();
+    let changed: Vec<_> = scanned
+        .iter()
+        .filter(|(name, (_, entry))| {
+            current.get(name.as_str()).is_none_or(|old| {
+                old.date != entry.date || (!entry.dir && journal.file_size(old) != entry.size)
+            })
+        })
+        .collect();
+    let roots: Vec<String> = args.inputs.iter().map(|p| add_entry_name(p)).collect();
+    let deleted: Vec<&str> = current
+        .keys()
+        .copied()
+        .filter(|name| !scanned.contains_key(*name))
+        .filter(|name| {
+            roots.iter().any(|root| {
+                let name = name.trim_end_matches('/');
+                name == root || name.starts_with(&format!("{root}/")) || root.is_empty()
+            })
+        })
+        .collect();
+    if changed.is_empty() && deleted.is_empty() {
+        info!(archive = %args.archive.display(), "archive is up to date");
+        return Ok(());
+    }
+
+    let mut date = zpars::journal_date_from_unix(unix_now());
+    if let Some(last) = journal.versions.last()
+        && date <= last.date
+    {
+        date = zpars::journal_date_from_unix(zpars::journal_unix_from_date(last.date) + 1);
+    }
+
+    let mut end = existing;
+    let mut index_end = source_len;
+    if let Some(block) = journal.incomplete {
+        // An interrupted transaction is discarded and overwritten. Block
+        // offsets of an encrypted archive count from the end of its salt.
+        let salt = if password.is_some() {
+            zpars::SALT_LEN
+        } else {
+            0
+        };
+        let at = block_offset(open_archive(source, &args.password)?, block)? + salt as u64;
+        warn!(offset = at, file = %source.display(), "discarding incomplete transaction");
+        match &args.index {
+            Some(index) => {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(index)?
+                    .set_len(at)?;
+                index_end = at;
+            }
+            None => {
+                truncate_parts(&mut parts, at, multi)?;
+                end = at;
+            }
+        }
+    }
+
+    // A multi-part archive gets a new part; a single file is appended to.
+    // With an index, the part number and (for the keystream) the size of the
+    // earlier parts come from the index.
+    let fresh = match &args.index {
+        Some(_) => journal.versions.is_empty(),
+        None => end == 0,
+    };
+    let (file, base) = if multi {
+        let (number, base) = match &args.index {
+            Some(index) if password.is_some() && !fresh => (
+                journal.versions.len() + 1,
+                indexed_archive_size(index, &args.password, &journal)?,
+            ),
+            Some(_) => (journal.versions.len() + 1, 0),
+            None => (parts.len() + 1, end),
+        };
+        let path = zpars::part_path(&args.archive, number as u32);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("creating archive part {}", path.display()))?;
+        info!(part = %path.display(), "writing new archive part");
+        (file, base)
+    } else {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&args.archive)
+            .with_context(|| format!("opening archive {}", args.archive.display()))?;
+        file.seek(SeekFrom::Start(end))?;
+        (file, 0)
+    };
+
+    // The archive's salt starts its first part; an index's salt is derived
+    // from it.
+    let salt = match &password {
+        None => None,
+        Some(_) if args.index.is_some() && index_end > 0 => {
+            Some(zpars::index_salt(&read_salt(source)?))
+        }
+        Some(_) if fresh => Some(zpars::new_salt()?),
+        Some(_) => Some(read_salt(&parts[0].0)?),
+    };
+    let output: Box<dyn WriteSeek> = match (&password, &salt) {
+        (Some(password), Some(salt)) if fresh => Box::new(zpars::EncryptWriter::create_with_salt(
+            BufWriter::new(file),
+            password.as_bytes(),
+            salt,
+        )?),
+        (Some(password), Some(salt)) => Box::new(zpars::EncryptWriter::append_part(
+            BufWriter::new(file),
+            password.as_bytes(),
+            salt,
+            base,
+        )?),
+        _ => Box::new(BufWriter::new(file)),
+    };
+
+    let first = journal.fragments.len().max(1) as u32;
+    let mut tx = zpars::TransactionWriter::new(output, date, first, args.level)?;
+    tx.index_fragments(&journal.fragments);
+    if args.index.is_some() {
+        tx.keep_index();
+    }
+    for (name, (path, entry)) in &changed {
+        let ptrs = if entry.dir {
+            Vec::new()
+        } else {
+            let input = File::open(path)
+                .with_context(|| format!("opening input file {}", path.display()))?;
+            tx.add_file_data(BufReader::new(input))?
+        };
+        debug!(file = %name, fragments = ptrs.len(), "adding file");
+        tx.add_entry(&zpars::JournalEntry {
+            name: (*name).clone(),
+            date: entry.date,
+            attr: entry.attr.clone(),
+            ptrs,
+        })?;
+    }
+    for name in &deleted {
+        debug!(file = %name, "recording deletion");
+        tx.add_entry(&zpars::JournalEntry {
+            name: (*name).to_owned(),
+            date: 0,
+            attr: Vec::new(),
+            ptrs: Vec::new(),
+        })?;
+    }
+    let stats = tx.stats();
+    match &args.index {
+        Some(index) => {
+            let (mut output, copy) = tx.finish_with_index()?;
+            output.flush()?;
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(index)
+                .with_context(|| format!("opening index {}", index.display()))?;
+            let mut file = BufWriter::new(file);
+            file.seek(SeekFrom::Start(index_end))?;
+            let mut output: Box<dyn WriteSeek> = match (&password, &salt) {
+                (Some(password), Some(salt)) => {
+                    let index_salt = zpars::index_salt(salt);
+                    if index_end == 0 {
+                        Box::new(zpars::EncryptWriter::create_with_salt(
+                            file,
+                            password.as_bytes(),
+                            &index_salt,
+                        )?)
+                    } else {
+                        Box::new(zpars::EncryptWriter::append(
+                            file,
+                            password.as_bytes(),
+                            &index_salt,
+                        )?)
+                    }
+                }
+                _ => Box::new(file),
+            };
+            output.write_all(&copy)?;
+            output.flush()?;
+            debug!(index = %index.display(), bytes = copy.len(), "updated index");
+        }
+        None => tx.finish()?.flush()?,
+    }
+
+    info!(
+        version = journal.versions.len() + 1,
+        date = %zpars::format_journal_date(date),
+        added = changed.len(),
+        deleted = deleted.len(),
+        dedup_hits = stats.hits,
+        dedup_misses = stats.misses,
+        dedup_bytes = stats.hit_bytes,
+        stored_bytes = stats.stored_bytes,
+        "transaction appended"
+    );
+    Ok(())
+}
+
+/// The salt at the start of an encrypted archive file.
+fn read_salt(path: &Path) -> Result<[u8; zpars::SALT_LEN]> {
+    let mut salt = [0u8; zpars::SALT_LEN];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut salt))
+        .with_context(|| format!("reading salt of {}", path.display()))?;
+    Ok(salt)
+}
+
+/// Size of the encrypted archive an index describes: its salt, then each
+/// transaction's `c`, `h` and `i` blocks and the csize bytes of `d` blocks
+/// the index leaves out.
+fn indexed_archive_size(
+    index: &Path,
+    password: &PasswordArgs,
+    journal: &zpars::Journal,
+) -> Result<u64> {
+    let blocks = zpars::inspect_zpaq_reader(open_archive(index, password)?)?;
+    let index_end = std::fs::metadata(index)?.len() - zpars::SALT_LEN as u64;
+    let mut size = zpars::SALT_LEN as u64;
+    for (i, v) in journal.versions.iter().enumerate() {
+        let start = blocks[v.block_index].start_offset as u64;
+        let next = journal
+            .versions
+            .get(i + 1)
+            .map(|n| n.block_index)
+            .or(journal.incomplete)
+            .map_or(index_end, |b| blocks[b].start_offset as u64);
+        size += next - start + v.csize;
+    }
+    Ok(size)
+}
+
+/// A file or directory found by `add`, with what its index record needs.
+struct ScannedEntry {
+    date: u64,
+    size: u64,
+    attr: Vec<u8>,
+    dir: bool,
+}
+
+/// Index name of an `add` input: the path as given, with `/` separators.
+/// `.` is empty, so the names below it have no prefix.
+fn add_entry_name(path: &Path) -> String {
+    let name = path.to_string_lossy().replace('\\', "/");
+    let name = name.trim_end_matches('/');
+    match name.strip_prefix("./").unwrap_or(name) {
+        "." => String::new(),
+        name => name.to_owned(),
+    }
+}
+
+/// Record `path` and, for a directory, everything below it. Directory names
+/// end in `/`. Symlinks and special files are skipped, as are the archive's
+/// parts.
+fn scan_add_input(
+    path: &Path,
+    archive: &[PathBuf],
+    out: &mut BTreeMap<String, (PathBuf, ScannedEntry)>,
+) -> Result<()> {
+    let mut pending = vec![(add_entry_name(path), path.to_path_buf())];
+    while let Some((name, path)) = pending.pop() {
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("reading metadata {}", path.display()))?;
+        let dir = metadata.is_dir();
+        if !dir && !metadata.is_file() {
+            debug!(path = %path.display(), "skipping special file");
+            continue;
+        }
+        if !dir
+            && !archive.is_empty()
+            && std::fs::canonicalize(&path).is_ok_and(|p| archive.contains(&p))
+        {
+            continue;
+        }
+        let mtime = metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let entry = ScannedEntry {
+            date: zpars::journal_date_from_unix(mtime),
+            size: if dir { 0 } else { metadata.len() },
+            attr: file_attr(&metadata),
+            dir,
+        };
+        if dir {
+            for child in std::fs::read_dir(&path)
+                .with_context(|| format!("reading directory {}", path.display()))?
+            {
+                let child = child?;
+                let child_name = child.file_name().to_string_lossy().into_owned();
+                let child_name = if name.is_empty() {
+                    child_name
+                } else {
+                    format!("{name}/{child_name}")
+                };
+                pending.push((child_name, child.path()));
+            }
+            if !name.is_empty() {
+                out.insert(format!("{name}/"), (path, entry));
+            }
+        } else {
+            out.insert(name, (path, entry));
+        }
+    }
+    Ok(())
+}
+
+/// Attributes in zpaq's index format: `u` and the low 16 mode bits on Unix.
+#[cfg(unix)]
+fn file_attr(metadata: &std::fs::Metadata) -> Vec<u8> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = metadata.permissions().mode();
+    vec![b'u', mode as u8, (mode >> 8) as u8]
+}
+
+/// Attributes in zpaq's index format: `w` and the 32-bit file attributes.
+#[cfg(windows)]
+fn file_attr(metadata: &std::fs::Metadata) -> Vec<u8> {
+    use std::os::windows::fs::MetadataExt;
+    let mut attr = vec![b'w'];
+    attr.extend_from_slice(&metadata.file_attributes().to_le_bytes());
+    attr
+}
+
+#[cfg(not(any(unix, windows)))]
+fn file_attr(_metadata: &std::fs::Metadata) -> Vec<u8> {
+    Vec::new()
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Cut an archive back to `end` bytes: the part holding `end` is truncated
+/// and later parts are removed. A part of a multi-part archive left empty is
+/// removed too, so the next part reuses its number.
+fn truncate_parts(parts: &mut Vec<(PathBuf, u64)>, end: u64, multi: bool) -> Result<()> {
+    let mut start = 0;
+    let mut kept = Vec::new();
+    for (path, len) in parts.drain(..) {
+        if start + len <= end {
+            kept.push((path, len));
+        } else if start < end || !multi {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(end - start)?;
+            kept.push((path, end - start));
+        } else {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("removing archive part {}", path.display()))?;
+        }
+        start += len;
+    }
+    *parts = kept;
+    Ok(())
+}
+
+/// Output of `add`: a plain or an encrypting archive writer.
+trait WriteSeek: Write + Seek {}
+
+impl<T: Write + Seek> WriteSeek for T {}
+
+/// Byte offset of block `index` (counting every block) in `archive`.
+fn block_offset(archive: impl Read, index: usize) -> Result<u64> {
+    let mut reader = zpars::ZpaqReader::new(archive);
+    for _ in 0..index {
+        reader.next_block()?;
+    }
+    let header = reader
+        .next_block()?
+        .with_context(|| format!("archive ends before block {index}"))?;
+    Ok(header.start_offset as u64)
+}
+
+/// Unix modes print in octal and Windows attributes in hex, as stored after
+/// their `u`/`w` tag.
+fn format_attr(attr: &[u8]) -> String {
+    let value = |b: &[u8]| {
+        let mut v = [0u8; 4];
+        v[..b.len().min(4)].copy_from_slice(&b[..b.len().min(4)]);
+        u32::from_le_bytes(v)
+    };
+    match attr.split_first() {
+        Some((b'u', rest)) => format!("{:o}", value(rest)),
+        Some((b'w', rest)) => format!("w{:x}", value(rest)),
+        _ => String::new(),
+    }
+}
+
+/// The reference extractor is never run for an encrypted archive, since it
+/// would take the password on its command line.
+fn reference_fallback_available(args: &ExtractZpaqArgs) -> bool {
+    args.allow_reference_fallback && args.reference_bin.exists() && !args.password.is_set()
+}
+
+/// Restore the file tree of a journaling archive as of `until`, decoding only
+/// the data blocks that hold fragments of that version.
+fn run_extract_zpaq_until(args: &ExtractZpaqArgs, until: zpars::JournalUntil) -> Result<()> {
+    let open = || open_archive(&args.input, &args.password);
+    let mut journal = match zpars::Journal::read(open()?) {
+        Ok(journal) => journal,
+        Err(ZparsError::Unsupported(feature)) if reference_fallback_available(args) => {
+            warn!(feature, "native decoder cannot handle this archive");
+            return run_reference_fallback(args);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if journal.versions.is_empty() {
+        anyhow::bail!("--until requires a journaling archive");
     }
+    let total = journal.versions.len();
+    journal.roll_back(until);
+    let Some(version) = journal.versions.last() else {
+        anyhow::bail!("no version of the archive matches --until");
+    };
+    info!(
+        version = journal.versions.len(),
+        of = total,
+        date = %zpars::format_journal_date(version.date),
+        "rolling back journaling archive"
+    );
+
+    // Files are written as the data blocks holding their fragments are
+    // decoded, one at a time.
+    let needed = journal.needed_fragments();
+    let mut pending = journal.assemble_files();
+    let mut files = write_ready_journal_files(&mut pending, &args.output_dir)?;
+    journal.read_data_blocks(open()?, &needed, |seg| -> Result<()> {
+        pending.add_segment(&seg)?;
+        files += write_ready_journal_files(&mut pending, &args.output_dir)?;
+        Ok(())
+    })?;
+    ensure_journal_files_complete(&pending)?;
+    info!(
+        files,
+        fragments = needed.len(),
+        mode = "native",
+        "zpaq journaling extraction completed"
+    );
+    Ok(())
+}
+
+fn run_reference_fallback(args: &ExtractZpaqArgs) -> Result<()> {
+    let blocks = zpars::inspect_zpaq_reader(open_archive(&args.input, &args.password)?)?;
+    for block in 0..blocks.len() {
+        info!(block, path = "reference", "decoding block");
+    }
+    run_reference_extract(
+        &args.reference_bin,
+        &args.input,
+        &args.output_dir,
+        args.until.as_deref(),
+        args.threads(),
+    )?;
+    info!(
+        blocks = blocks.len(),
+        mode = "reference",
+        "zpaq extraction completed"
+    );
+    Ok(())
 }
 
 fn write_native_segments(
@@ -452,12 +1592,10 @@ fn write_native_segments(
     output_dir: &Path,
 ) -> Result<()> {
     for seg in segments {
-        let name = if seg.filename.is_empty() {
-            format!("block{}_segment.bin", seg.block_index)
-        } else {
-            seg.filename.clone()
+        let Some(path) = segment_output_path(output_dir, seg) else {
+            warn!(file = %seg.filename, "skipping unsafe segment filename");
+            continue;
         };
-        let path = output_dir.join(name);
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)?;
         }
@@ -467,13 +1605,83 @@ fn write_native_segments(
     Ok(())
 }
 
-fn run_reference_extract(reference_bin: &Path, input: &Path, output_dir: &Path) -> Result<()> {
+/// Write the files `pending` has all the data of under `output_dir`,
+/// returning the number of entries written.
+fn write_ready_journal_files(
+    pending: &mut zpars::JournalFiles,
+    output_dir: &Path,
+) -> Result<usize> {
+    let mut files = 0usize;
+    while let Some((name, entry, data)) = pending.next_ready() {
+        files += 1;
+        let Some(path) = journal_output_path(output_dir, &name) else {
+            warn!(file = %name, "skipping unsafe path in archive index");
+            continue;
+        };
+        if entry.is_dir() {
+            std::fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, &data)
+            .with_context(|| format!("writing extracted file {}", path.display()))?;
+        debug!(file = %path.display(), bytes = data.len(), "extracted file");
+    }
+    Ok(files)
+}
+
+/// Fail for a file whose fragments the archive's data blocks did not hold.
+fn ensure_journal_files_complete(pending: &zpars::JournalFiles) -> Result<()> {
+    match pending.remaining().next() {
+        Some(name) => Err(ZparsError::Corrupt("fragment data missing from archive"))
+            .with_context(|| format!("extracting {name}")),
+        None => Ok(()),
+    }
+}
+
+/// Where a segment is extracted: its filename as `journal_output_path` maps
+/// it, or `block<N>_segment.bin` for an unnamed segment.
+fn segment_output_path(output_dir: &Path, seg: &zpars::ZpaqExtractedSegment) -> Option<PathBuf> {
+    if seg.filename.is_empty() {
+        return Some(output_dir.join(format!("block{}_segment.bin", seg.block_index)));
+    }
+    journal_output_path(output_dir, &seg.filename)
+}
+
+/// Map an archived path (possibly absolute or with a drive letter) below
+/// `output_dir`; `None` if it would escape it.
+fn journal_output_path(output_dir: &Path, name: &str) -> Option<PathBuf> {
+    let mut path = output_dir.to_path_buf();
+    for (i, part) in name.split('/').enumerate() {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            drive if i == 0 && drive.ends_with(':') => {}
+            part => path.push(part),
+        }
+    }
+    (path != output_dir).then_some(path)
+}
+
+fn run_reference_extract(
+    reference_bin: &Path,
+    input: &Path,
+    output_dir: &Path,
+    until: Option<&str>,
+    threads: usize,
+) -> Result<()> {
     let input_str = input
         .to_str()
         .ok_or_else(|| anyhow::anyhow!("input path contains non-utf8 bytes"))?;
-    let status = ProcessCommand::new(reference_bin)
-        .current_dir(output_dir)
-        .args(["x", input_str, "-force", "-t1"])
+    let mut cmd = ProcessCommand::new(reference_bin);
+    cmd.current_dir(output_dir)
+        .args(["x", input_str, "-force", &format!("-t{threads}")]);
+    if let Some(until) = until {
+        cmd.args(["-until", until]);
+    }
+    let status = cmd
         .status()
         .with_context(|| format!("running reference extractor {}", reference_bin.display()))?;
 
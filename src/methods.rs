@@ -0,0 +1,273 @@
+use crate::compiler::{ZpaqModel, compile};
+use crate::error::{Result, ZparsError};
+use crate::preprocess::{BwtEncoder, Lz77Encoder};
+
+/// Highest compression level accepted by `Method::new`.
+pub const MAX_METHOD: u8 = 5;
+
+/// Stored LZ77 codes, expanded by PCOMP into a 4 MiB history in `M`.
+/// See `Lz77Encoder` for the code format.
+const LZ77_CFG: &str = "\
+comp 0 0 0 22 0 (hh hm ph pm n)
+hcomp
+pcomp lz77 ;
+  (R1 is the state: 0 code, 1 literals, 2 distance bytes. R2 counts the
+  literals or distance bytes left, R3 is the match length, R4 the distance - 1)
+  a> 255 if a=0 r=a 1 halt endif (EOS)
+  c=a a=r 1 a== 0 if
+    a=c a>>= 6 a== 0 if
+      a=c a+= 1 r=a 2 a= 1 r=a 1
+    else
+      r=a 2 a=c a&= 63 a+= 4 r=a 3 a=0 r=a 4 a= 2 r=a 1
+    endif
+    halt
+  endif
+  a== 1 if
+    a=c *b=a b++ out
+    a=r 2 a-- r=a 2 a== 0 if a=0 r=a 1 endif
+    halt
+  endif
+  a=r 4 a<<= 8 a+=c r=a 4
+  a=r 2 a-- r=a 2 a== 0 if
+    d=r 4 a=b a-=d a-- c=a d=r 3 (copy from B - distance - 1)
+    do a=*c *b=a out c++ b++ d-- a=d a> 0 while
+    a=0 r=a 1
+  endif
+  halt
+end
+";
+
+/// Order 0 and 1 model of a BWT, inverted by PCOMP one chunk at a time.
+/// See `BwtEncoder` for the chunk layout.
+const BWT_CFG: &str = "\
+comp 1 0 22 22 2 (hh hm ph pm n)
+  0 icm 5     (order 0)
+  1 isse 16 0 (order 1)
+hcomp
+  *b=a a=0 hash d= 1 *d=a
+  halt
+pcomp bwt ;
+  (collect the chunk in M: n and the end row at 0 and 4, then the column)
+  a> 255 if b=0 halt endif (EOS)
+  *b=a b++
+  a=b a< 9 if halt endif
+  c= 3 a=*c a<<= 8 c-- a+=*c a<<= 8 c-- a+=*c a<<= 8 c-- a+=*c r=a 1
+  a+= 9 a==b ifnot halt endif
+  c= 7 a=*c a<<= 8 c-- a+=*c a<<= 8 c-- a+=*c a<<= 8 c-- a+=*c r=a 2
+  a+= 8 r=a 4
+  (count bytes in H[0...255], skipping the end row)
+  d=0 do *d=0 d++ a=d a> 255 until
+  c= 8 do d=r 4 a=c a==d ifnot d=*c *d++ endif c++ a=c a<b while
+  (H[x] = first row starting with byte x; row 0 starts with the end)
+  a= 1 r=a 5 d=0 do a=*d c=a a=r 5 *d=a a+=c r=a 5 d++ a=d a> 255 until
+  (H[256 + row of the next suffix] = row)
+  c= 8 do
+    d=r 4 a=c a==d if
+      d= 255 d++ a=r 2 *d=a
+    else
+      d=*c a=*d *d++ a+= 255 a++ d=a a=c a-= 8 *d=a
+    endif
+    c++ a=c a<b
+  while
+  (output n bytes following the links from the end row)
+  a=r 2 r=a 3 do
+    a=r 3 a+= 255 a++ d=a a=*d r=a 3
+    a+= 8 c=a a=*c out
+    a=r 1 a-- r=a 1 a> 0
+  while
+  b=0 halt
+end
+";
+
+/// Order 0-5 ISSE chain plus an order 7 match model (~111 MB per block).
+const MID_CFG: &str = "\
+comp 3 3 0 0 8 (hh hm ph pm n)
+  0 icm 5        (order 0...5 chain)
+  1 isse 13 0
+  2 isse 17 1
+  3 isse 18 2
+  4 isse 18 3
+  5 isse 19 4
+  6 match 22 24  (order 7)
+  7 mix 16 0 7 24 255  (order 1)
+hcomp
+  c++ *c=a b=c a=0 (save in rotating buffer M)
+  d= 1 hash *d=a   (orders 1...5 for isse)
+  b-- d++ hash *d=a
+  b-- d++ hash *d=a
+  b-- d++ hash *d=a
+  b-- d++ hash *d=a
+  b-- d++ hash b-- hash *d=a (order 7 for match)
+  d++ a=*c a<<= 8 *d=a       (order 1 for mix)
+  halt
+post
+  0
+end
+";
+
+/// Order 0-6 chain, match, word and sparse models, two mixers and an SSE
+/// stage (~170 MB per block).
+const MAX_CFG: &str = "\
+comp 5 9 0 0 19 (hh hm ph pm n)
+  0 const 160
+  1 icm 5             (order 0...6 chain)
+  2 isse 13 1
+  3 isse 16 2
+  4 isse 18 3
+  5 isse 19 4
+  6 isse 19 5
+  7 match 22 24 (order 8)
+  8 icm 17         (current word)
+  9 isse 19 8      (previous and current word)
+  10 icm 13           (sparse: byte 2)
+  11 icm 13           (sparse: bytes 3 and 4)
+  12 mix 16 0 12 24 255 (order 1)
+  13 mix 8 0 13 10 255  (order 0)
+  14 mix2 0 12 13 24 0
+  15 sse 8 14 32 255    (order 0)
+  16 mix2 8 14 15 16 255
+  17 sse 16 16 32 255   (order 1)
+  18 mix2 0 16 17 16 0
+hcomp
+  c++ *c=a b=c a=0 (save in rotating buffer M)
+  d= 2 hash *d=a   (orders 1...4)
+  b-- d++ hash *d=a
+  b-- d++ hash *d=a
+  b-- d++ hash *d=a
+  b-- d++ hash b-- hash *d=a (order 6)
+  b-- d++ hash b-- hash *d=a (order 8 for match)
+  (word hashes; H[20] keeps the previous word)
+  d= 8 a=*c a&~ 32 a-= 65 a< 26 if
+    a+= 65 a+=*d a*= 20 *d=a
+  else
+    a=*d a== 0 ifnot
+      d= 20 *d=a d= 8 *d=0
+    endif
+  endif
+  d= 20 a=*d a*= 11 d= 8 a+=*d d= 9 *d=a
+  d= 10 b=c b-- a=0 hash *d=a
+  d++ b-- a=0 hash b-- hash *d=a
+  d= 12 a=*c a<<= 8 *d=a (order 1 for mixer and SSE)
+  d= 17 *d=a
+  halt
+post
+  0
+end
+";
+
+/// Compression level of `compress --format zpaq` and `add`, compiled once
+/// and shared by every block written with it.
+///
+/// 0 is stored. 1 and 2 store LZ77 codes (2 searches longer hash chains and
+/// defers a match when the next byte starts a longer one). 3 codes a BWT
+/// with order 0 and 1 context models. 4 and 5 are context-mixing models
+/// without preprocessing, the order 0-5 chain and the full model above.
+/// Every level decodes with the configs embedded in its blocks; zpaq's E8E9
+/// filter and its per-block analysis of the input are not implemented.
+#[derive(Debug, Clone)]
+pub struct Method {
+    level: u8,
+    model: Option<ZpaqModel>,
+}
+
+impl Method {
+    pub fn new(level: u8) -> Result<Self> {
+        let cfg = match level {
+            0 => return Ok(Self { level, model: None }),
+            1 | 2 => LZ77_CFG,
+            3 => BWT_CFG,
+            4 => MID_CFG,
+            5 => MAX_CFG,
+            _ => return Err(ZparsError::InvalidOption("ZPAQ method level must be 0-5")),
+        };
+        Ok(Self {
+            level,
+            model: Some(compile(cfg)?),
+        })
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// Compiled model, or `None` for stored blocks.
+    pub fn model(&self) -> Option<&ZpaqModel> {
+        self.model.as_ref()
+    }
+
+    /// Fresh encoder for the transform undone by the model's PCOMP.
+    pub(crate) fn preprocessor(&self) -> Option<Preprocessor> {
+        match self.level {
+            1 => Some(Preprocessor::Lz77(Lz77Encoder::new(1, false))),
+            2 => Some(Preprocessor::Lz77(Lz77Encoder::new(32, true))),
+            3 => Some(Preprocessor::Bwt(BwtEncoder::default())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Preprocessor {
+    Lz77(Lz77Encoder),
+    Bwt(BwtEncoder),
+}
+
+impl Preprocessor {
+    pub(crate) fn write(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        match self {
+            Self::Lz77(enc) => enc.write(data, out),
+            Self::Bwt(enc) => enc.write(data, out),
+        }
+    }
+
+    /// Emit everything buffered for the current segment.
+    pub(crate) fn finish(&mut self, out: &mut Vec<u8>) {
+        match self {
+            Self::Lz77(enc) => enc.finish(out),
+            Self::Bwt(enc) => enc.finish(out),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::one_block;
+    use crate::zpaq::extract_bytes;
+
+    fn sample() -> Vec<u8> {
+        let mut data = b"The quick brown fox jumps over the lazy dog. ".repeat(200);
+        data.extend((0..600u32).map(|i| (i * i % 251) as u8));
+        data
+    }
+
+    #[test]
+    fn levels_roundtrip_and_compress() {
+        let data = sample();
+        for level in 0..=MAX_METHOD {
+            let archive = one_block(level, "a.txt", &data);
+            if level > 0 {
+                assert!(archive.len() < data.len() / 4, "level {level}");
+            }
+            let segs = extract_bytes(&archive).expect("extract");
+            assert_eq!(segs.len(), 1);
+            assert_eq!(segs[0].data, data, "level {level}");
+        }
+    }
+
+    #[test]
+    fn preprocessing_levels_carry_pcomp() {
+        for level in 0..=MAX_METHOD {
+            let method = Method::new(level).expect("method");
+            let pcomp = method.model().and_then(|m| m.pcomp.as_ref());
+            assert_eq!(pcomp.is_some(), (1..=3).contains(&level), "level {level}");
+            assert_eq!(method.preprocessor().is_some(), pcomp.is_some());
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_levels() {
+        assert!(Method::new(MAX_METHOD + 1).is_err());
+        assert!(Method::new(u8::MAX).is_err());
+    }
+}
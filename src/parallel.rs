@@ -0,0 +1,256 @@
+use crate::error::{Result, ZparsError};
+use crate::reader::{BlockChunks, ZpaqReader};
+use crate::zpaq::{ZpaqExtractedSegment, extract_reader, log_decoded_block, read_block_segments};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex, mpsc};
+use tracing::debug;
+
+/// Blocks read ahead per worker, bounding memory while the oldest block is
+/// still being decoded.
+const CHUNKS_PER_WORKER: usize = 2;
+
+/// `extract_reader` with up to `threads` blocks decoded at a time.
+///
+/// Blocks are independent, each with its own model, so the archive is split
+/// at block tags and the pieces are decoded on a pool of worker threads; the
+/// segments are returned in archive order, as `extract_reader` returns them.
+/// A block whose data happens to contain a block tag (such as a stored
+/// archive) is split there; its decoding runs out of input, and it is decoded
+/// again with the pieces that follow it. With `threads` of 0 or 1 this is
+/// `extract_reader`.
+pub fn extract_reader_threaded<R: Read>(
+    inner: R,
+    threads: usize,
+) -> Result<Vec<ZpaqExtractedSegment>> {
+    if threads <= 1 {
+        return extract_reader(inner);
+    }
+    let mut out = Vec::new();
+    extract_blocks_threaded(inner, threads, |segments| -> Result<()> {
+        out.extend(segments);
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+/// `extract_reader_threaded` handing each block's segments to `sink` in
+/// archive order as soon as the block is decoded, so only the blocks waiting
+/// for an earlier one to finish are held in memory.
+pub fn extract_blocks_threaded<R, E, F>(
+    inner: R,
+    threads: usize,
+    mut sink: F,
+) -> std::result::Result<(), E>
+where
+    R: Read,
+    E: From<ZparsError>,
+    F: FnMut(Vec<ZpaqExtractedSegment>) -> std::result::Result<(), E>,
+{
+    if threads <= 1 {
+        let mut reader = ZpaqReader::new(inner);
+        let mut block = 0usize;
+        while reader.next_block()?.is_some() {
+            let segments = read_block_segments(&mut reader)?;
+            log_decoded_block(block, &segments);
+            sink(segments)?;
+            block += 1;
+        }
+        return Ok(());
+    }
+    let mut chunks = BlockChunks::new(inner);
+    std::thread::scope(|scope| {
+        let (job_tx, job_rx) = mpsc::channel::<(usize, Arc<Vec<u8>>)>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (done_tx, done_rx) = mpsc::channel();
+        for _ in 0..threads {
+            let job_rx = Arc::clone(&job_rx);
+            let done_tx = done_tx.clone();
+            scope.spawn(move || {
+                loop {
+                    let job = job_rx.lock().expect("job queue lock").recv();
+                    let Ok((index, chunk)) = job else {
+                        break;
+                    };
+                    if done_tx.send((index, decode_chunk(&chunk))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // Chunks sent to the workers and not yet emitted, and their results.
+        let mut pending: BTreeMap<usize, Arc<Vec<u8>>> = BTreeMap::new();
+        let mut results: BTreeMap<usize, Decoded> = BTreeMap::new();
+        let (mut read, mut next, mut eof) = (0usize, 0usize, false);
+        let mut blocks = 0usize;
+        loop {
+            while !eof && pending.len() < threads * CHUNKS_PER_WORKER {
+                match chunks.next_chunk()? {
+                    Some((_, chunk)) => {
+                        let chunk = Arc::new(chunk);
+                        pending.insert(read, Arc::clone(&chunk));
+                        job_tx
+                            .send((read, chunk))
+                            .map_err(|_| ZparsError::Corrupt("block decoder exited"))?;
+                        read += 1;
+                    }
+                    None => eof = true,
+                }
+            }
+            if next == read {
+                break;
+            }
+            while !results.contains_key(&next) {
+                let (index, decoded) = done_rx
+                    .recv()
+                    .map_err(|_| ZparsError::Corrupt("block decoder exited"))?;
+                // Results of chunks decoded again as part of a split block
+                // are stale.
+                if index >= next {
+                    results.insert(index, decoded);
+                }
+            }
+
+            let mut last = next;
+            let mut decoded = results.remove(&next).expect("result present");
+            let mut data = Vec::new();
+            while let Decoded::Truncated(e) = decoded {
+                if data.is_empty() {
+                    data.extend_from_slice(&pending[&next]);
+                }
+                last += 1;
+                if last == read {
+                    match chunks.next_chunk()? {
+                        Some((_, chunk)) => pending.insert(read, Arc::new(chunk)),
+                        None => return Err(e.into()),
+                    };
+                    read += 1;
+                }
+                data.extend_from_slice(&pending[&last]);
+                debug!(
+                    chunk = next,
+                    pieces = last - next + 1,
+                    "decoding split block"
+                );
+                decoded = decode_chunk(&data);
+            }
+            let decoded_blocks = match decoded {
+                Decoded::Done(decoded_blocks) => decoded_blocks,
+                Decoded::Failed(e) | Decoded::Truncated(e) => return Err(e.into()),
+            };
+            for index in next..=last {
+                pending.remove(&index);
+                results.remove(&index);
+            }
+            next = last + 1;
+            for mut segments in decoded_blocks {
+                for s in &mut segments {
+                    s.block_index += blocks;
+                }
+                log_decoded_block(blocks, &segments);
+                sink(segments)?;
+                blocks += 1;
+            }
+        }
+        debug!(blocks, threads, "decoded blocks in parallel");
+        Ok(())
+    })
+}
+
+/// The segments of each block of a chunk (block indexes counted from the
+/// chunk's first block), or why the chunk could not be decoded.
+enum Decoded {
+    Done(Vec<Vec<ZpaqExtractedSegment>>),
+    /// The chunk ended inside a block.
+    Truncated(ZparsError),
+    Failed(ZparsError),
+}
+
+fn decode_chunk(chunk: &[u8]) -> Decoded {
+    let mut reader = ZpaqReader::new(chunk);
+    let mut blocks = Vec::new();
+    let mut decode = || -> Result<()> {
+        while reader.next_block()?.is_some() {
+            blocks.push(read_block_segments(&mut reader)?);
+        }
+        Ok(())
+    };
+    match decode() {
+        Ok(()) => Decoded::Done(blocks),
+        Err(e @ ZparsError::Io(_)) => Decoded::Failed(e),
+        Err(e) if reader.offset() >= chunk.len() => Decoded::Truncated(e),
+        Err(e) => Decoded::Failed(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::one_block;
+
+    #[test]
+    fn parallel_extraction_matches_sequential() {
+        let mut archive = Vec::new();
+        for i in 0..8u8 {
+            let data = format!("block {i} ").repeat(200 + usize::from(i) * 50);
+            let level = u8::from(i % 3 != 0);
+            archive.extend(one_block(level, &format!("f{i}"), data.as_bytes()));
+        }
+        // A stored block holding a whole archive is split at its tags.
+        let inner = [
+            one_block(0, "x", b"inner one"),
+            one_block(0, "y", b"inner two"),
+        ]
+        .concat();
+        archive.extend(one_block(0, "nested.zpaq", &inner));
+        archive.extend(one_block(1, "after", b"after the nested archive"));
+
+        let sequential = extract_reader(archive.as_slice()).expect("sequential");
+        for threads in [2, 8] {
+            let parallel = extract_reader_threaded(archive.as_slice(), threads).expect("parallel");
+            assert_eq!(parallel.len(), sequential.len());
+            for (p, s) in parallel.iter().zip(&sequential) {
+                assert_eq!(
+                    (p.block_index, &p.filename, &p.data),
+                    (s.block_index, &s.filename, &s.data)
+                );
+            }
+        }
+        assert_eq!(sequential.last().expect("last").block_index, 9);
+
+        // Errors inside a block are reported, not retried.
+        let mut bad = archive.clone();
+        bad.truncate(archive.len() - 3);
+        assert!(extract_reader_threaded(bad.as_slice(), 4).is_err());
+    }
+
+    #[test]
+    fn sink_receives_each_block_in_order() {
+        let mut archive = Vec::new();
+        for i in 0..6u8 {
+            let data = format!("block {i} ").repeat(100);
+            archive.extend(one_block(1, &format!("f{i}"), data.as_bytes()));
+        }
+        for threads in [1, 3] {
+            let mut names = Vec::new();
+            extract_blocks_threaded(archive.as_slice(), threads, |segments| -> Result<()> {
+                assert_eq!(segments.len(), 1);
+                names.push((segments[0].block_index, segments[0].filename.clone()));
+                Ok(())
+            })
+            .expect("extract");
+            let expected: Vec<_> = (0..6).map(|i| (i, format!("f{i}"))).collect();
+            assert_eq!(names, expected, "threads {threads}");
+        }
+
+        // An error from the sink stops extraction.
+        let mut calls = 0;
+        let res = extract_blocks_threaded(archive.as_slice(), 2, |_| -> Result<()> {
+            calls += 1;
+            Err(ZparsError::InvalidOption("stop"))
+        });
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::error::Result;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Whether `path` names a multi-part archive: a name with `?` wildcards,
+/// like zpaq's `backup??.zpaq` for `backup01.zpaq`, `backup02.zpaq`, ...
+pub fn is_multi_part(path: &Path) -> bool {
+    path.to_str().is_some_and(|s| s.contains('?'))
+}
+
+/// Path of part `n` of a multi-part archive. As in zpaq, the digits of `n`
+/// replace the `?`s from right to left, padded with zeros.
+pub fn part_path(pattern: &Path, n: u32) -> PathBuf {
+    let Some(pattern) = pattern.to_str() else {
+        return pattern.to_path_buf();
+    };
+    let mut n = n;
+    let mut out: Vec<char> = pattern.chars().collect();
+    for c in out.iter_mut().rev() {
+        if *c == '?' {
+            *c = char::from(b'0' + (n % 10) as u8);
+            n /= 10;
+        }
+    }
+    PathBuf::from(out.into_iter().collect::<String>())
+}
+
+/// The existing parts of an archive, in order: parts 1, 2, ... up to the
+/// first missing one, or `path` itself when it has no wildcards.
+pub fn part_paths(path: &Path) -> Vec<PathBuf> {
+    if !is_multi_part(path) {
+        return vec![path.to_path_buf()];
+    }
+    (1..)
+        .map(|n| part_path(path, n))
+        .take_while(|p| p.is_file())
+        .collect()
+}
+
+/// Reads the parts of an archive back to back as one stream.
+pub struct PartsReader {
+    parts: std::vec::IntoIter<PathBuf>,
+    current: Option<File>,
+}
+
+impl PartsReader {
+    /// Open `path`, expanding `?` wildcards into its parts. Fails when no
+    /// part exists.
+    pub fn open(path: &Path) -> Result<Self> {
+        let parts = part_paths(path);
+        let Some(first) = parts.first() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no parts of {} found", path.display()),
+            )
+            .into());
+        };
+        let current = Some(File::open(first)?);
+        let mut parts = parts.into_iter();
+        parts.next();
+        Ok(Self { parts, current })
+    }
+}
+
+impl Read for PartsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(file) = &mut self.current {
+            let n = file.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.current = match self.parts.next() {
+                Some(path) => Some(File::open(path)?),
+                None => None,
+            };
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_parts_and_reads_them_in_order() {
+        assert_eq!(part_path(Path::new("b??.zpaq"), 7), Path::new("b07.zpaq"));
+        assert_eq!(
+            part_path(Path::new("b?-?.zpaq"), 12),
+            Path::new("b1-2.zpaq")
+        );
+        assert_eq!(part_path(Path::new("b?.zpaq"), 12), Path::new("b2.zpaq"));
+        assert!(!is_multi_part(Path::new("b.zpaq")));
+
+        let dir = tempfile::tempdir().expect("tempdir");
+        let pattern = dir.path().join("backup??.zpaq");
+        assert!(PartsReader::open(&pattern).is_err());
+        std::fs::write(part_path(&pattern, 1), b"first ").expect("part 1");
+        std::fs::write(part_path(&pattern, 2), b"").expect("part 2");
+        std::fs::write(part_path(&pattern, 3), b"third").expect("part 3");
+        // Part 5 is not reached past the missing part 4.
+        std::fs::write(part_path(&pattern, 5), b"orphan").expect("part 5");
+
+        assert_eq!(part_paths(&pattern).len(), 3);
+        let mut data = String::new();
+        PartsReader::open(&pattern)
+            .expect("open")
+            .read_to_string(&mut data)
+            .expect("read");
+        assert_eq!(data, "first third");
+    }
+}
@@ -0,0 +1,602 @@
+use crate::coder::BitPredictor;
+use crate::error::{Result, ZparsError};
+use crate::statetable::state_table;
+use crate::zpaq::{COMP_SIZE, ZpaqBlockHeader};
+use crate::zpaql::Zpaql;
+use std::sync::OnceLock;
+use tracing::debug;
+
+const CONS: u8 = 1;
+const CM: u8 = 2;
+const ICM: u8 = 3;
+const MATCH: u8 = 4;
+const AVG: u8 = 5;
+const MIX2: u8 = 6;
+const MIX: u8 = 7;
+const ISSE: u8 = 8;
+const SSE: u8 = 9;
+
+/// Model-independent lookup tables shared by every predictor.
+struct Tables {
+    stretch: Vec<i16>,
+    squash: Vec<i16>,
+    dt2k: [i32; 256],
+    dt: [i32; 1024],
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let stretch = (0..32768)
+            .map(|i| {
+                let x = ((f64::from(i) + 0.5) / (32767.5 - f64::from(i))).ln();
+                ((x * 64.0 + 0.5 + 100000.0) as i32 - 100000) as i16
+            })
+            .collect();
+        let squash = (0..4096)
+            .map(|i| (32768.0 / (1.0 + (f64::from(i - 2048) * (-1.0 / 64.0)).exp())) as i16)
+            .collect();
+        let mut dt2k = [0i32; 256];
+        for (i, v) in dt2k.iter_mut().enumerate().skip(1) {
+            *v = 2048 / i as i32;
+        }
+        let mut dt = [0i32; 1024];
+        for (i, v) in dt.iter_mut().enumerate() {
+            *v = (1 << 17) / (i as i32 * 2 + 3) * 2;
+        }
+        Tables {
+            stretch,
+            squash,
+            dt2k,
+            dt,
+        }
+    })
+}
+
+/// `x -> floor(32768 / (1 + exp(-x / 64)))` for `x` in -2048..=2047.
+fn squash(x: i32) -> i32 {
+    i32::from(tables().squash[(x + 2048) as usize])
+}
+
+/// `x -> round(64 * ln((x + 0.5) / (32767.5 - x)))`, the inverse of `squash`.
+fn stretch(x: u32) -> i32 {
+    i32::from(tables().stretch[x as usize])
+}
+
+fn clamp2k(x: i32) -> i32 {
+    x.clamp(-2048, 2047)
+}
+
+fn clamp512k(x: i32) -> i32 {
+    x.clamp(-(1 << 19), (1 << 19) - 1)
+}
+
+/// One model component and its adaptive state. Field use depends on `kind`,
+/// following libzpaq's layout.
+#[derive(Debug, Clone, Default)]
+struct Component {
+    kind: u8,
+    args: [u8; 5],
+    limit: u32,
+    cxt: u32,
+    a: u32,
+    b: u32,
+    c: u32,
+    cm: Vec<u32>,
+    ht: Vec<u8>,
+    a16: Vec<u16>,
+}
+
+impl Component {
+    fn cm_at(&self, i: u32) -> u32 {
+        self.cm[i as usize & (self.cm.len() - 1)]
+    }
+
+    fn cm_mut(&mut self, i: u32) -> &mut u32 {
+        let mask = self.cm.len() - 1;
+        &mut self.cm[i as usize & mask]
+    }
+
+    fn ht_at(&self, i: u32) -> u8 {
+        self.ht[i as usize & (self.ht.len() - 1)]
+    }
+
+    fn ht_mut(&mut self, i: u32) -> &mut u8 {
+        let mask = self.ht.len() - 1;
+        &mut self.ht[i as usize & mask]
+    }
+}
+
+/// Native ZPAQ context-mixing predictor.
+///
+/// Builds the components described by a block header's COMP section and runs
+/// its HCOMP program after every byte to refresh the component contexts.
+#[derive(Debug, Clone)]
+pub struct Predictor {
+    comps: Vec<Component>,
+    p: Vec<i32>,
+    h: Vec<u32>,
+    c8: u32,
+    hmap4: u32,
+    z: Zpaql,
+}
+
+impl Predictor {
+    pub fn new(header: &ZpaqBlockHeader) -> Result<Self> {
+        let st = state_table();
+        let n = usize::from(header.n_components);
+        let mut comps = Vec::with_capacity(n);
+        let mut p = vec![0i32; n];
+        let mut cp = 0usize;
+
+        for (i, pi) in p.iter_mut().enumerate() {
+            let kind = *header
+                .comp
+                .get(cp)
+                .ok_or(ZparsError::Corrupt("COMP overflows header"))?;
+            let size = COMP_SIZE
+                .get(usize::from(kind))
+                .copied()
+                .filter(|&s| s > 0)
+                .ok_or(ZparsError::Corrupt("invalid component type"))?;
+            let bytes = header
+                .comp
+                .get(cp..cp + usize::from(size))
+                .ok_or(ZparsError::Corrupt("component overflows header"))?;
+            let mut args = [0u8; 5];
+            args[..bytes.len() - 1].copy_from_slice(&bytes[1..]);
+            cp += usize::from(size);
+
+            let mut cr = Component {
+                kind,
+                args,
+                ..Component::default()
+            };
+            let idx = i as u8;
+            match kind {
+                CONS => *pi = (i32::from(args[0]) - 128) * 16,
+                CM => {
+                    cr.cm = sized(args[0], 0, "max size for CM is 32")?;
+                    cr.cm.fill(0x8000_0000);
+                    cr.limit = u32::from(args[1]) * 4;
+                }
+                ICM => {
+                    if args[0] > 26 {
+                        return Err(ZparsError::InvalidFormat("max size for ICM is 26"));
+                    }
+                    cr.limit = 1023;
+                    cr.cm = (0..256).map(|j| st.cminit(j)).collect();
+                    cr.ht = sized(args[0], 6, "max size for ICM is 26")?;
+                }
+                MATCH => {
+                    cr.cm = sized(args[0], 0, "max size for MATCH is 32 32")?;
+                    cr.ht = sized(args[1], 0, "max size for MATCH is 32 32")?;
+                    cr.ht[0] = 1;
+                }
+                AVG => {
+                    if args[0] >= idx || args[1] >= idx {
+                        return Err(ZparsError::InvalidFormat("AVG input out of range"));
+                    }
+                }
+                MIX2 => {
+                    if args[1] >= idx || args[2] >= idx {
+                        return Err(ZparsError::InvalidFormat("MIX2 input out of range"));
+                    }
+                    cr.a16 = sized(args[0], 0, "max size for MIX2 is 32")?;
+                    cr.a16.fill(32768);
+                    cr.c = cr.a16.len() as u32;
+                }
+                MIX => {
+                    let m = u32::from(args[2]);
+                    if args[1] >= idx || m < 1 || m > u32::from(idx - args[1]) {
+                        return Err(ZparsError::InvalidFormat("MIX inputs out of range"));
+                    }
+                    if args[0] > 32 {
+                        return Err(ZparsError::InvalidFormat("max size for MIX is 32"));
+                    }
+                    let rows = 1usize << args[0];
+                    cr.c = rows as u32;
+                    cr.cm = filled(rows * m as usize, 65536 / m)?;
+                }
+                ISSE => {
+                    if args[1] >= idx {
+                        return Err(ZparsError::InvalidFormat("ISSE j >= i"));
+                    }
+                    cr.ht = sized(args[0], 6, "max size for ISSE is 32")?;
+                    cr.cm = vec![0; 512];
+                    for j in 0..256 {
+                        cr.cm[j * 2] = 1 << 15;
+                        cr.cm[j * 2 + 1] = clamp512k(stretch(st.cminit(j) >> 8) * 1024) as u32;
+                    }
+                }
+                SSE => {
+                    if args[1] >= idx {
+                        return Err(ZparsError::InvalidFormat("SSE j >= i"));
+                    }
+                    if u32::from(args[2]) > u32::from(args[3]) * 4 {
+                        return Err(ZparsError::InvalidFormat("SSE start > limit*4"));
+                    }
+                    cr.cm = sized(args[0], 5, "max size for SSE is 32")?;
+                    for (j, v) in cr.cm.iter_mut().enumerate() {
+                        *v = ((squash((j & 31) as i32 * 64 - 992) as u32) << 17)
+                            | u32::from(args[2]);
+                    }
+                    cr.limit = u32::from(args[3]) * 4;
+                }
+                _ => return Err(ZparsError::Corrupt("invalid component type")),
+            }
+            comps.push(cr);
+        }
+
+        debug!(
+            components = n,
+            hh = header.hh,
+            hm = header.hm,
+            "initialized zpaq predictor"
+        );
+
+        Ok(Self {
+            comps,
+            p,
+            h: vec![0; n],
+            c8: 1,
+            hmap4: 1,
+            z: Zpaql::from_block_header(header)?,
+        })
+    }
+
+    /// Probability that the next bit is 1, as a 15-bit value.
+    pub fn predict(&mut self) -> u32 {
+        let t = tables();
+        let c8 = self.c8;
+        let hmap4 = self.hmap4;
+        for i in 0..self.comps.len() {
+            let pi = {
+                let p = &self.p;
+                let cr = &mut self.comps[i];
+                let args = cr.args;
+                match cr.kind {
+                    CONS => p[i],
+                    CM => {
+                        cr.cxt = self.h[i] ^ hmap4;
+                        stretch(cr.cm_at(cr.cxt) >> 17)
+                    }
+                    ICM => {
+                        if c8 == 1 || (c8 & 0xf0) == 16 {
+                            cr.c = find(&mut cr.ht, self.h[i].wrapping_add(16 * c8));
+                        }
+                        cr.cxt = u32::from(cr.ht[(cr.c + (hmap4 & 15)) as usize]);
+                        stretch(cr.cm[cr.cxt as usize] >> 8)
+                    }
+                    MATCH => {
+                        if cr.a == 0 {
+                            0
+                        } else {
+                            let byte = cr.ht_at(cr.limit.wrapping_sub(cr.b));
+                            cr.c = u32::from(byte >> (7 - cr.cxt)) & 1;
+                            let sign = 1 - 2 * cr.c as i32;
+                            stretch(((t.dt2k[cr.a as usize] * sign) & 32767) as u32)
+                        }
+                    }
+                    AVG => {
+                        let wt = i32::from(args[2]);
+                        (p[usize::from(args[0])] * wt + p[usize::from(args[1])] * (256 - wt)) >> 8
+                    }
+                    MIX2 => {
+                        cr.cxt = self.h[i].wrapping_add(c8 & u32::from(args[4])) & (cr.c - 1);
+                        let w = i32::from(cr.a16[cr.cxt as usize]);
+                        (w * p[usize::from(args[1])] + (65536 - w) * p[usize::from(args[2])]) >> 16
+                    }
+                    MIX => {
+                        let m = usize::from(args[2]);
+                        let row = self.h[i].wrapping_add(c8 & u32::from(args[4])) & (cr.c - 1);
+                        cr.cxt = row * m as u32;
+                        let wt = &cr.cm[cr.cxt as usize..cr.cxt as usize + m];
+                        let j0 = usize::from(args[1]);
+                        let dot: i32 = wt
+                            .iter()
+                            .zip(&p[j0..j0 + m])
+                            .map(|(&w, &pj)| ((w as i32) >> 8) * pj)
+                            .sum();
+                        clamp2k(dot >> 8)
+                    }
+                    ISSE => {
+                        if c8 == 1 || (c8 & 0xf0) == 16 {
+                            cr.c = find(&mut cr.ht, self.h[i].wrapping_add(16 * c8));
+                        }
+                        cr.cxt = u32::from(cr.ht[(cr.c + (hmap4 & 15)) as usize]);
+                        let w0 = cr.cm[cr.cxt as usize * 2] as i32;
+                        let w1 = cr.cm[cr.cxt as usize * 2 + 1] as i32;
+                        clamp2k((w0 * p[usize::from(args[1])] + w1 * 64) >> 16)
+                    }
+                    _ => {
+                        // SSE
+                        cr.cxt = self.h[i].wrapping_add(c8).wrapping_mul(32);
+                        let pq = (p[usize::from(args[1])] + 992).clamp(0, 1983);
+                        let wt = (pq & 63) as u32;
+                        cr.cxt = cr.cxt.wrapping_add((pq >> 6) as u32);
+                        let lo = cr.cm_at(cr.cxt) >> 10;
+                        let hi = cr.cm_at(cr.cxt.wrapping_add(1)) >> 10;
+                        let pr = stretch((lo * (64 - wt) + hi * wt) >> 13);
+                        cr.cxt = cr.cxt.wrapping_add(wt >> 5);
+                        pr
+                    }
+                }
+            };
+            self.p[i] = pi;
+        }
+        self.p.last().map_or(16384, |&last| squash(last) as u32)
+    }
+
+    /// Train every component on bit `y` and advance the bit/byte context.
+    pub fn update(&mut self, y: u32) -> Result<()> {
+        let t = tables();
+        let st = state_table();
+        let hmap4 = self.hmap4;
+        let yi = y as i32;
+        for i in 0..self.comps.len() {
+            let p = &self.p;
+            let cr = &mut self.comps[i];
+            let args = cr.args;
+            match cr.kind {
+                CM | SSE => train(cr, y, &t.dt),
+                ICM => {
+                    let slot = (cr.c + (hmap4 & 15)) as usize;
+                    cr.ht[slot] = st.next(cr.ht[slot], y);
+                    let pn = &mut cr.cm[cr.cxt as usize];
+                    *pn = pn.wrapping_add(((yi * 32767 - (*pn >> 8) as i32) >> 2) as u32);
+                }
+                MATCH => {
+                    if cr.c != y {
+                        cr.a = 0;
+                    }
+                    let limit = cr.limit;
+                    let b = cr.ht_mut(limit);
+                    *b = b.wrapping_add(*b).wrapping_add(y as u8);
+                    cr.cxt += 1;
+                    if cr.cxt == 8 {
+                        cr.cxt = 0;
+                        cr.limit = cr.limit.wrapping_add(1) & (cr.ht.len() as u32).wrapping_sub(1);
+                        let hi = self.h[i];
+                        if cr.a == 0 {
+                            cr.b = cr.limit.wrapping_sub(cr.cm_at(hi));
+                            if cr.b & (cr.ht.len() as u32).wrapping_sub(1) != 0 {
+                                while cr.a < 255
+                                    && cr.ht_at(cr.limit.wrapping_sub(cr.a + 1))
+                                        == cr.ht_at(
+                                            cr.limit
+                                                .wrapping_sub(cr.a)
+                                                .wrapping_sub(cr.b.wrapping_add(1)),
+                                        )
+                                {
+                                    cr.a += 1;
+                                }
+                            }
+                        } else if cr.a < 255 {
+                            cr.a += 1;
+                        }
+                        let limit = cr.limit;
+                        *cr.cm_mut(hi) = limit;
+                    }
+                }
+                MIX2 => {
+                    let err = ((yi * 32767 - squash(p[i])) * i32::from(args[3])) >> 5;
+                    let mut w = i32::from(cr.a16[cr.cxt as usize]);
+                    w += (err * (p[usize::from(args[1])] - p[usize::from(args[2])]) + (1 << 12))
+                        >> 13;
+                    cr.a16[cr.cxt as usize] = w.clamp(0, 65535) as u16;
+                }
+                MIX => {
+                    let m = usize::from(args[2]);
+                    let err = ((yi * 32767 - squash(p[i])) * i32::from(args[3])) >> 4;
+                    let j0 = usize::from(args[1]);
+                    let row = cr.cxt as usize;
+                    for (w, &pj) in cr.cm[row..row + m].iter_mut().zip(&p[j0..j0 + m]) {
+                        *w = clamp512k(*w as i32 + ((err * pj + 0x8000) >> 16)) as u32;
+                    }
+                }
+                ISSE => {
+                    let err = yi * 32767 - squash(p[i]);
+                    let k = cr.cxt as usize * 2;
+                    let w0 = cr.cm[k] as i32;
+                    let w1 = cr.cm[k + 1] as i32;
+                    cr.cm[k] =
+                        clamp512k(w0 + ((err * p[usize::from(args[1])] + 0x8000) >> 16)) as u32;
+                    cr.cm[k + 1] = clamp512k(w1 + ((err + 16) >> 5)) as u32;
+                    let slot = (cr.c + (hmap4 & 15)) as usize;
+                    cr.ht[slot] = st.next(cr.cxt as u8, y);
+                }
+                _ => {}
+            }
+        }
+
+        self.c8 = (self.c8 << 1) | y;
+        if self.c8 >= 256 {
+            self.z.run(self.c8 - 256)?;
+            self.hmap4 = 1;
+            self.c8 = 1;
+            for (i, h) in self.h.iter_mut().enumerate() {
+                *h = self.z.h(i);
+            }
+        } else if (16..32).contains(&self.c8) {
+            self.hmap4 = ((self.hmap4 & 0xf) << 5) | (y << 4) | 1;
+        } else {
+            self.hmap4 = (self.hmap4 & 0x1f0) | (((self.hmap4 & 0xf) * 2 + y) & 0xf);
+        }
+        Ok(())
+    }
+}
+
+impl BitPredictor for Predictor {
+    fn predict(&mut self) -> u32 {
+        Predictor::predict(self)
+    }
+
+    fn update(&mut self, y: u32) -> Result<()> {
+        Predictor::update(self, y)
+    }
+}
+
+/// Allocate `2^(bits + extra)` zeroed entries.
+fn sized<T: Clone + Default>(bits: u8, extra: u8, what: &'static str) -> Result<Vec<T>> {
+    if bits > 32 {
+        return Err(ZparsError::InvalidFormat(what));
+    }
+    filled(1usize << (u32::from(bits) + u32::from(extra)), T::default())
+}
+
+/// Allocate `n` copies of `value`, reporting a failed allocation as an error.
+fn filled<T: Clone>(n: usize, value: T) -> Result<Vec<T>> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(n)
+        .map_err(|_| ZparsError::Unsupported("component memory requirement too large"))?;
+    v.resize(n, value);
+    Ok(v)
+}
+
+/// Adjust the CM/SSE counter at `cr.cxt` toward bit `y`.
+fn train(cr: &mut Component, y: u32, dt: &[i32; 1024]) {
+    let limit = cr.limit;
+    let pn = cr.cm_mut(cr.cxt);
+    let count = *pn & 0x3ff;
+    let error = y as i32 * 32767 - (*pn >> 17) as i32;
+    let delta = (error.wrapping_mul(dt[count as usize]) & -1024) as u32;
+    *pn = pn
+        .wrapping_add(delta)
+        .wrapping_add(u32::from(count < limit));
+}
+
+/// Find the 16-byte row for context `cxt` in an ICM/ISSE hash table, replacing
+/// the least used of three candidate rows on a miss. Element 0 of each row
+/// holds a checksum, element 1 the priority.
+fn find(ht: &mut [u8], cxt: u32) -> u32 {
+    let sizebits = (ht.len() / 16).trailing_zeros();
+    let chk = ((cxt >> sizebits) & 255) as u8;
+    let h0 = cxt.wrapping_mul(16) as usize & (ht.len() - 16);
+    if ht[h0] == chk {
+        return h0 as u32;
+    }
+    let h1 = h0 ^ 16;
+    if ht[h1] == chk {
+        return h1 as u32;
+    }
+    let h2 = h0 ^ 32;
+    if ht[h2] == chk {
+        return h2 as u32;
+    }
+    let slot = if ht[h0 + 1] <= ht[h1 + 1] && ht[h0 + 1] <= ht[h2 + 1] {
+        h0
+    } else if ht[h1 + 1] < ht[h2 + 1] {
+        h1
+    } else {
+        h2
+    };
+    ht[slot..slot + 16].fill(0);
+    ht[slot] = chk;
+    slot as u32
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::coder::Decoder;
+    use crate::coder::tests::compress_with;
+
+    #[test]
+    fn squash_and_stretch_match_libzpaq_checksums() {
+        let mut stsum = 0u32;
+        for i in (0..32768).rev() {
+            stsum = stsum.wrapping_mul(3).wrapping_add(stretch(i) as u32);
+        }
+        let mut sqsum = 0u32;
+        for i in (0..4096).rev() {
+            sqsum = sqsum.wrapping_mul(3).wrapping_add(squash(i - 2048) as u32);
+        }
+        assert_eq!(stsum, 3_887_533_746);
+        assert_eq!(sqsum, 2_278_286_169);
+    }
+
+    /// Every component type, fed by an order-1 HCOMP that stores the same
+    /// context hash into H[0..n].
+    pub(crate) fn test_header() -> ZpaqBlockHeader {
+        let comp = vec![
+            CONS, 160, // 0
+            ICM, 8, // 1
+            ISSE, 10, 1, // 2
+            CM, 12, 20, // 3
+            MATCH, 12, 14, // 4
+            MIX, 8, 1, 4, 24, 255, // 5
+            SSE, 8, 5, 32, 255, // 6
+            AVG, 5, 6, 128, // 7
+            MIX2, 4, 5, 7, 24, 0, // 8
+        ];
+        // a<<= 8 ... hash the byte with d=0..8: d= 0, then (*d=a d++) x9
+        let mut hcomp = vec![207, 8, 95, 0];
+        for _ in 0..9 {
+            hcomp.extend_from_slice(&[112, 25]);
+        }
+        hcomp.extend_from_slice(&[56, 0]);
+        ZpaqBlockHeader {
+            start_offset: 0,
+            level: 1,
+            zpaql_type: 1,
+            hsize: (6 + comp.len() + hcomp.len()) as u16,
+            hh: 4,
+            hm: 0,
+            ph: 0,
+            pm: 0,
+            n_components: 9,
+            comp_bytes: comp.len() + 6,
+            hcomp_bytes: hcomp.len(),
+            segment_offset: 0,
+            comp,
+            hcomp,
+        }
+    }
+
+    #[test]
+    fn all_components_roundtrip_through_arithmetic_coder() {
+        let header = test_header();
+        let mut input = Vec::new();
+        for i in 0..2000u32 {
+            input.extend_from_slice(b"the quick brown fox ");
+            input.push((i.wrapping_mul(2_654_435_761) >> 24) as u8);
+        }
+
+        let mut pr = Predictor::new(&header).expect("predictor");
+        let coded = compress_with(&mut pr, &input);
+        assert!(coded.len() < input.len() / 4, "model should compress");
+
+        let mut pr = Predictor::new(&header).expect("predictor");
+        let mut dec = Decoder::new(true);
+        let mut src = coded.as_slice();
+        let mut restored = Vec::new();
+        loop {
+            let c = dec.decompress_modeled(&mut src, &mut pr).expect("decode");
+            if c < 0 {
+                break;
+            }
+            restored.push(c as u8);
+        }
+        assert_eq!(restored, input);
+    }
+
+    #[test]
+    fn rejects_forward_component_references() {
+        let mut header = test_header();
+        let j = header.comp.len() - 4; // MIX2 j
+        header.comp[j] = 8;
+        assert!(Predictor::new(&header).is_err());
+    }
+
+    #[test]
+    fn oversized_mix_is_an_error() {
+        let mut header = test_header();
+        let mix = header.comp.iter().position(|&b| b == MIX).expect("MIX");
+        header.comp[mix + 1] = 33;
+        assert!(matches!(
+            Predictor::new(&header),
+            Err(ZparsError::InvalidFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,362 @@
+//! Encoders for the transforms undone by the PCOMP programs in `methods`.
+
+/// LZ77 history reachable by a match; the PCOMP keeps it in `M` (`pm` 22).
+const LZ77_WINDOW_BITS: u8 = 22;
+const WINDOW: usize = 1 << LZ77_WINDOW_BITS;
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + 63;
+const MAX_LITERALS: usize = 64;
+const HASH_BITS: u32 = 18;
+const NONE: usize = usize::MAX;
+
+/// Input is encoded once this much is buffered beyond the window.
+const LZ77_CHUNK: usize = 1 << 20;
+
+/// `M` and `H` of the inverse BWT PCOMP hold `2^BWT_BITS` entries: a chunk
+/// with its 8-byte header in `M`, and 256 counters plus one link per byte of
+/// the transformed chunk in `H`.
+const BWT_BITS: u8 = 22;
+const BWT_CHUNK: usize = (1 << BWT_BITS) - 257;
+
+/// Byte-aligned LZ77 encoder.
+///
+/// Codes are `00LLLLLL` for a run of `L + 1` literals that follow, and
+/// `KKLLLLLL` (`K` of 1 to 3) for a match of `L + 4` bytes whose distance
+/// minus 1 follows in `K` big-endian bytes. Matches come from hash chains
+/// searched `depth` candidates deep; with `lazy`, a match is deferred when
+/// the next position has a longer one.
+#[derive(Debug, Clone)]
+pub(crate) struct Lz77Encoder {
+    depth: usize,
+    lazy: bool,
+    /// Input from absolute position `base`, including the history.
+    buf: Vec<u8>,
+    base: usize,
+    /// Absolute position of the next byte to encode.
+    pos: usize,
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    literals: Vec<u8>,
+}
+
+impl Lz77Encoder {
+    pub(crate) fn new(depth: usize, lazy: bool) -> Self {
+        Self {
+            depth,
+            lazy,
+            buf: Vec::new(),
+            base: 0,
+            pos: 0,
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; WINDOW],
+            literals: Vec::new(),
+        }
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.buf.extend_from_slice(data);
+        if self.base + self.buf.len() - self.pos >= LZ77_CHUNK {
+            self.encode(out, false);
+        }
+    }
+
+    /// Encode the rest of the segment and start over for the next one.
+    pub(crate) fn finish(&mut self, out: &mut Vec<u8>) {
+        self.encode(out, true);
+        self.flush_literals(out);
+        // Chain entries before `base` are never followed, so only the heads
+        // need clearing.
+        self.buf.clear();
+        self.base = self.pos;
+        self.head.fill(NONE);
+    }
+
+    /// Encode buffered input, keeping `MAX_MATCH + 1` bytes of lookahead
+    /// unless `last`, then drop history that is out of reach.
+    fn encode(&mut self, out: &mut Vec<u8>, last: bool) {
+        let end = self.base + self.buf.len();
+        let stop = if last {
+            end
+        } else {
+            end.saturating_sub(MAX_MATCH + 1)
+        };
+        while self.pos < stop {
+            let (mut len, mut dist) = self.find(self.pos);
+            if self.lazy && len >= MIN_MATCH && self.pos + 1 < stop {
+                let (next_len, _) = self.find(self.pos + 1);
+                if next_len > len {
+                    len = 0;
+                    dist = 0;
+                }
+            }
+            if len < MIN_MATCH {
+                self.insert(self.pos);
+                self.literals.push(self.buf[self.pos - self.base]);
+                if self.literals.len() == MAX_LITERALS {
+                    self.flush_literals(out);
+                }
+                self.pos += 1;
+                continue;
+            }
+            self.flush_literals(out);
+            let off = dist - 1;
+            let k = if off < 1 << 8 {
+                1
+            } else if off < 1 << 16 {
+                2
+            } else {
+                3
+            };
+            out.push(((k << 6) | (len - MIN_MATCH)) as u8);
+            out.extend_from_slice(&(off as u32).to_be_bytes()[4 - k..]);
+            for p in self.pos..self.pos + len {
+                self.insert(p);
+            }
+            self.pos += len;
+        }
+
+        let keep_from = self.pos.saturating_sub(WINDOW);
+        if keep_from >= self.base + WINDOW {
+            self.buf.drain(..keep_from - self.base);
+            self.base = keep_from;
+        }
+    }
+
+    /// Longest match for `pos` and its distance, or length 0.
+    fn find(&self, pos: usize) -> (usize, usize) {
+        let end = self.base + self.buf.len();
+        if pos + MIN_MATCH > end {
+            return (0, 0);
+        }
+        let max = MAX_MATCH.min(end - pos);
+        let cur = &self.buf[pos - self.base..pos - self.base + max];
+        let (mut best_len, mut best_dist) = (0, 0);
+        let mut cand = self.head[self.hash(pos)];
+        for _ in 0..self.depth {
+            if cand == NONE || cand < self.base || cand >= pos || pos - cand >= WINDOW {
+                break;
+            }
+            let old = &self.buf[cand - self.base..];
+            let len = cur.iter().zip(old).take_while(|(a, b)| a == b).count();
+            if len > best_len {
+                (best_len, best_dist) = (len, pos - cand);
+                if len == max {
+                    break;
+                }
+            }
+            cand = self.prev[cand & (WINDOW - 1)];
+        }
+        (best_len, best_dist)
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH > self.base + self.buf.len() {
+            return;
+        }
+        let h = self.hash(pos);
+        self.prev[pos & (WINDOW - 1)] = self.head[h];
+        self.head[h] = pos;
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let at = pos - self.base;
+        let word = u32::from_le_bytes(self.buf[at..at + 4].try_into().expect("4 bytes"));
+        (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn flush_literals(&mut self, out: &mut Vec<u8>) {
+        if self.literals.is_empty() {
+            return;
+        }
+        out.push((self.literals.len() - 1) as u8);
+        out.append(&mut self.literals);
+    }
+}
+
+/// Burrows-Wheeler transform in chunks of up to `BWT_CHUNK` bytes.
+///
+/// Each chunk of `n` bytes is written as `n` and the row of the end-of-chunk
+/// symbol (4 bytes each, LSB first), then the `n + 1` bytes of the last
+/// column of the sorted rotations; the byte in the end symbol's row is 0.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BwtEncoder {
+    buf: Vec<u8>,
+}
+
+impl BwtEncoder {
+    pub(crate) fn write(&mut self, mut data: &[u8], out: &mut Vec<u8>) {
+        while !data.is_empty() {
+            let n = (BWT_CHUNK - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() == BWT_CHUNK {
+                self.finish(out);
+            }
+        }
+    }
+
+    pub(crate) fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.buf.is_empty() {
+            return;
+        }
+        let sa = suffix_array(&self.buf);
+        let n = self.buf.len();
+        let row = sa.iter().position(|&s| s == 0).expect("suffix 0");
+        out.extend_from_slice(&(n as u32).to_le_bytes());
+        out.extend_from_slice(&(row as u32).to_le_bytes());
+        out.extend(sa.iter().map(|&s| match s as usize {
+            0 => 0,
+            s => self.buf[s - 1],
+        }));
+        self.buf.clear();
+    }
+}
+
+/// Suffix array of `s` followed by a unique smallest end symbol, so the
+/// result has `s.len() + 1` entries and starts with `s.len()`.
+///
+/// Prefix doubling: suffixes are sorted by their first `k` symbols, then by
+/// the rank pairs of `i` and `i + k`, with counting sorts, until all ranks
+/// differ.
+fn suffix_array(s: &[u8]) -> Vec<u32> {
+    let n = s.len() + 1;
+    let mut rank: Vec<u32> = s.iter().map(|&c| u32::from(c) + 1).collect();
+    rank.push(0);
+    let mut sa: Vec<u32> = (0..n as u32).collect();
+    sa.sort_unstable_by_key(|&i| rank[i as usize]);
+    let mut tmp = vec![0u32; n];
+    let mut count = vec![0usize; n.max(257) + 1];
+    rerank(&sa, &mut rank, &mut tmp, |r, i| r[i]);
+
+    let mut k = 1usize;
+    while (rank[sa[n - 1] as usize] as usize) < n - 1 {
+        // Order by the rank at `i + k` (suffixes shorter than `k` first),
+        // then stably by the rank at `i`.
+        let mut second = Vec::with_capacity(n);
+        second.extend((n - k.min(n)..n).map(|i| i as u32));
+        second.extend(
+            sa.iter()
+                .filter(|&&i| i as usize >= k)
+                .map(|&i| i - k as u32),
+        );
+        count.fill(0);
+        for &i in &second {
+            count[rank[i as usize] as usize + 1] += 1;
+        }
+        for r in 1..count.len() {
+            count[r] += count[r - 1];
+        }
+        for &i in &second {
+            let slot = &mut count[rank[i as usize] as usize];
+            sa[*slot] = i;
+            *slot += 1;
+        }
+        rerank(&sa, &mut rank, &mut tmp, |r, i| {
+            (r[i], r.get(i + k).copied().map_or(0, |v| v + 1))
+        });
+        k *= 2;
+    }
+    sa
+}
+
+/// Give suffixes in `sa` order dense ranks, equal where `key` is equal.
+fn rerank<K: PartialEq>(
+    sa: &[u32],
+    rank: &mut [u32],
+    tmp: &mut [u32],
+    key: impl Fn(&[u32], usize) -> K,
+) {
+    let mut r = 0u32;
+    tmp[sa[0] as usize] = 0;
+    for w in sa.windows(2) {
+        if key(rank, w[1] as usize) != key(rank, w[0] as usize) {
+            r += 1;
+        }
+        tmp[w[1] as usize] = r;
+    }
+    rank.copy_from_slice(tmp);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive_suffix_array(s: &[u8]) -> Vec<u32> {
+        let mut sa: Vec<u32> = (0..=s.len() as u32).collect();
+        sa.sort_by(|&a, &b| s[a as usize..].cmp(&s[b as usize..]));
+        sa
+    }
+
+    #[test]
+    fn suffix_array_matches_naive_sort() {
+        for s in [
+            &b""[..],
+            b"a",
+            b"banana",
+            b"mississippi",
+            &[0, 0, 0, 0, 255, 0, 0],
+            &[7; 100],
+        ] {
+            assert_eq!(suffix_array(s), naive_suffix_array(s), "{s:?}");
+        }
+        let text: Vec<u8> = (0..3000u32).map(|i| (i * i % 7 + i / 500) as u8).collect();
+        assert_eq!(suffix_array(&text), naive_suffix_array(&text));
+    }
+
+    #[test]
+    fn bwt_chunk_layout() {
+        let mut enc = BwtEncoder::default();
+        let mut out = Vec::new();
+        enc.write(b"banana", &mut out);
+        enc.finish(&mut out);
+        // Sorted rotations of "banana$": $, a$, ana$, anana$, banana$, na$, nana$.
+        let mut expected = vec![6, 0, 0, 0, 4, 0, 0, 0];
+        expected.extend_from_slice(b"annb\0aa");
+        assert_eq!(out, expected);
+    }
+
+    /// Decode LZ77 codes the way the PCOMP does.
+    fn lz77_decode(codes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < codes.len() {
+            let c = usize::from(codes[i]);
+            i += 1;
+            let len = c & 63;
+            match c >> 6 {
+                0 => {
+                    out.extend_from_slice(&codes[i..i + len + 1]);
+                    i += len + 1;
+                }
+                k => {
+                    let off = codes[i..i + k]
+                        .iter()
+                        .fold(0usize, |a, &b| (a << 8) | usize::from(b));
+                    i += k;
+                    let from = out.len() - off - 1;
+                    for j in 0..len + MIN_MATCH {
+                        out.push(out[from + j]);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn lz77_roundtrip_across_chunks() {
+        let mut data = b"abcabcabcabc the quick brown fox ".repeat(40_000);
+        data.extend((0..200_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8));
+        data.extend_from_within(..100_000);
+        for (depth, lazy) in [(1, false), (16, true)] {
+            let mut enc = Lz77Encoder::new(depth, lazy);
+            let mut codes = Vec::new();
+            for piece in data.chunks(70_000) {
+                enc.write(piece, &mut codes);
+            }
+            enc.finish(&mut codes);
+            assert!(codes.len() < data.len() / 3, "depth {depth}");
+            assert_eq!(lz77_decode(&codes), data, "depth {depth}");
+        }
+    }
+}
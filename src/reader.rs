@@ -0,0 +1,773 @@
+use crate::coder::{ByteSource, Decoder};
+use crate::error::{Result, ZparsError};
+use crate::predictor::Predictor;
+use crate::zpaq::{MAGIC_16, PassOrProgramPostProcessor, ZpaqBlockHeader, parse_header};
+use sha1::{Digest, Sha1};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use tracing::trace;
+
+/// Decoded bytes are handed to the caller's writer in chunks of this size.
+const FLUSH_BYTES: usize = 1 << 16;
+
+/// Streaming ZPAQ archive reader.
+///
+/// Scans the input for locator tags, parses block headers and decodes
+/// segments incrementally. Memory use is bounded by the model of the current
+/// block, not by the archive size. Like `inspect_bytes`, false magic matches
+/// (a tag not followed by a valid level and type) are skipped and scanning
+/// resumes right after the tag's first byte.
+///
+/// As in libzpaq, the locator tag is optional for a block that starts the
+/// stream or directly follows the end of the previous block: such a block
+/// may start with `zPQ`, as zpipe and other level 1 producers write it.
+pub struct ZpaqReader<R> {
+    src: Source<R>,
+    block_index: usize,
+    block: Option<Block>,
+    /// Whether the input is at the start or right after a block's end
+    /// marker, where a block without a locator tag may start.
+    at_block_end: bool,
+}
+
+/// Name and comment of a segment, returned before its data is decoded.
+#[derive(Debug, Clone)]
+pub struct ZpaqSegmentHeader {
+    pub block_index: usize,
+    pub filename: String,
+    pub comment: String,
+}
+
+struct Block {
+    header: ZpaqBlockHeader,
+    dec: Decoder,
+    pr: Option<Predictor>,
+    pp: PassOrProgramPostProcessor,
+    first_segment: bool,
+    in_segment: bool,
+    filename: String,
+    tagless: bool,
+    /// Hash and length of the current segment's data decoded so far.
+    hasher: Sha1,
+    decoded: usize,
+}
+
+/// The trailer of a decoded segment.
+struct SegmentEnd {
+    sha1: Option<[u8; 20]>,
+    mismatch: bool,
+}
+
+impl<R: Read> ZpaqReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            src: Source {
+                inner: BufReader::new(inner),
+                offset: 0,
+                pushback: Vec::new(),
+            },
+            block_index: 0,
+            block: None,
+            at_block_end: true,
+        }
+    }
+
+    /// Number of input bytes consumed so far.
+    pub fn offset(&self) -> usize {
+        self.src.offset
+    }
+
+    /// Abandon the current block and consume the next `n` input bytes
+    /// without scanning them, as zpaq does to pass over a transaction's `d`
+    /// blocks. The next block is expected to start right after them. Returns
+    /// false if the input ends first.
+    pub fn skip_input(&mut self, n: u64) -> Result<bool> {
+        self.block = None;
+        self.at_block_end = true;
+        Ok(self.src.skip(n)? == n)
+    }
+
+    /// Find and parse the next block header.
+    ///
+    /// An unfinished current block is abandoned; scanning resumes from the
+    /// current position, so its remaining data is searched for tags. A block
+    /// that had no tag is decoded to its end instead, since the block after
+    /// it may have no tag either.
+    pub fn next_block(&mut self) -> Result<Option<ZpaqBlockHeader>> {
+        if self.block.as_ref().is_some_and(|b| b.tagless) {
+            while self.next_segment()?.is_some() {}
+        }
+        self.block = None;
+        let mut tagless = std::mem::take(&mut self.at_block_end);
+        loop {
+            let prefix = if std::mem::take(&mut tagless) && self.find_tagless_start()? {
+                3
+            } else if self.find_magic()? {
+                MAGIC_16.len()
+            } else {
+                return Ok(None);
+            };
+            let at = self.src.offset - prefix;
+
+            let Some(level) = self.src.read_byte()? else {
+                return Ok(None);
+            };
+            if level != 1 && level != 2 {
+                self.src.unread(&[level]);
+                continue;
+            }
+            let Some(zpaql_type) = self.src.read_byte()? else {
+                return Ok(None);
+            };
+            if zpaql_type != 1 {
+                self.src.unread(&[level, zpaql_type]);
+                continue;
+            }
+
+            let mut h = Vec::with_capacity(7);
+            for _ in 0..7 {
+                h.push(self.src.next_byte("truncated ZPAQL header prefix")?);
+            }
+            let header_total = usize::from(u16::from_le_bytes([h[0], h[1]])) + 2;
+            while h.len() < header_total {
+                h.push(self.src.next_byte("truncated ZPAQL header")?);
+            }
+
+            let header = parse_header(at, prefix, level, zpaql_type, &h)?;
+            self.block = Some(Block {
+                header: header.clone(),
+                dec: Decoder::new(header.n_components != 0),
+                pr: None,
+                pp: PassOrProgramPostProcessor::new(header.ph, header.pm),
+                first_segment: true,
+                in_segment: false,
+                filename: String::new(),
+                tagless: prefix < MAGIC_16.len(),
+                hasher: Sha1::new(),
+                decoded: 0,
+            });
+            self.block_index += 1;
+            return Ok(Some(header));
+        }
+    }
+
+    /// Read the next segment header of the current block, or `None` once the
+    /// block's end marker is reached. Undecoded data of the previous segment
+    /// is skipped.
+    ///
+    /// Nothing is decoded yet, so a caller can look at the name and move on to
+    /// the next block without building the block's model.
+    pub fn next_segment(&mut self) -> Result<Option<ZpaqSegmentHeader>> {
+        if self.block.as_ref().is_some_and(|b| b.in_segment) {
+            self.read_segment(&mut std::io::sink())?;
+        }
+        let Some(block) = self.block.as_mut() else {
+            return Ok(None);
+        };
+
+        let Some((filename, comment)) = read_segment_header(&mut self.src)? else {
+            self.block = None;
+            self.at_block_end = true;
+            return Ok(None);
+        };
+        block.in_segment = true;
+        block.filename.clone_from(&filename);
+
+        Ok(Some(ZpaqSegmentHeader {
+            block_index: self.block_index - 1,
+            filename,
+            comment,
+        }))
+    }
+
+    /// PCOMP bytecode (including END) of the current block; `None` for PASS
+    /// or before its first segment header has been read.
+    ///
+    /// The program is coded at the start of the first segment's data, so this
+    /// builds the model and decodes it if that has not happened yet.
+    pub fn pcomp(&mut self) -> Result<Option<&[u8]>> {
+        if self.block.as_ref().is_some_and(|b| b.in_segment) {
+            self.start_data()?;
+        }
+        Ok(self.block.as_ref().and_then(|b| b.pp.program()))
+    }
+
+    /// Build the predictor and decode the postprocessor description, which
+    /// precede the first segment's data.
+    fn start_data(&mut self) -> Result<()> {
+        let Some(block) = self.block.as_mut().filter(|b| b.first_segment) else {
+            return Ok(());
+        };
+        block.first_segment = false;
+        if block.header.n_components != 0 {
+            block.pr = Some(Predictor::new(&block.header)?);
+        }
+        let mut sink = Vec::new();
+        while (block.pp.state() & 3) != 1 {
+            let c = decompress_byte(&mut block.dec, block.pr.as_mut(), &mut self.src)?;
+            block.pp.write(c, &mut sink)?;
+        }
+        Ok(())
+    }
+
+    /// Decode the current segment's data into `out` and read its trailer,
+    /// returning the stored SHA-1 if present. Data already decoded through
+    /// `segment_data` is not written again.
+    ///
+    /// A stored SHA-1 that does not match the decoded data is reported as
+    /// `ChecksumMismatch` after the whole segment is consumed, so reading can
+    /// continue with the next segment.
+    pub fn read_segment<W: Write>(&mut self, out: &mut W) -> Result<Option<[u8; 20]>> {
+        let mut buf = Vec::with_capacity(FLUSH_BYTES);
+        loop {
+            let end = self.decode_some(&mut buf)?;
+            out.write_all(&buf)?;
+            buf.clear();
+            if let Some(end) = end {
+                return self.finish_segment(end);
+            }
+        }
+    }
+
+    /// The current segment's data as a `Read`, decoded as it is read. Once
+    /// it returns 0 bytes the trailer has been checked; a SHA-1 mismatch is
+    /// an `InvalidData` error wrapping `ChecksumMismatch`.
+    pub fn segment_data(&mut self) -> SegmentData<'_, R> {
+        SegmentData {
+            reader: self,
+            buf: Vec::new(),
+            pos: 0,
+            end: None,
+            sha1: None,
+            done: false,
+        }
+    }
+
+    /// Iterate over the headers of the remaining blocks, skipping their
+    /// data.
+    pub fn blocks(&mut self) -> Blocks<'_, R> {
+        Blocks { reader: self }
+    }
+
+    /// Iterate over the headers of the remaining segments, across blocks.
+    /// The data of the segment last returned can be read with
+    /// `Segments::data`; it is skipped otherwise.
+    pub fn segments(&mut self) -> Segments<'_, R> {
+        Segments { reader: self }
+    }
+
+    /// Decode at least `FLUSH_BYTES` more of the current segment into `out`,
+    /// or up to its end, which reads the trailer.
+    fn decode_some(&mut self, out: &mut Vec<u8>) -> Result<Option<SegmentEnd>> {
+        if self.block.as_ref().is_some_and(|b| b.in_segment) {
+            self.start_data()?;
+        }
+        let Some(block) = self.block.as_mut().filter(|b| b.in_segment) else {
+            return Err(ZparsError::InvalidFormat("no segment to read"));
+        };
+
+        let from = out.len();
+        let mut c = 0;
+        while out.len() - from < FLUSH_BYTES && c >= 0 {
+            c = decompress_byte(&mut block.dec, block.pr.as_mut(), &mut self.src)?;
+            block.pp.write(c, out)?;
+        }
+        block.hasher.update(&out[from..]);
+        block.decoded += out.len() - from;
+        if c >= 0 {
+            return Ok(None);
+        }
+
+        let seg_end = self.src.next_byte("segment end marker")?;
+        let sha1 = if seg_end == 254 {
+            None
+        } else if seg_end == 253 {
+            let mut sum = [0u8; 20];
+            for b in &mut sum {
+                *b = self.src.next_byte("sha1 byte")?;
+            }
+            Some(sum)
+        } else {
+            return Err(ZparsError::Corrupt("missing end-of-segment marker"));
+        };
+        block.in_segment = false;
+        let hash = std::mem::take(&mut block.hasher).finalize();
+        trace!(
+            block = self.block_index - 1,
+            bytes = std::mem::take(&mut block.decoded),
+            "decoded segment"
+        );
+        Ok(Some(SegmentEnd {
+            sha1,
+            mismatch: sha1.is_some_and(|sum| sum[..] != hash[..]),
+        }))
+    }
+
+    /// The stored SHA-1 of a segment that ended, or `ChecksumMismatch`.
+    fn finish_segment(&mut self, end: SegmentEnd) -> Result<Option<[u8; 20]>> {
+        if end.mismatch {
+            let filename = self
+                .block
+                .as_mut()
+                .map(|b| std::mem::take(&mut b.filename))
+                .unwrap_or_default();
+            return Err(ZparsError::ChecksumMismatch {
+                block_index: self.block_index - 1,
+                filename,
+            });
+        }
+        Ok(end.sha1)
+    }
+
+    /// Consume `zPQ` if the input continues with it; otherwise consume
+    /// nothing.
+    fn find_tagless_start(&mut self) -> Result<bool> {
+        let mut read = Vec::with_capacity(3);
+        for &expected in &MAGIC_16[13..] {
+            match self.src.read_byte()? {
+                Some(b) => {
+                    read.push(b);
+                    if b != expected {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        if read == MAGIC_16[13..] {
+            return Ok(true);
+        }
+        self.src.unread(&read);
+        Ok(false)
+    }
+
+    /// Consume input up to and including the next 16-byte locator tag.
+    fn find_magic(&mut self) -> Result<bool> {
+        // The tag's first byte does not recur in it, so a mismatch only
+        // needs to check whether the byte restarts a match.
+        let mut matched = 0usize;
+        while matched < MAGIC_16.len() {
+            let Some(b) = self.src.read_byte()? else {
+                return Ok(false);
+            };
+            if b == MAGIC_16[matched] {
+                matched += 1;
+            } else {
+                matched = usize::from(b == MAGIC_16[0]);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Iterator over block headers; see `ZpaqReader::blocks`.
+pub struct Blocks<'a, R> {
+    reader: &'a mut ZpaqReader<R>,
+}
+
+impl<R: Read> Iterator for Blocks<'_, R> {
+    type Item = Result<ZpaqBlockHeader>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_block().transpose()
+    }
+}
+
+/// Iterator over segment headers; see `ZpaqReader::segments`.
+pub struct Segments<'a, R> {
+    reader: &'a mut ZpaqReader<R>,
+}
+
+impl<R: Read> Segments<'_, R> {
+    /// The data of the segment last returned, as a `Read`.
+    pub fn data(&mut self) -> SegmentData<'_, R> {
+        self.reader.segment_data()
+    }
+}
+
+impl<R: Read> Iterator for Segments<'_, R> {
+    type Item = Result<ZpaqSegmentHeader>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.next_segment() {
+                Ok(Some(seg)) => return Some(Ok(seg)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            match self.reader.next_block() {
+                Ok(Some(_)) => {}
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// A segment's data, decoded as it is read; see `ZpaqReader::segment_data`.
+/// Dropping it before the end abandons the rest of the segment.
+pub struct SegmentData<'a, R> {
+    reader: &'a mut ZpaqReader<R>,
+    buf: Vec<u8>,
+    pos: usize,
+    /// The trailer, checked once `buf` has been handed out.
+    end: Option<SegmentEnd>,
+    sha1: Option<[u8; 20]>,
+    done: bool,
+}
+
+impl<R> SegmentData<'_, R> {
+    /// The stored SHA-1, once the data has been read to the end.
+    pub fn sha1(&self) -> Option<[u8; 20]> {
+        self.sha1
+    }
+}
+
+impl<R: Read> Read for SegmentData<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if self.pos < self.buf.len() {
+                let n = out.len().min(self.buf.len() - self.pos);
+                out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if let Some(end) = self.end.take() {
+                self.done = true;
+                self.sha1 = self.reader.finish_segment(end).map_err(into_io)?;
+            }
+            if self.done {
+                return Ok(0);
+            }
+            self.buf.clear();
+            self.pos = 0;
+            self.end = self.reader.decode_some(&mut self.buf).map_err(into_io)?;
+        }
+    }
+}
+
+/// Splits an archive into the bytes from one block tag (a locator tag
+/// followed by a valid level and type) to the next, so blocks can be decoded
+/// apart from each other.
+pub(crate) struct BlockChunks<R> {
+    inner: BufReader<R>,
+    offset: usize,
+    /// Bytes read past the end of the last chunk returned.
+    chunk: Vec<u8>,
+}
+
+impl<R: Read> BlockChunks<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            offset: 0,
+            chunk: Vec::new(),
+        }
+    }
+
+    /// The next run of bytes up to (not including) the next block tag, with
+    /// its offset. Only the first chunk can start without a tag.
+    pub(crate) fn next_chunk(&mut self) -> Result<Option<(usize, Vec<u8>)>> {
+        let tag = MAGIC_16.len() + 2;
+        // Progress towards a tag at the end of `chunk`: magic bytes matched,
+        // then the level and the type.
+        let mut matched = 0usize;
+        loop {
+            let buf = match self.inner.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if buf.is_empty() {
+                if self.chunk.is_empty() {
+                    return Ok(None);
+                }
+                let chunk = std::mem::take(&mut self.chunk);
+                let start = self.offset;
+                self.offset += chunk.len();
+                return Ok(Some((start, chunk)));
+            }
+            let mut used = 0;
+            for &b in buf {
+                used += 1;
+                self.chunk.push(b);
+                matched = match matched {
+                    16 if matches!(b, 1 | 2) => 17,
+                    17 if b == 1 => 18,
+                    m if m < 16 && b == MAGIC_16[m] => m + 1,
+                    _ => usize::from(b == MAGIC_16[0]),
+                };
+                if matched == tag && self.chunk.len() > tag {
+                    let next = self.chunk.split_off(self.chunk.len() - tag);
+                    let chunk = std::mem::replace(&mut self.chunk, next);
+                    self.inner.consume(used);
+                    let start = self.offset;
+                    self.offset += chunk.len();
+                    return Ok(Some((start, chunk)));
+                }
+            }
+            self.inner.consume(used);
+        }
+    }
+}
+
+fn into_io(e: ZparsError) -> std::io::Error {
+    match e {
+        ZparsError::Io(e) => e,
+        e => std::io::Error::new(ErrorKind::InvalidData, e),
+    }
+}
+
+/// Buffered input that tracks its offset and can push back a few bytes.
+struct Source<R> {
+    inner: BufReader<R>,
+    offset: usize,
+    pushback: Vec<u8>,
+}
+
+impl<R: Read> Source<R> {
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        if let Some(b) = self.pushback.pop() {
+            self.offset += 1;
+            return Ok(Some(b));
+        }
+        let b = loop {
+            match self.inner.fill_buf() {
+                Ok(buf) => break buf.first().copied(),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        if b.is_some() {
+            self.inner.consume(1);
+            self.offset += 1;
+        }
+        Ok(b)
+    }
+
+    /// Consume up to `n` bytes, returning how many there were.
+    fn skip(&mut self, n: u64) -> Result<u64> {
+        let from_pushback = self.pushback.len().min(n as usize);
+        self.pushback.truncate(self.pushback.len() - from_pushback);
+        let rest = n - from_pushback as u64;
+        let skipped = std::io::copy(&mut (&mut self.inner).take(rest), &mut std::io::sink())?;
+        let total = from_pushback as u64 + skipped;
+        self.offset += total as usize;
+        Ok(total)
+    }
+
+    fn unread(&mut self, bytes: &[u8]) {
+        self.pushback.extend(bytes.iter().rev());
+        self.offset -= bytes.len();
+    }
+}
+
+impl<R: Read> ByteSource for Source<R> {
+    fn next_byte(&mut self, what: &'static str) -> Result<u8> {
+        self.read_byte()?.ok_or(ZparsError::Corrupt(what))
+    }
+}
+
+/// Read `1 filename\0 comment\0 0`, or `None` at the end-of-block marker.
+pub(crate) fn read_segment_header<S: ByteSource>(src: &mut S) -> Result<Option<(String, String)>> {
+    let marker = src.next_byte("segment marker")?;
+    if marker == 255 {
+        return Ok(None);
+    }
+    if marker != 1 {
+        return Err(ZparsError::Corrupt(
+            "missing segment or end-of-block marker",
+        ));
+    }
+
+    let filename = read_cstr(src)?;
+    let comment = read_cstr(src)?;
+    if src.next_byte("reserved byte")? != 0 {
+        return Err(ZparsError::Corrupt("missing reserved byte after comment"));
+    }
+    Ok(Some((filename, comment)))
+}
+
+fn read_cstr<S: ByteSource>(src: &mut S) -> Result<String> {
+    let mut out = Vec::new();
+    loop {
+        let c = src.next_byte("cstr")?;
+        if c == 0 {
+            break;
+        }
+        out.push(c);
+    }
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+pub(crate) fn decompress_byte<S: ByteSource>(
+    dec: &mut Decoder,
+    pr: Option<&mut Predictor>,
+    src: &mut S,
+) -> Result<i32> {
+    match pr {
+        Some(pr) => dec.decompress_modeled(src, pr),
+        None => dec.decompress_stored(src),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::one_block;
+
+    /// Reader that returns at most one byte per call, to exercise buffering.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn stored_block_with_trailer(name: &str, payload: &[u8], trailer: &[u8]) -> Vec<u8> {
+        let mut buf = MAGIC_16.to_vec();
+        buf.extend_from_slice(&[2, 1]);
+        buf.extend_from_slice(&7u16.to_le_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0]);
+        buf.push(1);
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(&[0, 0, 0]);
+        buf.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        buf.push(0); // PASS
+        buf.extend_from_slice(payload);
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.extend_from_slice(trailer);
+        buf.push(255);
+        buf
+    }
+
+    #[test]
+    fn streams_blocks_and_skips_false_tags() {
+        let mut archive = b"junk".to_vec();
+        // A tag followed by an invalid level whose byte restarts a real tag.
+        archive.extend_from_slice(&MAGIC_16);
+        archive.extend_from_slice(&one_block(0, "a", b"first"));
+        archive.extend_from_slice(&one_block(0, "b", b"second"));
+
+        let mut reader = ZpaqReader::new(Trickle(&archive));
+        let first = reader.next_block().expect("block").expect("some");
+        assert_eq!(first.start_offset, 4 + MAGIC_16.len());
+        let seg = reader.next_segment().expect("segment").expect("some");
+        assert_eq!(seg.filename, "a");
+        let mut data = Vec::new();
+        let sha1 = reader.read_segment(&mut data).expect("data");
+        assert_eq!(sha1, Some(Sha1::digest(b"first").into()));
+        assert_eq!(data, b"first");
+        assert!(reader.next_segment().expect("end").is_none());
+
+        let second = reader.next_block().expect("block").expect("some");
+        let first_len = one_block(0, "a", b"first").len();
+        assert_eq!(second.start_offset, first.start_offset + first_len);
+        let seg = reader.next_segment().expect("segment").expect("some");
+        assert_eq!((seg.block_index, seg.filename.as_str()), (1, "b"));
+        // Unread data is skipped by the next call.
+        assert!(reader.next_segment().expect("end").is_none());
+        assert!(reader.next_block().expect("eof").is_none());
+        assert_eq!(reader.offset(), archive.len());
+    }
+
+    #[test]
+    fn verifies_segment_sha1() {
+        let mut trailer = vec![253];
+        trailer.extend_from_slice(&Sha1::digest(b"payload"));
+        let good = stored_block_with_trailer("ok", b"payload", &trailer);
+        let mut reader = ZpaqReader::new(good.as_slice());
+        reader.next_block().expect("block");
+        reader.next_segment().expect("segment");
+        let sum = reader.read_segment(&mut Vec::new()).expect("verified");
+        assert_eq!(sum.map(|s| s[..] == trailer[1..]), Some(true));
+
+        let mut archive = stored_block_with_trailer("bad", b"pAyload", &trailer);
+        archive.extend_from_slice(&good);
+        let mut reader = ZpaqReader::new(archive.as_slice());
+        reader.next_block().expect("block");
+        reader.next_segment().expect("segment");
+        let err = reader.read_segment(&mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            err,
+            ZparsError::ChecksumMismatch { block_index: 0, ref filename } if filename == "bad"
+        ));
+        // The mismatching segment was fully consumed; reading continues.
+        assert!(reader.next_segment().expect("end").is_none());
+        assert!(reader.next_block().expect("block").is_some());
+
+        let report = crate::zpaq::verify_bytes(&archive).expect("verify");
+        assert_eq!((report.segments, report.verified), (2, 1));
+        assert_eq!(report.mismatches, [(0, "bad".to_string())]);
+    }
+
+    #[test]
+    fn iterates_lazily_and_reads_segment_data() {
+        let big: Vec<u8> = (0..3 * FLUSH_BYTES as u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let sha1_trailer = |data: &[u8]| {
+            let mut t = vec![253];
+            t.extend_from_slice(&Sha1::digest(data));
+            t
+        };
+        let trailer = sha1_trailer(&big);
+        let mut archive = stored_block_with_trailer("big", &big, &trailer);
+        archive.extend_from_slice(&one_block(0, "skipped", b"never decoded"));
+        archive.extend_from_slice(&stored_block_with_trailer(
+            "bad",
+            b"pAyload",
+            &sha1_trailer(b"payload"),
+        ));
+        archive.extend_from_slice(&one_block(0, "last", b"tail"));
+
+        let starts: Vec<_> = ZpaqReader::new(archive.as_slice())
+            .blocks()
+            .map(|b| b.expect("block").start_offset)
+            .collect();
+        assert_eq!(starts.len(), 4);
+
+        let mut reader = ZpaqReader::new(Trickle(&archive));
+        let mut segments = reader.segments();
+        let seg = segments.next().expect("some").expect("big");
+        assert_eq!(seg.filename, "big");
+        let mut data = segments.data();
+        let mut small = [0u8; 1000];
+        data.read_exact(&mut small).expect("read");
+        assert_eq!(small[..], big[..1000]);
+        let mut rest = Vec::new();
+        data.read_to_end(&mut rest).expect("rest");
+        assert_eq!(rest, big[1000..]);
+        assert_eq!(data.sha1().map(|s| s[..] == trailer[1..]), Some(true));
+
+        let names: Vec<_> = segments
+            .by_ref()
+            .take(2)
+            .map(|s| s.expect("segment").filename)
+            .collect();
+        assert_eq!(names, ["skipped", "bad"]);
+        let mut bad = Vec::new();
+        let err = segments.data().read_to_end(&mut bad).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(bad, b"pAyload");
+
+        // Stopping early leaves the rest of the input unread.
+        assert!(reader.offset() < archive.len());
+        let seg = reader.segments().next().expect("some").expect("last");
+        assert_eq!(seg.filename, "last");
+        assert!(reader.segments().next().is_none());
+    }
+
+    #[test]
+    fn truncated_header_is_corrupt() {
+        let block = one_block(0, "a", b"x");
+        let mut reader = ZpaqReader::new(&block[..MAGIC_16.len() + 5]);
+        assert!(matches!(
+            reader.next_block(),
+            Err(ZparsError::Corrupt("truncated ZPAQL header prefix"))
+        ));
+    }
+}
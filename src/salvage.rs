@@ -0,0 +1,303 @@
+use crate::error::{Result, ZparsError};
+use crate::parts::PartsReader;
+use crate::reader::{BlockChunks, ZpaqReader};
+use crate::zpaq::{MAGIC_16, ZpaqExtractedSegment};
+use std::collections::VecDeque;
+use std::io::Read;
+use std::path::Path;
+use tracing::{debug, warn};
+
+/// A region of an archive that `salvage_*` could not decode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZpaqDamage {
+    /// Byte range skipped, as offsets into the archive (after the salt of an
+    /// encrypted archive).
+    pub start: usize,
+    pub end: usize,
+    /// Block the range belongs to; `None` for data before the first block.
+    pub block_index: Option<usize>,
+    /// Segments whose data was lost, as far as their names could be read.
+    pub filenames: Vec<String>,
+    /// Why decoding stopped.
+    pub error: String,
+}
+
+/// Everything `salvage_*` recovered from an archive, and what it skipped.
+#[derive(Debug, Clone, Default)]
+pub struct ZpaqSalvage {
+    /// Segments that decoded completely and matched their SHA-1, if any.
+    pub segments: Vec<ZpaqExtractedSegment>,
+    pub damage: Vec<ZpaqDamage>,
+}
+
+/// Salvage the archive at `path`, which may name a multi-part archive.
+pub fn salvage_file(path: &Path) -> Result<ZpaqSalvage> {
+    salvage_reader(PartsReader::open(path)?)
+}
+
+pub fn salvage_bytes(data: &[u8]) -> Result<ZpaqSalvage> {
+    salvage_reader(data)
+}
+
+/// Decode every block that can be decoded, skipping damaged ones.
+///
+/// The archive is first split at block tags (a locator tag followed by a
+/// valid level and type), so a block is decoded from its own bytes only and
+/// damage cannot spread past the next tag. A block whose data happens to
+/// contain a tag (such as a stored archive) is split there; when its
+/// decoding runs out of input, it is decoded again with the pieces that
+/// follow it, which are kept as its data if that decodes cleanly. Within a
+/// block, the segments before a decoding error are kept; a segment whose
+/// SHA-1 does not match is dropped and decoding continues with the next one.
+/// Only I/O errors fail the call. One block, with any pieces joined to it,
+/// is held in memory at a time.
+pub fn salvage_reader<R: Read>(inner: R) -> Result<ZpaqSalvage> {
+    let mut chunks = BlockChunks::new(inner);
+    // Chunks read to join with a split block but not part of it.
+    let mut ahead: VecDeque<(usize, Vec<u8>)> = VecDeque::new();
+    let mut out = ZpaqSalvage::default();
+    let mut block_index = 0usize;
+    loop {
+        let next = match ahead.pop_front() {
+            Some(chunk) => Some(chunk),
+            None => chunks.next_chunk()?,
+        };
+        let Some((start, chunk)) = next else {
+            break;
+        };
+        // Only the first block can lack a tag, unless it follows another.
+        let tagged =
+            chunk.starts_with(&MAGIC_16) || (start == 0 && chunk.starts_with(&MAGIC_16[13..]));
+        if !tagged {
+            if !chunk.is_empty() {
+                out.damage.push(ZpaqDamage {
+                    start,
+                    end: start + chunk.len(),
+                    block_index: None,
+                    filenames: Vec::new(),
+                    error: "data before the first block".into(),
+                });
+            }
+            continue;
+        }
+        let mut result = salvage_chunk(start, &chunk, block_index);
+        let mut joined = chunk;
+        let mut pieces = 0usize;
+        while result.truncated {
+            if pieces == ahead.len() {
+                match chunks.next_chunk()? {
+                    Some(chunk) => ahead.push_back(chunk),
+                    None => break,
+                }
+            }
+            joined.extend_from_slice(&ahead[pieces].1);
+            pieces += 1;
+            let retry = salvage_chunk(start, &joined, block_index);
+            if retry.truncated {
+                continue;
+            }
+            if retry.salvage.damage.is_empty() {
+                debug!(block = block_index, pieces, "salvaged split block");
+                ahead.drain(..pieces);
+                result = retry;
+            }
+            break;
+        }
+        block_index += result.blocks;
+        out.segments.extend(result.salvage.segments);
+        out.damage.extend(result.salvage.damage);
+    }
+    for d in &out.damage {
+        warn!(
+            start = d.start,
+            end = d.end,
+            block = d.block_index,
+            error = %d.error,
+            "skipped damaged archive data"
+        );
+    }
+    Ok(out)
+}
+
+/// What `salvage_chunk` recovered from a chunk.
+struct ChunkSalvage {
+    salvage: ZpaqSalvage,
+    blocks: usize,
+    /// Whether decoding failed at the end of the chunk, inside a block.
+    truncated: bool,
+}
+
+/// Decode the blocks in `chunk`, the bytes from a block tag to the next tag:
+/// one block, and any blocks without a tag that directly follow it. Blocks
+/// are numbered from `first_block`.
+fn salvage_chunk(start: usize, chunk: &[u8], first_block: usize) -> ChunkSalvage {
+    let mut reader = ZpaqReader::new(chunk);
+    let mut out = ChunkSalvage {
+        salvage: ZpaqSalvage::default(),
+        blocks: 0,
+        truncated: false,
+    };
+    loop {
+        let index = first_block + out.blocks;
+        out.blocks += 1;
+        let damage =
+            |from: usize, to: usize, filenames: Vec<String>, error: ZparsError| ZpaqDamage {
+                start: start + from,
+                end: start + to,
+                block_index: Some(index),
+                filenames,
+                error: error.to_string(),
+            };
+        let block_start = reader.offset();
+        if let Err(e) = reader.next_block() {
+            out.truncated = reader.offset() >= chunk.len();
+            out.salvage
+                .damage
+                .push(damage(block_start, chunk.len(), Vec::new(), e));
+            return out;
+        }
+        let mut segments = 0usize;
+        loop {
+            let at = reader.offset();
+            let seg = match reader.next_segment() {
+                Ok(Some(seg)) => seg,
+                Ok(None) => break,
+                Err(e) => {
+                    out.truncated = reader.offset() >= chunk.len();
+                    out.salvage
+                        .damage
+                        .push(damage(at, chunk.len(), Vec::new(), e));
+                    return out;
+                }
+            };
+            let mut data = Vec::new();
+            match reader.read_segment(&mut data) {
+                Ok(sha1) => {
+                    segments += 1;
+                    out.salvage.segments.push(ZpaqExtractedSegment {
+                        block_index: index,
+                        filename: seg.filename,
+                        comment: seg.comment,
+                        data,
+                        sha1,
+                    });
+                }
+                Err(ZparsError::ChecksumMismatch { filename, .. }) => {
+                    let e = ZparsError::ChecksumMismatch {
+                        block_index: index,
+                        filename: filename.clone(),
+                    };
+                    out.salvage
+                        .damage
+                        .push(damage(at, reader.offset(), vec![filename], e));
+                }
+                Err(e) => {
+                    out.truncated = reader.offset() >= chunk.len();
+                    out.salvage
+                        .damage
+                        .push(damage(at, chunk.len(), vec![seg.filename], e));
+                    return out;
+                }
+            }
+        }
+        debug!(
+            block = index,
+            offset = start + block_start,
+            segments,
+            "salvaged block"
+        );
+
+        // Other bytes after the end of the block hold no block tag: a block
+        // whose tag was damaged.
+        let end = reader.offset();
+        if chunk[end..].starts_with(&MAGIC_16[13..]) {
+            continue;
+        }
+        if end < chunk.len() {
+            let e = ZparsError::Corrupt("data after end of block");
+            out.salvage
+                .damage
+                .push(damage(end, chunk.len(), Vec::new(), e));
+        }
+        return out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::one_block;
+
+    #[test]
+    fn skips_damaged_blocks_and_keeps_the_rest() {
+        let blocks = [
+            one_block(0, "a", &b"first block ".repeat(50)),
+            one_block(0, "b", &b"second block ".repeat(50)),
+            one_block(0, "c", &b"third block ".repeat(50)),
+            one_block(0, "d", &b"fourth block ".repeat(50)),
+        ];
+        let mut archive = blocks.concat();
+        let at = |i: usize| blocks[..i].iter().map(Vec::len).sum::<usize>();
+        // A flipped bit in b's data, and in d's locator tag.
+        archive[at(1) + 100] ^= 4;
+        archive[at(3) + 3] ^= 1;
+        assert!(crate::zpaq::extract_bytes(&archive).is_err());
+
+        let salvage = salvage_bytes(&archive).expect("salvage");
+        let names: Vec<_> = salvage.segments.iter().map(|s| &s.filename).collect();
+        assert_eq!(names, ["a", "c"]);
+        assert_eq!(salvage.segments[1].data, b"third block ".repeat(50));
+        assert_eq!(salvage.segments[1].block_index, 2);
+        assert_eq!(
+            salvage.damage[0],
+            ZpaqDamage {
+                start: at(1) + 27, // after the block header
+                end: at(2) - 1,
+                block_index: Some(1),
+                filenames: vec!["b".into()],
+                error: "SHA-1 mismatch in block 1, segment \"b\"".into(),
+            }
+        );
+        // d's bytes follow c's end of block, with no tag to start them.
+        assert_eq!(
+            (salvage.damage[1].start, salvage.damage[1].end),
+            (at(3), archive.len())
+        );
+        assert_eq!(salvage.damage[1].block_index, Some(2));
+        assert_eq!(salvage.damage.len(), 2);
+    }
+
+    #[test]
+    fn keeps_blocks_whose_data_holds_a_tag() {
+        let inner = [one_block(0, "x", b"inner x"), one_block(0, "y", b"inner y")].concat();
+        let stored = one_block(0, "inner.zpaq", &inner);
+        let last = one_block(0, "c", b"last");
+        let archive = [one_block(0, "a", b"first"), stored.clone(), last.clone()].concat();
+
+        let salvage = salvage_bytes(&archive).expect("salvage");
+        assert_eq!(salvage.damage, []);
+        let names: Vec<_> = salvage.segments.iter().map(|s| &s.filename).collect();
+        assert_eq!(names, ["a", "inner.zpaq", "c"]);
+        assert_eq!(salvage.segments[1].data, inner);
+        assert_eq!(salvage.segments[2].block_index, 2);
+
+        // Cut short, the block does not decode with the pieces after it
+        // either; they are salvaged on their own.
+        let cut = [&stored[..stored.len() - 10], &last].concat();
+        let salvage = salvage_bytes(&cut).expect("salvage");
+        let names: Vec<_> = salvage.segments.iter().map(|s| &s.filename).collect();
+        assert_eq!(names.last().map(|n| n.as_str()), Some("c"));
+        assert_eq!(salvage.damage[0].block_index, Some(0));
+    }
+
+    #[test]
+    fn reports_data_before_the_first_block() {
+        let mut archive = b"garbage".to_vec();
+        archive.extend(one_block(0, "a", b"kept"));
+        let salvage = salvage_bytes(&archive).expect("salvage");
+        assert_eq!(salvage.segments[0].data, b"kept");
+        assert_eq!((salvage.damage[0].start, salvage.damage[0].end), (0, 7));
+        assert_eq!(salvage.damage[0].block_index, None);
+        assert!(salvage_bytes(b"").expect("empty").damage.is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+use std::sync::OnceLock;
+
+/// Bit-history states for ICM and ISSE components, generated the same way as
+/// libzpaq's `StateTable`.
+///
+/// Each state stands for a bounded count of zeros (`n0`) and ones (`n1`) seen
+/// in a context. Counts that have a recent bit of either value get two states,
+/// one per last bit. `ns[s * 4 + y]` is the next state after bit `y` and
+/// `ns[s * 4 + 2..4]` hold `n0` and `n1`.
+#[derive(Debug, Clone)]
+pub struct StateTable {
+    ns: [u8; 1024],
+}
+
+/// The table shared by all predictors; it is model independent.
+pub fn state_table() -> &'static StateTable {
+    static TABLE: OnceLock<StateTable> = OnceLock::new();
+    TABLE.get_or_init(StateTable::new)
+}
+
+impl Default for StateTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateTable {
+    pub fn new() -> Self {
+        const N: usize = 50;
+        // (n0, n1, last bit) -> state number, assigned by increasing n0 + n1.
+        let mut t = vec![[[0u8; 2]; N]; N];
+        let mut state = 0i32;
+        for i in 0..N as i32 {
+            for n1 in 0..=i {
+                let n0 = i - n1;
+                let n = Self::num_states(n0, n1);
+                if n > 0 {
+                    t[n0 as usize][n1 as usize][0] = state as u8;
+                    t[n0 as usize][n1 as usize][1] = (state + n - 1) as u8;
+                    state += n;
+                }
+            }
+        }
+
+        let mut ns = [0u8; 1024];
+        for n0 in 0..N as i32 {
+            for n1 in 0..N as i32 {
+                for y in 0..Self::num_states(n0, n1) {
+                    let s = usize::from(t[n0 as usize][n1 as usize][y as usize]);
+                    let (mut s0, mut s1) = (n0, n1);
+                    Self::next_state(&mut s0, &mut s1, 0);
+                    ns[s * 4] = t[s0 as usize][s1 as usize][0];
+                    let (mut s0, mut s1) = (n0, n1);
+                    Self::next_state(&mut s0, &mut s1, 1);
+                    ns[s * 4 + 1] = t[s0 as usize][s1 as usize][1];
+                    ns[s * 4 + 2] = n0 as u8;
+                    ns[s * 4 + 3] = n1 as u8;
+                }
+            }
+        }
+        Self { ns }
+    }
+
+    /// Next state after observing bit `y` in `state`.
+    pub fn next(&self, state: u8, y: u32) -> u8 {
+        self.ns[usize::from(state) * 4 + y as usize]
+    }
+
+    /// Bounded `(n0, n1)` counts represented by `state`.
+    pub fn counts(&self, state: u8) -> (u8, u8) {
+        let s = usize::from(state) * 4;
+        (self.ns[s + 2], self.ns[s + 3])
+    }
+
+    /// Initial probability of a 1 in `state`, scaled by 2^23.
+    pub fn cminit(&self, state: usize) -> u32 {
+        let n0 = u32::from(self.ns[state * 4 + 2]);
+        let n1 = u32::from(self.ns[state * 4 + 3]);
+        ((n1 * 2 + 1) << 22) / (n0 + n1 + 1)
+    }
+
+    /// Number of states (0..=2) representing `n0` zeros and `n1` ones.
+    fn num_states(n0: i32, n1: i32) -> i32 {
+        const BOUND: [i32; 6] = [20, 48, 15, 8, 6, 5];
+        if n0 < n1 {
+            return Self::num_states(n1, n0);
+        }
+        if n0 < 0 || n1 < 0 || n1 >= BOUND.len() as i32 || n0 > BOUND[n1 as usize] {
+            return 0;
+        }
+        1 + i32::from(n1 > 0 && n0 + n1 <= 17)
+    }
+
+    /// Reduce the opposite count after a bit is observed.
+    fn discount(n0: &mut i32) {
+        let n = *n0;
+        *n0 = [1, 2, 3, 4, 5, 7, 8].iter().filter(|&&t| n >= t).count() as i32;
+    }
+
+    fn next_state(n0: &mut i32, n1: &mut i32, y: i32) {
+        if *n0 < *n1 {
+            Self::next_state(n1, n0, 1 - y);
+            return;
+        }
+        if y == 1 {
+            *n1 += 1;
+            Self::discount(n0);
+        } else {
+            *n0 += 1;
+            Self::discount(n1);
+        }
+        // Scale back counts that fall outside the representable bounds.
+        while Self::num_states(*n0, *n1) == 0 {
+            if *n1 < 2 {
+                *n0 -= 1;
+            } else {
+                *n0 = (*n0 * (*n1 - 1) + *n1 / 2) / *n1;
+                *n1 -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// First rows of libzpaq's generated table: next(y=0), next(y=1), n0, n1.
+    const REFERENCE_HEAD: [[u8; 4]; 16] = [
+        [1, 2, 0, 0],
+        [3, 5, 1, 0],
+        [4, 6, 0, 1],
+        [7, 9, 2, 0],
+        [8, 11, 1, 1],
+        [8, 11, 1, 1],
+        [10, 12, 0, 2],
+        [13, 15, 3, 0],
+        [14, 17, 2, 1],
+        [14, 17, 2, 1],
+        [16, 19, 1, 2],
+        [16, 19, 1, 2],
+        [18, 20, 0, 3],
+        [21, 23, 4, 0],
+        [22, 25, 3, 1],
+        [22, 25, 3, 1],
+    ];
+
+    #[test]
+    fn matches_reference_table() {
+        let st = StateTable::new();
+        for (s, row) in REFERENCE_HEAD.iter().enumerate() {
+            assert_eq!(&st.ns[s * 4..s * 4 + 4], row, "state {s}");
+        }
+
+        // Rolling checksum over the whole generated table.
+        let sum = st.ns.iter().fold(0u32, |acc, &v| {
+            acc.wrapping_mul(3).wrapping_add(u32::from(v))
+        });
+        assert_eq!(sum, 165_336_307);
+    }
+
+    #[test]
+    fn table_is_closed_and_uses_255_states() {
+        let st = state_table();
+        let used = (0..=255u8)
+            .filter(|&s| s == 0 || st.counts(s) != (0, 0))
+            .count();
+        assert_eq!(used, 255);
+        for s in 0..255u8 {
+            for y in 0..2 {
+                assert!(st.next(s, y) < 255, "state {s} escapes the table");
+            }
+        }
+        assert_eq!(st.cminit(0), 1 << 22);
+        assert_eq!(st.counts(st.next(0, 1)), (0, 1));
+    }
+}
@@ -0,0 +1,532 @@
+use crate::error::{Result, ZparsError};
+use crate::journal::{JournalEntry, JournalFragment, encode_entry};
+use crate::methods::Method;
+use crate::writer::ZpaqWriter;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use tracing::debug;
+
+/// Fragment size limits and boundary threshold of zpaq's default
+/// `-fragment 6` (64 KiB average).
+const MIN_FRAGMENT: usize = 64 << 6;
+const MAX_FRAGMENT: usize = 8128 << 6;
+const BOUNDARY: u32 = 1 << 22 >> 6;
+
+/// A `d` block (or an `i` block) is written once its data reaches this size.
+const BLOCK_BYTES: usize = 1 << 24;
+
+/// A stored `c` block ends with csize, the empty chunk, 254 and 255.
+const CSIZE_FROM_END: u64 = 8 + 4 + 2;
+
+/// Appends one journaling transaction to an archive.
+///
+/// `new` writes the `c` block with csize -1, which readers treat as an
+/// incomplete transaction. Fragments are packed into `d` blocks coded with
+/// the chosen method level. As in zpaq, the stored `h` blocks of their
+/// hashes and sizes and the stored `i` blocks of index records follow all
+/// the `d` blocks. `finish` writes them and patches the `c` block with the
+/// size of the `d` blocks, committing the transaction; readers skip that many
+/// bytes after the `c` block to reach the index.
+///
+/// Fragments are identified by SHA-1 and stored once: a fragment already in
+/// the archive (see `index_fragments`) or in this transaction is referenced
+/// by its existing ID.
+///
+/// With `keep_index`, the transaction is also returned as it appears in an
+/// index-only archive (zpaq's `-index`): the same `c`, `h` and `i` blocks
+/// without the `d` blocks.
+pub struct TransactionWriter<W: Write + Seek> {
+    out: W,
+    date: u64,
+    method: Method,
+    /// Position of the `c` block's csize field, and of the end of the block.
+    csize_at: u64,
+    start: u64,
+    next_fragment: u32,
+    block_first: u32,
+    data: Vec<u8>,
+    sizes: Vec<u32>,
+    hashes: Vec<u8>,
+    index: Vec<u8>,
+    index_blocks: u32,
+    /// Encoded `h` and `i` blocks, written after the `d` blocks.
+    h_blocks: Vec<u8>,
+    i_blocks: Vec<u8>,
+    known: HashMap<[u8; 20], (u32, u32)>,
+    stats: DedupStats,
+    c_block: Vec<u8>,
+    keep_index: bool,
+}
+
+/// Fragment counts of a transaction, for its summary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Fragments found in the archive or earlier in the transaction.
+    pub hits: u64,
+    pub hit_bytes: u64,
+    /// Fragments stored by the transaction.
+    pub misses: u64,
+    pub stored_bytes: u64,
+}
+
+impl<W: Write + Seek> TransactionWriter<W> {
+    /// Start a transaction dated `date` (`YYYYMMDDHHMMSS`, UTC) at the
+    /// current position of `out`. New fragments are numbered from
+    /// `first_fragment`, one past the archive's last fragment ID.
+    pub fn new(mut out: W, date: u64, first_fragment: u32, level: u8) -> Result<Self> {
+        let method = Method::new(level)?;
+        let at = out.stream_position()?;
+        let c = block(
+            &jdc_name(date, 'c', first_fragment),
+            &u64::MAX.to_le_bytes(),
+            None,
+            false,
+        )?;
+        out.write_all(&c)?;
+        let c_len = c.len() as u64;
+        Ok(Self {
+            out,
+            date,
+            method,
+            // csize is followed by the empty chunk, 254 and 255.
+            csize_at: at + c_len - CSIZE_FROM_END,
+            start: at + c_len,
+            next_fragment: first_fragment,
+            block_first: first_fragment,
+            data: Vec::new(),
+            sizes: Vec::new(),
+            hashes: Vec::new(),
+            index: Vec::new(),
+            index_blocks: 0,
+            h_blocks: Vec::new(),
+            i_blocks: Vec::new(),
+            known: HashMap::new(),
+            stats: DedupStats::default(),
+            c_block: c,
+            keep_index: false,
+        })
+    }
+
+    /// Make the archive's existing fragments (`Journal::fragments`, indexed
+    /// by ID) available for deduplication.
+    pub fn index_fragments(&mut self, fragments: &[JournalFragment]) {
+        for (id, f) in fragments.iter().enumerate().skip(1) {
+            self.known.entry(f.sha1).or_insert((id as u32, f.size));
+        }
+    }
+
+    /// Add one fragment, returning its ID. A fragment with the SHA-1 and
+    /// size of a known one is not stored again.
+    pub fn add_fragment(&mut self, data: &[u8]) -> Result<u32> {
+        let sha1: [u8; 20] = Sha1::digest(data).into();
+        let size = data.len() as u32;
+        if let Some(&(id, known_size)) = self.known.get(&sha1)
+            && known_size == size
+        {
+            self.stats.hits += 1;
+            self.stats.hit_bytes += u64::from(size);
+            return Ok(id);
+        }
+
+        let id = self.next_fragment;
+        self.next_fragment += 1;
+        self.known.insert(sha1, (id, size));
+        self.stats.misses += 1;
+        self.stats.stored_bytes += u64::from(size);
+        self.data.extend_from_slice(data);
+        self.sizes.push(size);
+        self.hashes.extend_from_slice(&sha1);
+        self.hashes.extend_from_slice(&size.to_le_bytes());
+        if self.data.len() >= BLOCK_BYTES {
+            self.flush_data()?;
+        }
+        Ok(id)
+    }
+
+    /// Cut `input` into fragments and add them, returning the fragment IDs
+    /// for the file's index record.
+    ///
+    /// Boundaries follow zpaq: a rolling hash over the bytes, whose
+    /// multiplier depends on whether an order-1 context predicted the byte,
+    /// ends a fragment when it drops below a threshold, within the size
+    /// limits. Boundaries depend only on nearby content, so shifted or
+    /// repeated data produces the same fragments.
+    pub fn add_file_data<R: Read>(&mut self, mut input: R) -> Result<Vec<u32>> {
+        let mut ptrs = Vec::new();
+        let mut buf = vec![0u8; 1 << 16];
+        let mut frag = Vec::with_capacity(MAX_FRAGMENT);
+        let (mut h, mut c1, mut o1) = (0u32, 0u8, [0u8; 256]);
+        loop {
+            let n = input.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            for &c in &buf[..n] {
+                let k = if c == o1[usize::from(c1)] {
+                    314159265
+                } else {
+                    271828182
+                };
+                h = h.wrapping_add(u32::from(c) + 1).wrapping_mul(k);
+                o1[usize::from(c1)] = c;
+                c1 = c;
+                frag.push(c);
+                if (h < BOUNDARY && frag.len() >= MIN_FRAGMENT) || frag.len() >= MAX_FRAGMENT {
+                    ptrs.push(self.add_fragment(&frag)?);
+                    frag.clear();
+                    h = 0;
+                }
+            }
+        }
+        if !frag.is_empty() {
+            ptrs.push(self.add_fragment(&frag)?);
+        }
+        Ok(ptrs)
+    }
+
+    /// Deduplication counts so far.
+    pub fn stats(&self) -> DedupStats {
+        self.stats
+    }
+
+    /// Add an index record; a deletion when `entry.date` is 0.
+    pub fn add_entry(&mut self, entry: &JournalEntry) -> Result<()> {
+        encode_entry(entry, &mut self.index);
+        if self.index.len() >= BLOCK_BYTES {
+            self.flush_index()?;
+        }
+        Ok(())
+    }
+
+    /// Also keep copies of the `c`, `h` and `i` blocks for
+    /// `finish_with_index`.
+    pub fn keep_index(&mut self) {
+        self.keep_index = true;
+    }
+
+    /// Write the remaining blocks and commit the transaction by storing its
+    /// size in the `c` block. Returns the writer positioned at the end.
+    pub fn finish(mut self) -> Result<W> {
+        self.commit()?;
+        Ok(self.out)
+    }
+
+    /// `finish`, also returning the committed transaction without its `d`
+    /// blocks, to append to an index-only archive. Requires `keep_index`.
+    /// The `c` block keeps the transaction's size in the archive.
+    pub fn finish_with_index(mut self) -> Result<(W, Vec<u8>)> {
+        if !self.keep_index {
+            return Err(ZparsError::InvalidOption(
+                "finish_with_index requires keep_index",
+            ));
+        }
+        let csize = self.commit()?;
+        let mut index = std::mem::take(&mut self.c_block);
+        let at = index.len() - CSIZE_FROM_END as usize;
+        index[at..at + 8].copy_from_slice(&csize.to_le_bytes());
+        index.append(&mut self.h_blocks);
+        index.append(&mut self.i_blocks);
+        Ok((self.out, index))
+    }
+
+    fn commit(&mut self) -> Result<u64> {
+        self.flush_data()?;
+        self.flush_index()?;
+        let csize = self.out.stream_position()? - self.start;
+        self.out.write_all(&self.h_blocks)?;
+        self.out.write_all(&self.i_blocks)?;
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(self.csize_at))?;
+        self.out.write_all(&csize.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+        debug!(
+            date = self.date,
+            csize,
+            hits = self.stats.hits,
+            misses = self.stats.misses,
+            "committed transaction"
+        );
+        Ok(csize)
+    }
+
+    /// Write the pending fragments as a `d` block and queue its `h` block.
+    fn flush_data(&mut self) -> Result<()> {
+        if self.sizes.is_empty() {
+            return Ok(());
+        }
+        let n = self.sizes.len() as u32;
+        for size in std::mem::take(&mut self.sizes) {
+            self.data.extend_from_slice(&size.to_le_bytes());
+        }
+        self.data.extend_from_slice(&self.block_first.to_le_bytes());
+        self.data.extend_from_slice(&n.to_le_bytes());
+        let d = block(
+            &jdc_name(self.date, 'd', self.block_first),
+            &self.data,
+            Some(&self.method),
+            true,
+        )?;
+        self.out.write_all(&d)?;
+
+        let mut h = (d.len() as u32).to_le_bytes().to_vec();
+        h.append(&mut self.hashes);
+        let h = block(&jdc_name(self.date, 'h', self.block_first), &h, None, true)?;
+        self.h_blocks.extend_from_slice(&h);
+
+        self.data.clear();
+        self.block_first = self.next_fragment;
+        Ok(())
+    }
+
+    fn flush_index(&mut self) -> Result<()> {
+        if self.index.is_empty() {
+            return Ok(());
+        }
+        self.index_blocks += 1;
+        let name = jdc_name(self.date, 'i', self.index_blocks);
+        let i = block(&name, &self.index, None, true)?;
+        self.i_blocks.extend_from_slice(&i);
+        self.index.clear();
+        Ok(())
+    }
+}
+
+fn jdc_name(date: u64, kind: char, number: u32) -> String {
+    format!("jDC{date:014}{kind}{number:010}")
+}
+
+/// One block holding one segment. The comment ends in `jDC\x01`, which marks
+/// journaling blocks for the reference tool.
+fn block(name: &str, data: &[u8], method: Option<&Method>, sha1: bool) -> Result<Vec<u8>> {
+    let mut w = ZpaqWriter::new(Vec::new());
+    match method {
+        Some(method) => w.start_method_block(method)?,
+        None => w.start_block()?,
+    }
+    let level = method.map_or(0, Method::level);
+    w.start_segment(name, &format!("{} {level} jDC\x01", data.len()))?;
+    w.write_data(data)?;
+    if sha1 {
+        w.end_segment()?;
+    } else {
+        w.end_segment_with(None)?;
+    }
+    w.end_block()?;
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::Journal;
+    use crate::reader::ZpaqReader;
+    use crate::zpaq::inspect_bytes;
+    use std::io::Cursor;
+
+    fn entry(name: &str, date: u64, ptrs: Vec<u32>) -> JournalEntry {
+        JournalEntry {
+            name: name.into(),
+            date,
+            attr: b"u\xa4\x81".to_vec(),
+            ptrs,
+        }
+    }
+
+    /// Incompressible bytes from a fixed-seed LCG.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+                (x >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn appends_committed_transactions() {
+        let big = noise(200_000, 1);
+
+        let mut tx =
+            TransactionWriter::new(Cursor::new(Vec::new()), 20240101000000, 1, 1).expect("tx");
+        let a = tx.add_file_data(big.as_slice()).expect("a");
+        let b = tx.add_file_data(&b"small"[..]).expect("b");
+        assert!(a.len() > 1);
+        assert_eq!(b, [a.len() as u32 + 1]);
+        let next = b[0] + 1;
+        tx.add_entry(&entry("a.bin", 20231201000000, a))
+            .expect("entry");
+        tx.add_entry(&entry("b.txt", 20231201000000, b))
+            .expect("entry");
+        tx.add_entry(&entry("dir/", 20231201000000, Vec::new()))
+            .expect("entry");
+        let out = tx.finish().expect("finish");
+
+        let mut tx = TransactionWriter::new(out, 20240102000000, next, 0).expect("tx");
+        let b = tx.add_file_data(&b"changed"[..]).expect("b");
+        tx.add_entry(&entry("b.txt", 20240101120000, b))
+            .expect("entry");
+        tx.add_entry(&entry("a.bin", 0, Vec::new())).expect("entry");
+        let archive = tx.finish().expect("finish").into_inner();
+
+        let journal = Journal::read_with_data(archive.as_slice()).expect("journal");
+        assert_eq!(journal.versions.len(), 2);
+        assert_eq!(journal.incomplete, None);
+        let v1 = journal.files_at(1);
+        assert_eq!(journal.file_data(v1["a.bin"]).expect("a"), big);
+        assert!(v1["dir/"].is_dir());
+        let files = journal.files();
+        assert_eq!(files.keys().copied().collect::<Vec<_>>(), ["b.txt", "dir/"]);
+        assert_eq!(journal.file_data(files["b.txt"]).expect("b"), b"changed");
+
+        // As in zpaq, csize spans the d blocks after each c block, which the
+        // h and i blocks follow.
+        let kinds: String = ZpaqReader::new(archive.as_slice())
+            .segments()
+            .map(|s| char::from(s.expect("segment").filename.as_bytes()[17]))
+            .collect();
+        assert_eq!(kinds, "cdhicdhi");
+        let blocks = inspect_bytes(&archive).expect("blocks");
+        for v in &journal.versions {
+            let span =
+                blocks[v.block_index + 2].start_offset - blocks[v.block_index + 1].start_offset;
+            assert_eq!(v.csize, span as u64);
+        }
+        // Level 1 stores LZ77 codes for a PCOMP with a 4 MiB history.
+        assert_eq!((blocks[1].n_components, blocks[1].pm), (0, 22));
+    }
+
+    #[test]
+    fn fragments_shifted_and_repeated_data_once() {
+        let data = noise(600_000, 7);
+        let mut shifted = b"inserted header\n".to_vec();
+        shifted.extend_from_slice(&data);
+
+        let mut tx =
+            TransactionWriter::new(Cursor::new(Vec::new()), 20240101000000, 1, 0).expect("tx");
+        let a = tx.add_file_data(data.as_slice()).expect("a");
+        assert!(a.len() > 2, "{} fragments", a.len());
+        let sizes: Vec<_> = tx.sizes.clone();
+        assert!(sizes.iter().all(|&s| s as usize <= MAX_FRAGMENT));
+        assert!(
+            sizes[..sizes.len() - 1]
+                .iter()
+                .all(|&s| s as usize >= MIN_FRAGMENT)
+        );
+
+        let copy = tx.add_file_data(data.as_slice()).expect("copy");
+        assert_eq!(copy, a);
+        let stats = tx.stats();
+        assert_eq!((stats.hits, stats.misses), (a.len() as u64, a.len() as u64));
+
+        // Only the fragment holding the insertion differs.
+        let b = tx.add_file_data(shifted.as_slice()).expect("shifted");
+        assert_eq!(b[1..], a[1..]);
+        assert_eq!(tx.stats().misses, a.len() as u64 + 1);
+        let archive = tx.finish().expect("finish");
+
+        // A later transaction reuses the archive's fragments.
+        let journal = Journal::read(archive.get_ref().as_slice()).expect("journal");
+        let next = journal.fragments.len() as u32;
+        let mut tx = TransactionWriter::new(archive, 20240102000000, next, 0).expect("tx");
+        tx.index_fragments(&journal.fragments);
+        assert_eq!(tx.add_file_data(data.as_slice()).expect("again"), a);
+        assert_eq!(tx.stats().misses, 0);
+    }
+
+    /// Fragment sizes of `data` by zpaq's rule, with the order-1 context
+    /// either kept across boundaries (as zpaq does) or reset with the hash.
+    fn fragment_sizes(data: &[u8], reset_context: bool) -> Vec<u32> {
+        let mut sizes = Vec::new();
+        let (mut h, mut c1, mut o1, mut len) = (0u32, 0u8, [0u8; 256], 0usize);
+        for &c in data {
+            let k = if c == o1[usize::from(c1)] {
+                314159265
+            } else {
+                271828182
+            };
+            h = h.wrapping_add(u32::from(c) + 1).wrapping_mul(k);
+            o1[usize::from(c1)] = c;
+            c1 = c;
+            len += 1;
+            if (h < BOUNDARY && len >= MIN_FRAGMENT) || len >= MAX_FRAGMENT {
+                sizes.push(len as u32);
+                (h, len) = (0, 0);
+                if reset_context {
+                    (c1, o1) = (0, [0; 256]);
+                }
+            }
+        }
+        if len > 0 {
+            sizes.push(len as u32);
+        }
+        sizes
+    }
+
+    #[test]
+    fn fragment_boundaries_keep_order1_context() {
+        // A byte cycle the order-1 context predicts once it has seen it,
+        // with a few random bytes: after a boundary the carried context
+        // keeps predicting, while a reset one would mispredict a whole cycle.
+        let mut data: Vec<u8> = (0..600_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for (i, &b) in noise(600, 5).iter().enumerate() {
+            data[i * 997] = b;
+        }
+
+        let mut tx =
+            TransactionWriter::new(Cursor::new(Vec::new()), 20240101000000, 1, 0).expect("tx");
+        tx.add_file_data(data.as_slice()).expect("data");
+        let carried = fragment_sizes(&data, false);
+        assert!(carried.len() > 4, "{} fragments", carried.len());
+        assert_eq!(tx.sizes, carried);
+        // Only the hash restarts at a boundary; resetting the context too
+        // would move the later boundaries.
+        assert_ne!(fragment_sizes(&data, true), carried);
+    }
+
+    #[test]
+    fn index_copy_is_the_archive_without_data_blocks() {
+        let data = noise(100_000, 3);
+        let mut tx =
+            TransactionWriter::new(Cursor::new(Vec::new()), 20240101000000, 1, 1).expect("tx");
+        tx.keep_index();
+        let ptrs = tx.add_file_data(data.as_slice()).expect("data");
+        tx.add_entry(&entry("a.bin", 20231201000000, ptrs))
+            .expect("entry");
+        let (archive, index) = tx.finish_with_index().expect("finish");
+        let archive = archive.into_inner();
+
+        let full = Journal::read(archive.as_slice()).expect("archive journal");
+        let only = Journal::read(index.as_slice()).expect("index journal");
+        assert_eq!(only.fragments, full.fragments);
+        assert_eq!(only.versions[0].csize, full.versions[0].csize);
+        assert_eq!(only.files_at(1), full.files_at(1));
+        let names: Vec<_> = crate::zpaq::extract_bytes(&index)
+            .expect("segments")
+            .into_iter()
+            .map(|s| s.filename.as_bytes()[17])
+            .collect();
+        assert_eq!(names, b"chi");
+
+        // A later part dedups against the index alone.
+        let mut tx =
+            TransactionWriter::new(Cursor::new(Vec::new()), 20240102000000, 3, 1).expect("tx");
+        tx.index_fragments(&only.fragments);
+        let again = tx.add_file_data(data.as_slice()).expect("again");
+        assert_eq!(again, full.files()["a.bin"].ptrs);
+        assert!(tx.finish_with_index().is_err());
+    }
+
+    #[test]
+    fn unfinished_transaction_is_incomplete() {
+        let mut tx =
+            TransactionWriter::new(Cursor::new(Vec::new()), 20240101000000, 1, 0).expect("tx");
+        tx.add_fragment(b"lost").expect("fragment");
+        tx.flush_data().expect("flush");
+        let archive = tx.out.into_inner();
+        let journal = Journal::read(archive.as_slice()).expect("journal");
+        assert!(journal.versions.is_empty());
+        assert_eq!(journal.incomplete, Some(0));
+    }
+}
@@ -0,0 +1,376 @@
+use crate::coder::Encoder;
+use crate::compiler::ZpaqModel;
+use crate::error::{Result, ZparsError};
+use crate::methods::{Method, Preprocessor};
+use crate::predictor::Predictor;
+use crate::zpaq::{MAGIC_16, ZpaqBlockHeader, ZpaqExtractedSegment};
+use sha1::{Digest, Sha1};
+use std::io::Write;
+use tracing::trace;
+
+/// Stored data is emitted in length-prefixed chunks of at most this size,
+/// matching libzpaq's encoder buffer. Coded data is flushed at the same size.
+const STORED_CHUNK: usize = 1 << 16;
+
+/// Streaming ZPAQ writer for stored and modeled blocks.
+///
+/// Each block starts with the locator tag, `zPQ`, the level and the model.
+/// Stored blocks use the empty model (`comp 0 0 0 0 0 hcomp end`) and write
+/// segment data as big-endian length-prefixed chunks ending in a zero length.
+/// Modeled blocks arithmetic-code the data with the block's predictor, which
+/// carries over between its segments. The first segment of a block carries
+/// the postprocessor description. This is the layout read by `ZpaqReader`
+/// and by the reference `zpaq x`.
+pub struct ZpaqWriter<W: Write> {
+    out: W,
+    in_block: bool,
+    in_segment: bool,
+    /// Postprocessor description still to be written with the first segment.
+    prefix: Option<Vec<u8>>,
+    /// Header of a modeled block; its predictor is built at the first segment.
+    header: Option<ZpaqBlockHeader>,
+    pr: Option<Predictor>,
+    /// Transform applied to segment data before coding, with its output.
+    pre: Option<Preprocessor>,
+    pre_out: Vec<u8>,
+    enc: Encoder,
+    buf: Vec<u8>,
+    hasher: Sha1,
+}
+
+impl<W: Write> ZpaqWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            in_block: false,
+            in_segment: false,
+            prefix: None,
+            header: None,
+            pr: None,
+            pre: None,
+            pre_out: Vec::new(),
+            enc: Encoder::new(),
+            buf: Vec::with_capacity(STORED_CHUNK),
+            hasher: Sha1::new(),
+        }
+    }
+
+    /// Write a block header for a stored block.
+    pub fn start_block(&mut self) -> Result<()> {
+        if self.in_block {
+            return Err(ZparsError::InvalidFormat("block already started"));
+        }
+        self.out.write_all(&MAGIC_16)?;
+        // level 2, ZPAQL type 1, hsize 7, hh hm ph pm n, COMP END, HCOMP END
+        self.out.write_all(&[2, 1, 7, 0, 0, 0, 0, 0, 0, 0, 0])?;
+        self.in_block = true;
+        self.prefix = Some(vec![0]);
+        self.header = None;
+        Ok(())
+    }
+
+    /// Write a block header for `model`.
+    ///
+    /// Data is coded as given: a model with a PCOMP program expects input that
+    /// was already preprocessed, and its segments should then be ended with
+    /// `end_segment_with` and the SHA-1 of the original data.
+    pub fn start_model_block(&mut self, model: &ZpaqModel) -> Result<()> {
+        if self.in_block {
+            return Err(ZparsError::InvalidFormat("block already started"));
+        }
+        let modeled = model.n_components != 0;
+        self.out.write_all(&MAGIC_16)?;
+        self.out.write_all(&[if modeled { 1 } else { 2 }, 1])?;
+        self.out.write_all(&model.header_bytes())?;
+        self.in_block = true;
+        self.prefix = Some(model.postprocessor_prefix());
+        self.header = modeled.then(|| model.block_header());
+        Ok(())
+    }
+
+    /// Start a block coded with `method`. Segment data is written raw and
+    /// preprocessed here as the method's PCOMP expects; `end_segment` hashes
+    /// the raw data.
+    pub fn start_method_block(&mut self, method: &Method) -> Result<()> {
+        match method.model() {
+            Some(model) => self.start_model_block(model)?,
+            None => self.start_block()?,
+        }
+        self.pre = method.preprocessor();
+        Ok(())
+    }
+
+    /// Write a segment header; data follows through `write_data`.
+    pub fn start_segment(&mut self, filename: &str, comment: &str) -> Result<()> {
+        if !self.in_block || self.in_segment {
+            return Err(ZparsError::InvalidFormat("segment started outside a block"));
+        }
+        if filename.contains('\0') || comment.contains('\0') {
+            return Err(ZparsError::InvalidOption(
+                "segment filename and comment must not contain NUL",
+            ));
+        }
+        self.out.write_all(&[1])?;
+        self.out.write_all(filename.as_bytes())?;
+        self.out.write_all(&[0])?;
+        self.out.write_all(comment.as_bytes())?;
+        self.out.write_all(&[0, 0])?;
+        self.in_segment = true;
+        self.hasher = Sha1::new();
+        if let Some(prefix) = self.prefix.take() {
+            if let Some(header) = &self.header {
+                self.pr = Some(Predictor::new(header)?);
+                self.enc = Encoder::new();
+            }
+            self.put(&prefix)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_data(&mut self, data: &[u8]) -> Result<()> {
+        if !self.in_segment {
+            return Err(ZparsError::InvalidFormat("data written outside a segment"));
+        }
+        self.hasher.update(data);
+        let Some(pre) = self.pre.as_mut() else {
+            return self.put(data);
+        };
+        let mut out = std::mem::take(&mut self.pre_out);
+        pre.write(data, &mut out);
+        let res = self.put(&out);
+        out.clear();
+        self.pre_out = out;
+        res
+    }
+
+    /// End the segment with a SHA-1 trailer over its data, returning the hash.
+    pub fn end_segment(&mut self) -> Result<[u8; 20]> {
+        let sum: [u8; 20] = self.hasher.finalize_reset().into();
+        self.end_segment_with(Some(sum))?;
+        Ok(sum)
+    }
+
+    /// End the segment with the given SHA-1 trailer, or none (`254`).
+    pub fn end_segment_with(&mut self, sha1: Option<[u8; 20]>) -> Result<()> {
+        if !self.in_segment {
+            return Err(ZparsError::InvalidFormat("no segment to end"));
+        }
+        if let Some(pre) = self.pre.as_mut() {
+            let mut out = std::mem::take(&mut self.pre_out);
+            pre.finish(&mut out);
+            self.put(&out)?;
+            out.clear();
+            self.pre_out = out;
+        }
+        match self.pr.as_mut() {
+            Some(pr) => {
+                self.enc.compress_modeled(&mut self.buf, None, pr)?;
+                self.out.write_all(&self.buf)?;
+                self.buf.clear();
+            }
+            None => self.flush_chunk()?,
+        }
+        self.out.write_all(&[0, 0, 0, 0])?;
+        match sha1 {
+            Some(sum) => {
+                self.out.write_all(&[253])?;
+                self.out.write_all(&sum)?;
+            }
+            None => self.out.write_all(&[254])?,
+        }
+        self.in_segment = false;
+        Ok(())
+    }
+
+    pub fn end_block(&mut self) -> Result<()> {
+        if !self.in_block || self.in_segment {
+            return Err(ZparsError::InvalidFormat("no block to end"));
+        }
+        self.out.write_all(&[255])?;
+        self.in_block = false;
+        self.prefix = None;
+        self.header = None;
+        self.pr = None;
+        self.pre = None;
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer; any open block is an error.
+    pub fn finish(mut self) -> Result<W> {
+        if self.in_block {
+            return Err(ZparsError::InvalidFormat("unterminated block"));
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Code `data` with the block's predictor, or buffer it as stored data.
+    fn put(&mut self, mut data: &[u8]) -> Result<()> {
+        let Some(pr) = self.pr.as_mut() else {
+            while !data.is_empty() {
+                let n = (STORED_CHUNK - self.buf.len()).min(data.len());
+                self.buf.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                if self.buf.len() == STORED_CHUNK {
+                    self.flush_chunk()?;
+                }
+            }
+            return Ok(());
+        };
+        for &c in data {
+            self.enc.compress_modeled(&mut self.buf, Some(c), pr)?;
+            if self.buf.len() >= STORED_CHUNK {
+                self.out.write_all(&self.buf)?;
+                self.buf.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        trace!(bytes = self.buf.len(), "writing stored chunk");
+        self.out.write_all(&(self.buf.len() as u32).to_be_bytes())?;
+        self.out.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+/// Write `segments` as stored blocks, starting a new block whenever
+/// `block_index` changes. Stored SHA-1 values are written as given, so this
+/// is the inverse of `extract_unmodeled_bytes`.
+pub fn write_unmodeled_bytes(segments: &[ZpaqExtractedSegment]) -> Result<Vec<u8>> {
+    let mut w = ZpaqWriter::new(Vec::new());
+    let mut block = None;
+    for seg in segments {
+        if block != Some(seg.block_index) {
+            if block.is_some() {
+                w.end_block()?;
+            }
+            w.start_block()?;
+            block = Some(seg.block_index);
+        }
+        w.start_segment(&seg.filename, &seg.comment)?;
+        w.write_data(&seg.data)?;
+        w.end_segment_with(seg.sha1)?;
+    }
+    if block.is_some() {
+        w.end_block()?;
+    }
+    w.finish()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::zpaq::{extract_bytes, extract_unmodeled_bytes, verify_bytes};
+
+    /// Write one block holding one segment `name` with a SHA-1 trailer,
+    /// stored at `level` 0 or coded with that method level.
+    pub(crate) fn write_block<W: Write>(w: &mut ZpaqWriter<W>, level: u8, name: &str, data: &[u8]) {
+        w.start_method_block(&Method::new(level).expect("method"))
+            .expect("block");
+        w.start_segment(name, "").expect("segment");
+        w.write_data(data).expect("data");
+        w.end_segment().expect("end");
+        w.end_block().expect("end block");
+    }
+
+    /// An archive of the one block `write_block` writes.
+    pub(crate) fn one_block(level: u8, name: &str, data: &[u8]) -> Vec<u8> {
+        let mut w = ZpaqWriter::new(Vec::new());
+        write_block(&mut w, level, name, data);
+        w.finish().expect("finish")
+    }
+
+    fn segment(block_index: usize, name: &str, data: &[u8], sha1: bool) -> ZpaqExtractedSegment {
+        ZpaqExtractedSegment {
+            block_index,
+            filename: name.into(),
+            comment: format!("{} stored", data.len()),
+            data: data.to_vec(),
+            sha1: sha1.then(|| Sha1::digest(data).into()),
+        }
+    }
+
+    #[test]
+    fn inverse_of_unmodeled_extraction() {
+        let big: Vec<u8> = (0..STORED_CHUNK * 2 + 17).map(|i| (i * 7) as u8).collect();
+        let segments = vec![
+            segment(0, "a.txt", b"hello", true),
+            segment(0, "", b"", false),
+            segment(1, "big.bin", &big, true),
+        ];
+        let archive = write_unmodeled_bytes(&segments).expect("write");
+
+        let back = extract_unmodeled_bytes(&archive).expect("extract");
+        assert_eq!(back.len(), segments.len());
+        for (a, b) in back.iter().zip(&segments) {
+            assert_eq!(
+                (a.block_index, &a.filename, &a.comment, &a.data, a.sha1),
+                (b.block_index, &b.filename, &b.comment, &b.data, b.sha1)
+            );
+        }
+        assert_eq!(write_unmodeled_bytes(&back).expect("rewrite"), archive);
+
+        let report = verify_bytes(&archive).expect("verify");
+        assert_eq!((report.verified, report.mismatches.len()), (2, 0));
+    }
+
+    #[test]
+    fn matches_reference_stored_layout() {
+        let mut w = ZpaqWriter::new(Vec::new());
+        w.start_block().expect("block");
+        w.start_segment("f", "3").expect("segment");
+        w.write_data(b"abc").expect("data");
+        let sum = w.end_segment().expect("end");
+        w.end_block().expect("end block");
+        let bytes = w.finish().expect("finish");
+
+        let mut expected = MAGIC_16.to_vec();
+        expected.extend_from_slice(&[2, 1, 7, 0, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(b"\x01f\x003\x00\x00");
+        expected.extend_from_slice(&[0, 0, 0, 4, 0, b'a', b'b', b'c', 0, 0, 0, 0, 253]);
+        expected.extend_from_slice(&sum);
+        expected.push(255);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn method_blocks_preprocess_each_segment() {
+        let data = b"segments of one preprocessed block ".repeat(300);
+        for level in 1..=3 {
+            let mut w = ZpaqWriter::new(Vec::new());
+            w.start_method_block(&Method::new(level).expect("method"))
+                .expect("block");
+            w.start_segment("a", "").expect("segment");
+            w.write_data(&data[..5000]).expect("data");
+            w.write_data(&data[5000..]).expect("data");
+            let sum = w.end_segment().expect("end");
+            assert_eq!(sum, <[u8; 20]>::from(Sha1::digest(&data)));
+            w.start_segment("b", "").expect("segment");
+            w.write_data(&data[..100]).expect("data");
+            w.end_segment().expect("end");
+            w.end_block().expect("end block");
+            let archive = w.finish().expect("finish");
+
+            let segs = extract_bytes(&archive).expect("extract");
+            assert_eq!(segs.len(), 2);
+            assert_eq!(segs[0].data, data, "level {level}");
+            assert_eq!(segs[1].data, &data[..100], "level {level}");
+        }
+    }
+
+    #[test]
+    fn rejects_misordered_calls() {
+        let mut w = ZpaqWriter::new(Vec::new());
+        assert!(w.start_segment("x", "").is_err());
+        w.start_block().expect("block");
+        assert!(w.write_data(b"x").is_err());
+        assert!(w.start_segment("x\0y", "").is_err());
+        assert!(w.finish().is_err());
+    }
+}
@@ -1,15 +1,19 @@
 use crate::error::{Result, ZparsError};
-use std::fs;
+use crate::parts::PartsReader;
+use crate::reader::ZpaqReader;
+use crate::zpaql::Zpaql;
+use std::io::Read;
 use std::path::Path;
-use tracing::{debug, trace};
+use tracing::{debug, info};
 
-const START_TAG_13: [u8; 13] = [
-    0x37, 0x6b, 0x53, 0x74, 0xa0, 0x31, 0x83, 0xd3, 0x8c, 0xb2, 0x28, 0xb0, 0xd3,
-];
-const MAGIC_16: [u8; 16] = [
+pub(crate) const MAGIC_16: [u8; 16] = [
     0x37, 0x6b, 0x53, 0x74, 0xa0, 0x31, 0x83, 0xd3, 0x8c, 0xb2, 0x28, 0xb0, 0xd3, b'z', b'P', b'Q',
 ];
-const COMP_SIZE: [u8; 10] = [0, 2, 3, 2, 3, 4, 6, 6, 3, 5];
+pub(crate) const COMP_SIZE: [u8; 10] = [0, 2, 3, 2, 3, 4, 6, 6, 3, 5];
+/// Component names as written in ZPAQL config files, indexed by type.
+pub(crate) const COMP_NAMES: [&str; 10] = [
+    "", "const", "cm", "icm", "match", "avg", "mix2", "mix", "isse", "sse",
+];
 
 #[derive(Debug, Clone)]
 pub struct ZpaqBlockHeader {
@@ -25,6 +29,10 @@ pub struct ZpaqBlockHeader {
     pub comp_bytes: usize,
     pub hcomp_bytes: usize,
     pub segment_offset: usize,
+    /// Component list bytes (`n_components` entries, without COMP END).
+    pub comp: Vec<u8>,
+    /// HCOMP bytecode including its trailing END byte.
+    pub hcomp: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
@@ -36,36 +44,33 @@ pub struct ZpaqExtractedSegment {
     pub sha1: Option<[u8; 20]>,
 }
 
+/// Outcome of checking every segment's stored SHA-1 against its decoded data.
+#[derive(Debug, Clone, Default)]
+pub struct ZpaqVerifyReport {
+    pub segments: usize,
+    /// Segments that carried a SHA-1 and matched it.
+    pub verified: usize,
+    /// `(block_index, filename)` of segments whose SHA-1 did not match.
+    pub mismatches: Vec<(usize, String)>,
+}
+
+/// Headers of every block in the archive at `path`, which may name a
+/// multi-part archive (`backup??.zpaq`); its parts are read as one stream.
 pub fn inspect_file(path: &Path) -> Result<Vec<ZpaqBlockHeader>> {
-    let data = fs::read(path)?;
-    inspect_bytes(&data)
+    inspect_reader(PartsReader::open(path)?)
 }
 
 pub fn inspect_bytes(data: &[u8]) -> Result<Vec<ZpaqBlockHeader>> {
-    let mut out = Vec::new();
-    let mut i = 0usize;
-
-    while i + MAGIC_16.len() + 2 < data.len() {
-        let Some(rel) = find_magic(&data[i..]) else {
-            break;
-        };
-        let at = i + rel;
-
-        let Some((block, consumed)) = parse_block_header(data, at)? else {
-            i = at + 1;
-            continue;
-        };
-
-        out.push(block);
-        i = at + consumed;
-    }
+    inspect_reader(data)
+}
 
-    Ok(out)
+/// Headers of every block read from `inner`, e.g. a `DecryptReader`.
+pub fn inspect_reader<R: Read>(inner: R) -> Result<Vec<ZpaqBlockHeader>> {
+    ZpaqReader::new(inner).blocks().collect()
 }
 
 pub fn extract_unmodeled_file(path: &Path) -> Result<Vec<ZpaqExtractedSegment>> {
-    let data = fs::read(path)?;
-    extract_unmodeled_bytes(&data)
+    extract_blocks(PartsReader::open(path)?, false)
 }
 
 pub fn archive_is_fully_unmodeled_file(path: &Path) -> Result<bool> {
@@ -73,25 +78,38 @@ pub fn archive_is_fully_unmodeled_file(path: &Path) -> Result<bool> {
     Ok(!blocks.is_empty() && blocks.iter().all(|b| b.n_components == 0))
 }
 
+pub fn extract_file(path: &Path) -> Result<Vec<ZpaqExtractedSegment>> {
+    extract_blocks(PartsReader::open(path)?, true)
+}
+
+/// Decode every block, modeled or not, with the native predictor and postprocessor.
+pub fn extract_bytes(data: &[u8]) -> Result<Vec<ZpaqExtractedSegment>> {
+    extract_blocks(data, true)
+}
+
+/// `extract_bytes` over a stream.
+pub fn extract_reader<R: Read>(inner: R) -> Result<Vec<ZpaqExtractedSegment>> {
+    extract_blocks(inner, true)
+}
+
+/// `extract_unmodeled_bytes` over a stream.
+pub fn extract_unmodeled_reader<R: Read>(inner: R) -> Result<Vec<ZpaqExtractedSegment>> {
+    extract_blocks(inner, false)
+}
+
 pub fn extract_unmodeled_bytes(data: &[u8]) -> Result<Vec<ZpaqExtractedSegment>> {
+    extract_blocks(data, false)
+}
+
+fn extract_blocks<R: Read>(inner: R, allow_modeled: bool) -> Result<Vec<ZpaqExtractedSegment>> {
+    let mut reader = ZpaqReader::new(inner);
     let mut out = Vec::new();
-    let mut i = 0usize;
     let mut block_index = 0usize;
 
-    while i + MAGIC_16.len() + 2 < data.len() {
-        let Some(rel) = find_magic(&data[i..]) else {
-            break;
-        };
-        let at = i + rel;
-
-        let Some((header, consumed)) = parse_block_header(data, at)? else {
-            i = at + 1;
-            continue;
-        };
-
-        if header.n_components != 0 {
+    while let Some(header) = reader.next_block()? {
+        if header.n_components != 0 && !allow_modeled {
             return Err(ZparsError::InvalidFormat(
-                "modeled blocks are not supported yet; use zpaq -m0 for now",
+                "modeled block in unmodeled-only extraction; use extract-zpaq",
             ));
         }
 
@@ -99,194 +117,140 @@ pub fn extract_unmodeled_bytes(data: &[u8]) -> Result<Vec<ZpaqExtractedSegment>>
             block = block_index,
             offset = header.start_offset,
             segment_offset = header.segment_offset,
-            "extracting unmodeled zpaq block"
+            components = header.n_components,
+            "extracting zpaq block"
         );
 
-        let mut pos = header.segment_offset;
-        let mut dec_curr = 0u32;
-        let mut pp = PassOrProgramPostProcessor::new(header.ph, header.pm);
-        let mut first_segment = true;
-
-        loop {
-            let marker = get_required(data, &mut pos, "segment marker")?;
-            if marker == 255 {
-                break;
-            }
-            if marker != 1 {
-                return Err(ZparsError::Corrupt(
-                    "missing segment or end-of-block marker",
-                ));
-            }
-
-            let filename = read_cstr(data, &mut pos)?;
-            let comment = read_cstr(data, &mut pos)?;
-            if get_required(data, &mut pos, "reserved byte")? != 0 {
-                return Err(ZparsError::Corrupt("missing reserved byte after comment"));
-            }
-
-            let mut segment_data = Vec::new();
-
-            if first_segment {
-                first_segment = false;
-                while (pp.state() & 3) != 1 {
-                    let c = decompress_unmodeled_byte(data, &mut pos, &mut dec_curr)?;
-                    pp.write(c, &mut segment_data)?;
-                }
-            }
-
-            loop {
-                let c = decompress_unmodeled_byte(data, &mut pos, &mut dec_curr)?;
-                pp.write(c, &mut segment_data)?;
-                if c < 0 {
-                    break;
-                }
-            }
-
-            let seg_end = get_required(data, &mut pos, "segment end marker")?;
-            let sha1 = if seg_end == 254 {
-                None
-            } else if seg_end == 253 {
-                let mut sum = [0u8; 20];
-                for b in &mut sum {
-                    *b = get_required(data, &mut pos, "sha1 byte")?;
-                }
-                Some(sum)
-            } else {
-                return Err(ZparsError::Corrupt("missing end-of-segment marker"));
-            };
-
-            trace!(
-                block = block_index,
-                file = filename,
-                bytes = segment_data.len(),
-                "decoded segment"
-            );
-
-            out.push(ZpaqExtractedSegment {
-                block_index,
-                filename,
-                comment,
-                data: segment_data,
-                sha1,
-            });
-        }
+        let segments = read_block_segments(&mut reader)?;
+        log_decoded_block(block_index, &segments);
+        out.extend(segments);
 
         block_index += 1;
-        i = pos.max(at + consumed);
     }
 
     Ok(out)
 }
 
-fn decompress_unmodeled_byte(data: &[u8], pos: &mut usize, curr: &mut u32) -> Result<i32> {
-    if *curr == 0 {
-        *curr = read_u32_be(data, pos)?;
-        if *curr == 0 {
-            return Ok(-1);
-        }
+/// Decode the segments of the block `reader` has just entered.
+pub(crate) fn read_block_segments<R: Read>(
+    reader: &mut ZpaqReader<R>,
+) -> Result<Vec<ZpaqExtractedSegment>> {
+    let mut segments = Vec::new();
+    while let Some(seg) = reader.next_segment()? {
+        let mut data = Vec::new();
+        let sha1 = reader.read_segment(&mut data)?;
+        segments.push(ZpaqExtractedSegment {
+            block_index: seg.block_index,
+            filename: seg.filename,
+            comment: seg.comment,
+            data,
+            sha1,
+        });
     }
-
-    *curr -= 1;
-    let b = get_required(data, pos, "compressed payload")?;
-    Ok(i32::from(b))
+    Ok(segments)
 }
 
-fn read_u32_be(data: &[u8], pos: &mut usize) -> Result<u32> {
-    let mut x = 0u32;
-    for _ in 0..4 {
-        x = (x << 8) | u32::from(get_required(data, pos, "u32")?);
-    }
-    Ok(x)
-}
-
-fn read_cstr(data: &[u8], pos: &mut usize) -> Result<String> {
-    let mut out = Vec::new();
-    loop {
-        let c = get_required(data, pos, "cstr")?;
-        if c == 0 {
-            break;
-        }
-        out.push(c);
-    }
-    Ok(String::from_utf8_lossy(&out).into_owned())
+/// Log a block decoded by the native decoder, with its segments.
+pub(crate) fn log_decoded_block(block: usize, segments: &[ZpaqExtractedSegment]) {
+    info!(
+        block,
+        path = "native",
+        segments = segments.len(),
+        bytes = segments.iter().map(|s| s.data.len()).sum::<usize>(),
+        "decoded block"
+    );
 }
 
-fn get_required(data: &[u8], pos: &mut usize, what: &'static str) -> Result<u8> {
-    if *pos >= data.len() {
-        return Err(ZparsError::Corrupt(what));
-    }
-    let b = data[*pos];
-    *pos += 1;
-    Ok(b)
+/// Decode every segment without keeping its data and collect SHA-1 mismatches.
+pub fn verify_file(path: &Path) -> Result<ZpaqVerifyReport> {
+    verify_reader(PartsReader::open(path)?)
 }
 
-fn find_magic(haystack: &[u8]) -> Option<usize> {
-    haystack
-        .windows(MAGIC_16.len())
-        .position(|w| w == MAGIC_16.as_slice())
+pub fn verify_bytes(data: &[u8]) -> Result<ZpaqVerifyReport> {
+    verify_reader(data)
 }
 
-fn parse_block_header(data: &[u8], at: usize) -> Result<Option<(ZpaqBlockHeader, usize)>> {
-    if at + MAGIC_16.len() + 2 > data.len() {
-        return Ok(None);
-    }
-
-    if data[at..at + START_TAG_13.len()] != START_TAG_13 {
-        return Ok(None);
-    }
-
-    let mut p = at + MAGIC_16.len();
-    let level = data[p];
-    p += 1;
-    if level != 1 && level != 2 {
-        return Ok(None);
+/// `verify_bytes` over a stream.
+pub fn verify_reader<R: Read>(inner: R) -> Result<ZpaqVerifyReport> {
+    let mut reader = ZpaqReader::new(inner);
+    let mut report = ZpaqVerifyReport::default();
+    while reader.next_block()?.is_some() {
+        while reader.next_segment()?.is_some() {
+            report.segments += 1;
+            match reader.read_segment(&mut std::io::sink()) {
+                Ok(Some(_)) => report.verified += 1,
+                Ok(None) => {}
+                Err(ZparsError::ChecksumMismatch {
+                    block_index,
+                    filename,
+                }) => report.mismatches.push((block_index, filename)),
+                Err(e) => return Err(e),
+            }
+        }
     }
+    Ok(report)
+}
 
-    let zpaql_type = data[p];
-    p += 1;
-    if zpaql_type != 1 {
+/// PCOMP bytecode (including END) of the block described by `header`, or
+/// `None` when the block uses PASS or has no segments.
+///
+/// The postprocessor is sent at the start of the first segment, so this decodes
+/// just enough of it to recover the program.
+pub fn read_pcomp(data: &[u8], header: &ZpaqBlockHeader) -> Result<Option<Vec<u8>>> {
+    let mut reader = ZpaqReader::new(data.get(header.start_offset..).unwrap_or_default());
+    if reader.next_block()?.is_none() || reader.next_segment()?.is_none() {
         return Ok(None);
     }
+    Ok(reader.pcomp()?.map(<[u8]>::to_vec))
+}
 
-    if p + 7 > data.len() {
-        return Err(ZparsError::Corrupt("truncated ZPAQL header prefix"));
-    }
-
-    let hsize = u16::from_le_bytes([data[p], data[p + 1]]);
-    let hh = data[p + 2];
-    let hm = data[p + 3];
-    let ph = data[p + 4];
-    let pm = data[p + 5];
-    let n_components = data[p + 6];
-
-    let header_start = p;
+/// Parse a block header from `h`, which starts at the `hsize` field and holds
+/// at least 7 bytes and at least `hsize + 2` bytes. `at` is the offset of the
+/// block's start: its locator tag, or `zPQ` for a block without one.
+/// `prefix` is the length of the tag and `zPQ`.
+pub(crate) fn parse_header(
+    at: usize,
+    prefix: usize,
+    level: u8,
+    zpaql_type: u8,
+    h: &[u8],
+) -> Result<ZpaqBlockHeader> {
+    let hsize = u16::from_le_bytes([h[0], h[1]]);
+    let hh = h[2];
+    let hm = h[3];
+    let ph = h[4];
+    let pm = h[5];
+    let n_components = h[6];
     let header_total = hsize as usize + 2;
-    if header_start + header_total > data.len() {
-        return Err(ZparsError::Corrupt("truncated ZPAQL header"));
+    // Level 2 added unmodeled blocks; level 1 always has a model.
+    if level == 1 && n_components == 0 {
+        return Err(ZparsError::Corrupt(
+            "ZPAQ level 1 requires at least 1 component",
+        ));
     }
 
-    let mut cp = header_start + 7;
+    let mut cp = 7;
     for _ in 0..n_components {
-        if cp >= header_start + header_total {
+        if cp >= header_total {
             return Err(ZparsError::Corrupt("COMP overflows header"));
         }
-        let t = data[cp] as usize;
+        let t = h[cp] as usize;
         if t >= COMP_SIZE.len() || COMP_SIZE[t] == 0 {
             return Err(ZparsError::Corrupt("invalid component type"));
         }
         let sz = COMP_SIZE[t] as usize;
-        if cp + sz > header_start + header_total {
+        if cp + sz > header_total {
             return Err(ZparsError::Corrupt("component overflows header"));
         }
         cp += sz;
     }
 
-    if cp >= header_start + header_total || data[cp] != 0 {
+    if cp >= header_total || h[cp] != 0 {
         return Err(ZparsError::Corrupt("missing COMP END"));
     }
     cp += 1;
 
-    let comp_bytes = cp - (header_start + 2);
+    let comp_bytes = cp - 2;
     if comp_bytes > hsize as usize {
         return Err(ZparsError::Corrupt("invalid hsize/COMP layout"));
     }
@@ -296,57 +260,60 @@ fn parse_block_header(data: &[u8], at: usize) -> Result<Option<(ZpaqBlockHeader,
         return Err(ZparsError::Corrupt("missing HCOMP"));
     }
 
-    if data[header_start + header_total - 1] != 0 {
+    if h[header_total - 1] != 0 {
         return Err(ZparsError::Corrupt("missing HCOMP END"));
     }
 
-    let segment_offset = header_start + header_total;
-    let consumed = (segment_offset - at).max(1);
-
-    Ok(Some((
-        ZpaqBlockHeader {
-            start_offset: at,
-            level,
-            zpaql_type,
-            hsize,
-            hh,
-            hm,
-            ph,
-            pm,
-            n_components,
-            comp_bytes,
-            hcomp_bytes,
-            segment_offset,
-        },
-        consumed,
-    )))
+    Ok(ZpaqBlockHeader {
+        start_offset: at,
+        level,
+        zpaql_type,
+        hsize,
+        hh,
+        hm,
+        ph,
+        pm,
+        n_components,
+        comp_bytes,
+        hcomp_bytes,
+        segment_offset: at + prefix + 2 + header_total,
+        comp: h[7..cp - 1].to_vec(),
+        hcomp: h[cp..header_total].to_vec(),
+    })
 }
 
 #[derive(Debug, Clone)]
-struct PassOrProgramPostProcessor {
+pub(crate) struct PassOrProgramPostProcessor {
     state: u8,
     program_remaining: usize,
-    program_mode: bool,
-    _ph: u8,
-    _pm: u8,
+    program: Vec<u8>,
+    vm: Option<Zpaql>,
+    ph: u8,
+    pm: u8,
 }
 
 impl PassOrProgramPostProcessor {
-    fn new(ph: u8, pm: u8) -> Self {
+    pub(crate) fn new(ph: u8, pm: u8) -> Self {
         Self {
             state: 0,
             program_remaining: 0,
-            program_mode: false,
-            _ph: ph,
-            _pm: pm,
+            program: Vec::new(),
+            vm: None,
+            ph,
+            pm,
         }
     }
 
-    fn state(&self) -> u8 {
+    pub(crate) fn state(&self) -> u8 {
         self.state
     }
 
-    fn write(&mut self, c: i32, out: &mut Vec<u8>) -> Result<()> {
+    /// Loaded PCOMP program, once the postprocessor header is complete.
+    pub(crate) fn program(&self) -> Option<&[u8]> {
+        self.vm.as_ref().map(Zpaql::program)
+    }
+
+    pub(crate) fn write(&mut self, c: i32, out: &mut Vec<u8>) -> Result<()> {
         match self.state {
             0 => {
                 if c < 0 {
@@ -385,6 +352,7 @@ impl PassOrProgramPostProcessor {
                 if self.program_remaining == 0 {
                     return Err(ZparsError::Corrupt("empty PCOMP"));
                 }
+                self.program.reserve(self.program_remaining);
                 self.state = 4;
                 Ok(())
             }
@@ -392,16 +360,28 @@ impl PassOrProgramPostProcessor {
                 if c < 0 {
                     return Err(ZparsError::Corrupt("unexpected EOS reading PCOMP body"));
                 }
-                self.program_remaining = self.program_remaining.saturating_sub(1);
+                self.program.push(c as u8);
+                self.program_remaining -= 1;
                 if self.program_remaining == 0 {
-                    self.program_mode = true;
+                    debug!(
+                        pcomp_bytes = self.program.len(),
+                        ph = self.ph,
+                        pm = self.pm,
+                        "loaded PCOMP postprocessor"
+                    );
+                    let program = std::mem::take(&mut self.program);
+                    self.vm = Some(Zpaql::new(&program, self.ph, self.pm)?);
                     self.state = 5;
                 }
                 Ok(())
             }
             5 => {
-                let _ = c;
-                let _ = self.program_mode;
+                let Some(vm) = self.vm.as_mut() else {
+                    return Err(ZparsError::Corrupt("invalid postprocessor state"));
+                };
+                // EOS is delivered to PCOMP as A = 2^32 - 1.
+                vm.run(c as u32)?;
+                out.extend_from_slice(&vm.take_output());
                 Ok(())
             }
             _ => Err(ZparsError::Corrupt("invalid postprocessor state")),
@@ -440,4 +420,120 @@ mod tests {
         assert_eq!(b.hsize, 7);
         assert_eq!(b.n_components, 0);
     }
+
+    #[test]
+    fn runs_pcomp_postprocessor() {
+        // PCOMP: a> 255 jt 3 a+= 1 out halt END -- emits each byte plus one.
+        let pcomp = [239, 255, 39, 3, 135, 1, 57, 56, 0];
+        let mut stored = vec![1, pcomp.len() as u8, 0];
+        stored.extend_from_slice(&pcomp);
+        stored.extend_from_slice(b"HAL");
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&MAGIC_16);
+        buf.extend_from_slice(&[2, 1]);
+        buf.extend_from_slice(&7u16.to_le_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0]);
+        buf.extend_from_slice(&[1, b'f', 0, 0, 0]);
+        buf.extend_from_slice(&(stored.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&stored);
+        buf.extend_from_slice(&[0, 0, 0, 0, 254, 255]);
+
+        let segs = extract_unmodeled_bytes(&buf).expect("extract");
+        assert_eq!(segs.len(), 1);
+        assert_eq!(segs[0].filename, "f");
+        assert_eq!(segs[0].data, b"IBM");
+
+        let header = &inspect_bytes(&buf).expect("inspect")[0];
+        let program = read_pcomp(&buf, header).expect("pcomp");
+        assert_eq!(program.as_deref(), Some(pcomp.as_slice()));
+    }
+
+    /// A level 1 block coded with the predictor tests' model, starting with
+    /// `prefix` (the locator tag and `zPQ`, or just `zPQ`).
+    fn modeled_block(prefix: &[u8], name: &str, payload: &[u8]) -> Vec<u8> {
+        let header = crate::predictor::tests::test_header();
+        let mut plain = vec![0]; // PASS postprocessor
+        plain.extend_from_slice(payload);
+        let mut pr = crate::predictor::Predictor::new(&header).expect("predictor");
+        let coded = crate::coder::tests::compress_with(&mut pr, &plain);
+
+        let mut buf = prefix.to_vec();
+        buf.extend_from_slice(&[1, 1]);
+        buf.extend_from_slice(&header.hsize.to_le_bytes());
+        buf.extend_from_slice(&[header.hh, header.hm, 0, 0, header.n_components]);
+        buf.extend_from_slice(&header.comp);
+        buf.push(0);
+        buf.extend_from_slice(&header.hcomp);
+        buf.push(1);
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(&[0, 0, 0]);
+        buf.extend_from_slice(&coded);
+        buf.extend_from_slice(&[254, 255]);
+        buf
+    }
+
+    #[test]
+    fn extracts_modeled_block() {
+        let payload = b"modeled zpaq block, modeled zpaq block, modeled zpaq block";
+        let buf = modeled_block(&MAGIC_16, "m", payload);
+
+        assert!(extract_unmodeled_bytes(&buf).is_err());
+        let segs = extract_bytes(&buf).expect("extract");
+        assert_eq!(segs.len(), 1);
+        assert_eq!(segs[0].filename, "m");
+        assert_eq!(segs[0].data, payload);
+    }
+
+    #[test]
+    fn reads_level_1_blocks_without_locator_tags() {
+        // zpipe style: the stream starts with `zPQ`, and the next block
+        // follows the end marker directly.
+        let mut buf = modeled_block(b"zPQ", "first", b"first tagless block");
+        let second = buf.len();
+        buf.extend(modeled_block(b"zPQ", "second", b"second tagless block"));
+        let third = buf.len();
+        buf.extend(modeled_block(&MAGIC_16, "third", b"tagged block"));
+
+        let blocks = inspect_bytes(&buf).expect("inspect");
+        let starts: Vec<_> = blocks.iter().map(|b| b.start_offset).collect();
+        assert_eq!(starts, [0, second, third]);
+        assert!(blocks.iter().all(|b| b.level == 1));
+        assert_eq!(
+            blocks[0].segment_offset,
+            3 + 2 + blocks[0].hsize as usize + 2
+        );
+        let segs = extract_bytes(&buf).expect("extract");
+        let data: Vec<_> = segs.iter().map(|s| s.data.as_slice()).collect();
+        assert_eq!(
+            data,
+            [
+                &b"first tagless block"[..],
+                b"second tagless block",
+                b"tagged block"
+            ]
+        );
+
+        // Elsewhere `zPQ` is just data.
+        let mut shifted = b"x".to_vec();
+        shifted.extend(modeled_block(b"zPQ", "lost", b"not a block"));
+        assert!(inspect_bytes(&shifted).expect("inspect").is_empty());
+    }
+
+    #[test]
+    fn level_1_blocks_need_a_model() {
+        // An unmodeled (n = 0) header, valid only at level 2.
+        let mut buf = MAGIC_16.to_vec();
+        buf.extend_from_slice(&[1, 1]);
+        buf.extend_from_slice(&7u16.to_le_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            inspect_bytes(&buf),
+            Err(ZparsError::Corrupt(
+                "ZPAQ level 1 requires at least 1 component"
+            ))
+        ));
+        buf[16] = 2;
+        assert_eq!(inspect_bytes(&buf).expect("level 2").len(), 1);
+    }
 }
@@ -0,0 +1,505 @@
+use crate::error::{Result, ZparsError};
+use crate::zpaq::{COMP_NAMES, COMP_SIZE, ZpaqBlockHeader};
+use tracing::trace;
+
+/// Native ZPAQL interpreter.
+///
+/// Executes HCOMP/PCOMP bytecode with the register file and memory layout of
+/// libzpaq: 32-bit registers `A B C D`, 256 general registers `R`, a condition
+/// flag `F`, a `u32` array `H` of `2^hbits` entries and a byte array `M` of
+/// `2^mbits` entries. Both arrays are indexed modulo their size.
+///
+/// `OUT` appends `A`'s low byte to the output, except in a VM built with
+/// `from_block_header`: like libzpaq, HCOMP output is ignored.
+#[derive(Debug, Clone)]
+pub struct Zpaql {
+    prog: Vec<u8>,
+    h: Vec<u32>,
+    m: Vec<u8>,
+    r: [u32; 256],
+    a: u32,
+    b: u32,
+    c: u32,
+    d: u32,
+    f: bool,
+    pc: usize,
+    out: Vec<u8>,
+    keep_output: bool,
+}
+
+impl Zpaql {
+    /// Build a VM for `prog` (bytecode including its trailing END byte).
+    pub fn new(prog: &[u8], hbits: u8, mbits: u8) -> Result<Self> {
+        if prog.is_empty() {
+            return Err(ZparsError::InvalidFormat("empty ZPAQL program"));
+        }
+        Ok(Self {
+            prog: prog.to_vec(),
+            h: alloc_zeroed(hbits, "ZPAQL H array too large")?,
+            m: alloc_zeroed(mbits, "ZPAQL M array too large")?,
+            r: [0; 256],
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            f: false,
+            pc: 0,
+            out: Vec::new(),
+            keep_output: true,
+        })
+    }
+
+    /// Build the context-model VM for a parsed block header (HCOMP sized by `hh`/`hm`).
+    pub fn from_block_header(header: &ZpaqBlockHeader) -> Result<Self> {
+        let mut z = Self::new(&header.hcomp, header.hh, header.hm)?;
+        z.keep_output = false;
+        Ok(z)
+    }
+
+    /// Run the program once with `input` loaded into `A`, until `HALT`.
+    pub fn run(&mut self, input: u32) -> Result<()> {
+        self.pc = 0;
+        self.a = input;
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Element `i` of `H`, as read by the predictor after each byte.
+    pub fn h(&self, i: usize) -> u32 {
+        self.h[i & (self.h.len() - 1)]
+    }
+
+    /// Bytes emitted by `OUT` since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.prog
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let op = self.prog.get(self.pc).copied().unwrap_or(0);
+        self.pc += 1;
+        op
+    }
+
+    fn mb(&mut self) -> &mut u8 {
+        let i = self.b as usize & (self.m.len() - 1);
+        &mut self.m[i]
+    }
+
+    fn mc(&mut self) -> &mut u8 {
+        let i = self.c as usize & (self.m.len() - 1);
+        &mut self.m[i]
+    }
+
+    fn hd(&mut self) -> &mut u32 {
+        let i = self.d as usize & (self.h.len() - 1);
+        &mut self.h[i]
+    }
+
+    /// Read location `loc`: 0..=3 are `A B C D`, 4 is `*B`, 5 is `*C`, 6 is `*D`.
+    fn get(&mut self, loc: u8) -> u32 {
+        match loc {
+            0 => self.a,
+            1 => self.b,
+            2 => self.c,
+            3 => self.d,
+            4 => u32::from(*self.mb()),
+            5 => u32::from(*self.mc()),
+            _ => *self.hd(),
+        }
+    }
+
+    fn set(&mut self, loc: u8, v: u32) {
+        match loc {
+            0 => self.a = v,
+            1 => self.b = v,
+            2 => self.c = v,
+            3 => self.d = v,
+            4 => *self.mb() = v as u8,
+            5 => *self.mc() = v as u8,
+            _ => *self.hd() = v,
+        }
+    }
+
+    /// Source operand selected by the low 3 bits of an opcode; 7 is an inline byte.
+    fn operand(&mut self, op: u8) -> u32 {
+        match op & 7 {
+            7 => u32::from(self.fetch()),
+            loc => self.get(loc),
+        }
+    }
+
+    fn jump(&mut self, taken: bool) -> Result<()> {
+        let n = self.fetch();
+        if taken {
+            let target = self.pc as isize + isize::from(n as i8);
+            if target < 0 {
+                return Err(exec_error());
+            }
+            self.pc = target as usize;
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<bool> {
+        let op = self.fetch();
+        match op {
+            56 => return Ok(false),
+            57 if self.keep_output => self.out.push(self.a as u8),
+            57 => {}
+            59 => {
+                let mb = u32::from(*self.mb());
+                self.a = self.a.wrapping_add(mb).wrapping_add(512).wrapping_mul(773);
+            }
+            60 => {
+                let a = self.a;
+                let hd = self.hd();
+                *hd = hd.wrapping_add(a).wrapping_add(512).wrapping_mul(773);
+            }
+            63 => self.jump(true)?,
+            39 => self.jump(self.f)?,
+            47 => self.jump(!self.f)?,
+            55 => {
+                let n = self.fetch();
+                self.r[usize::from(n)] = self.a;
+            }
+            1..=55 => {
+                let loc = op >> 3;
+                match op & 7 {
+                    0 if loc > 0 => self.swap(loc),
+                    1 => {
+                        let v = self.get(loc).wrapping_add(1);
+                        self.set(loc, v);
+                    }
+                    2 => {
+                        let v = self.get(loc).wrapping_sub(1);
+                        self.set(loc, v);
+                    }
+                    3 => {
+                        let v = !self.get(loc);
+                        self.set(loc, v);
+                    }
+                    4 => self.set(loc, 0),
+                    7 if loc < 4 => {
+                        let n = self.fetch();
+                        self.set(loc, self.r[usize::from(n)]);
+                    }
+                    _ => return Err(exec_error()),
+                }
+            }
+            64..=119 => {
+                let v = self.operand(op);
+                self.set((op - 64) >> 3, v);
+            }
+            128..=239 => {
+                let x = self.operand(op);
+                let a = self.a;
+                match (op - 128) >> 3 {
+                    0 => self.a = a.wrapping_add(x),
+                    1 => self.a = a.wrapping_sub(x),
+                    2 => self.a = a.wrapping_mul(x),
+                    3 => self.a = a.checked_div(x).unwrap_or(0),
+                    4 => self.a = a.checked_rem(x).unwrap_or(0),
+                    5 => self.a = a & x,
+                    6 => self.a = a & !x,
+                    7 => self.a = a | x,
+                    8 => self.a = a ^ x,
+                    9 => self.a = a << (x & 31),
+                    10 => self.a = a >> (x & 31),
+                    11 => self.f = a == x,
+                    12 => self.f = a < x,
+                    _ => self.f = a > x,
+                }
+            }
+            255 => {
+                let lo = usize::from(self.fetch());
+                let hi = usize::from(self.fetch());
+                self.pc = lo + 256 * hi;
+                if self.pc >= self.prog.len() {
+                    return Err(exec_error());
+                }
+            }
+            _ => {
+                trace!(op, pc = self.pc - 1, "invalid ZPAQL opcode");
+                return Err(exec_error());
+            }
+        }
+        Ok(true)
+    }
+
+    /// `X<>A`. Byte locations exchange only the low 8 bits of `A`.
+    fn swap(&mut self, loc: u8) {
+        let a = self.a;
+        match loc {
+            4 | 5 => {
+                let old = self.get(loc);
+                self.set(loc, a);
+                self.a = (a & !255) | old;
+            }
+            _ => {
+                let old = self.get(loc);
+                self.set(loc, a);
+                self.a = old;
+            }
+        }
+    }
+}
+
+fn exec_error() -> ZparsError {
+    ZparsError::Corrupt("ZPAQL execution error")
+}
+
+fn alloc_zeroed<T: Clone + Default>(bits: u8, what: &'static str) -> Result<Vec<T>> {
+    if bits > 32 {
+        return Err(ZparsError::InvalidFormat(what));
+    }
+    let n = 1usize << bits;
+    let mut v = Vec::new();
+    v.try_reserve_exact(n)
+        .map_err(|_| ZparsError::Unsupported(what))?;
+    v.resize(n, T::default());
+    Ok(v)
+}
+
+const LOCATIONS: [&str; 7] = ["a", "b", "c", "d", "*b", "*c", "*d"];
+const ALU_OPS: [&str; 14] = [
+    "+=", "-=", "*=", "/=", "%=", "&=", "&~", "|=", "^=", "<<=", ">>=", "==", "<", ">",
+];
+
+/// Mnemonic of `op` in zpaqd config syntax, and how many operand bytes follow.
+pub(crate) fn mnemonic(op: u8) -> Option<(String, usize)> {
+    let name = match op {
+        0 => "error".to_string(),
+        39 => return Some(("jt".into(), 1)),
+        47 => return Some(("jf".into(), 1)),
+        55 => return Some(("r=a".into(), 1)),
+        56 => "halt".to_string(),
+        57 => "out".to_string(),
+        59 => "hash".to_string(),
+        60 => "hashd".to_string(),
+        63 => return Some(("jmp".into(), 1)),
+        1..=55 => {
+            let loc = LOCATIONS[usize::from(op >> 3)];
+            match op & 7 {
+                0 => format!("{loc}<>a"),
+                1 => format!("{loc}++"),
+                2 => format!("{loc}--"),
+                3 => format!("{loc}!"),
+                4 => format!("{loc}=0"),
+                7 if op < 32 => return Some((format!("{loc}=r"), 1)),
+                _ => return None,
+            }
+        }
+        64..=119 => {
+            let dst = LOCATIONS[usize::from((op - 64) >> 3)];
+            return Some(match op & 7 {
+                7 => (format!("{dst}="), 1),
+                src => (format!("{dst}={}", LOCATIONS[usize::from(src)]), 0),
+            });
+        }
+        128..=239 => {
+            let alu = ALU_OPS[usize::from((op - 128) >> 3)];
+            return Some(match op & 7 {
+                7 => (format!("a{alu}"), 1),
+                src => (format!("a{alu}{}", LOCATIONS[usize::from(src)]), 0),
+            });
+        }
+        255 => return Some(("lj".into(), 2)),
+        _ => return None,
+    };
+    Some((name, 0))
+}
+
+/// Disassemble ZPAQL bytecode into `(offset, instruction)` pairs.
+///
+/// Conditional and short jumps print their signed offset, as written in
+/// config files. Undefined opcodes and truncated operands are rendered as
+/// parenthesized comments so the listing stays readable.
+pub fn disassemble(prog: &[u8]) -> Vec<(usize, String)> {
+    let mut out = Vec::new();
+    let mut pc = 0usize;
+    while pc < prog.len() {
+        let op = prog[pc];
+        let Some((name, operands)) = mnemonic(op) else {
+            out.push((pc, format!("(undefined opcode {op})")));
+            pc += 1;
+            continue;
+        };
+        let Some(args) = prog.get(pc + 1..pc + 1 + operands) else {
+            out.push((pc, format!("{name} (truncated)")));
+            break;
+        };
+        let text = match (op, args) {
+            (39 | 47 | 63, &[n]) => format!("{name} {}", n as i8),
+            (255, &[lo, hi]) => format!("{name} {}", usize::from(lo) + 256 * usize::from(hi)),
+            (_, &[n]) => format!("{name} {n}"),
+            _ => name,
+        };
+        out.push((pc, text));
+        pc += 1 + operands;
+    }
+    out
+}
+
+/// Render a block's model as a zpaqd config file (`comp ... hcomp ... end`).
+///
+/// `pcomp` is the postprocessor bytecode including END, or `None` for PASS.
+pub fn disassemble_config(header: &ZpaqBlockHeader, pcomp: Option<&[u8]>) -> String {
+    let mut out = format!(
+        "comp {} {} {} {} {} (hh hm ph pm n)\n",
+        header.hh, header.hm, header.ph, header.pm, header.n_components
+    );
+    let mut cp = 0usize;
+    for i in 0..header.n_components {
+        let t = usize::from(header.comp.get(cp).copied().unwrap_or(0));
+        let size = usize::from(COMP_SIZE.get(t).copied().unwrap_or(0)).max(1);
+        let Some(args) = header.comp.get(cp + 1..cp + size) else {
+            out.push_str(&format!("  {i} (truncated component)\n"));
+            break;
+        };
+        let name = COMP_NAMES.get(t).copied().unwrap_or("");
+        out.push_str(&format!("  {i} {name}"));
+        for a in args {
+            out.push_str(&format!(" {a}"));
+        }
+        out.push('\n');
+        cp += size;
+    }
+
+    out.push_str("hcomp\n");
+    push_listing(&mut out, &header.hcomp);
+    match pcomp {
+        Some(prog) => {
+            out.push_str("pcomp ;\n");
+            push_listing(&mut out, prog);
+        }
+        None => out.push_str("post\n  0\n"),
+    }
+    out.push_str("end\n");
+    out
+}
+
+fn push_listing(out: &mut String, prog: &[u8]) {
+    // The trailing END byte is the `pcomp`/`end` keyword that follows.
+    let body = prog.strip_suffix(&[0]).unwrap_or(prog);
+    for (pc, text) in disassemble(body) {
+        out.push_str(&format!("  ({pc:>4}) {text}\n"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn order1_hash_matches_reference_formula() {
+        // a<<= 8 (a*=256 via shift), *d=a hash, halt:
+        //   a<<= 8; hashd; halt; END
+        let prog = [207, 8, 60, 56, 0];
+        let mut z = Zpaql::new(&prog, 0, 0).expect("vm");
+        z.run(0x41).expect("run");
+        let expected = (0x4100u32 + 512).wrapping_mul(773);
+        assert_eq!(z.h(0), expected);
+        z.run(0x42).expect("run");
+        let expected = expected
+            .wrapping_add(0x4200)
+            .wrapping_add(512)
+            .wrapping_mul(773);
+        assert_eq!(z.h(0), expected);
+    }
+
+    #[test]
+    fn loops_and_output() {
+        // c= 3; do { out; c--; a=c; a> 0 } while: emits input byte 3 times.
+        //   0: c= 3        (87 3)
+        //   2: out         (57)
+        //   3: c--         (18)
+        //   4: b=a         (72)  save input
+        //   5: a=c         (66)
+        //   6: a> 0        (239 0)
+        //   8: a=b         (65)
+        //   9: jt -9 -> 2  (39 247)
+        //  11: halt        (56)
+        let prog = [87, 3, 57, 18, 72, 66, 239, 0, 65, 39, 247, 56, 0];
+        let mut z = Zpaql::new(&prog, 0, 0).expect("vm");
+        z.run(u32::from(b'x')).expect("run");
+        assert_eq!(z.take_output(), b"xxx");
+    }
+
+    #[test]
+    fn hcomp_output_is_ignored() {
+        // out; halt
+        let hcomp = vec![57, 56, 0];
+        let header = ZpaqBlockHeader {
+            start_offset: 0,
+            level: 1,
+            zpaql_type: 1,
+            hsize: (6 + hcomp.len()) as u16,
+            hh: 0,
+            hm: 0,
+            ph: 0,
+            pm: 0,
+            n_components: 0,
+            comp_bytes: 6,
+            hcomp_bytes: hcomp.len(),
+            segment_offset: 0,
+            comp: Vec::new(),
+            hcomp,
+        };
+        let mut z = Zpaql::from_block_header(&header).expect("vm");
+        for c in 0..1000 {
+            z.run(c).expect("run");
+        }
+        assert!(z.take_output().is_empty());
+    }
+
+    #[test]
+    fn invalid_opcode_is_an_error() {
+        let mut z = Zpaql::new(&[5, 56, 0], 0, 0).expect("vm");
+        assert!(z.run(0).is_err());
+        let mut z = Zpaql::new(&[0], 0, 0).expect("vm");
+        assert!(z.run(0).is_err());
+    }
+
+    #[test]
+    fn disassembles_in_config_syntax() {
+        let prog = [239, 255, 39, 3, 135, 1, 57, 56, 7, 2, 255, 44, 1, 5, 207];
+        let listing: Vec<_> = disassemble(&prog).into_iter().map(|(_, t)| t).collect();
+        assert_eq!(
+            listing,
+            [
+                "a> 255",
+                "jt 3",
+                "a+= 1",
+                "out",
+                "halt",
+                "a=r 2",
+                "lj 300",
+                "(undefined opcode 5)",
+                "a<<= (truncated)",
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_whole_block_config() {
+        let header = crate::predictor::tests::test_header();
+        let cfg = disassemble_config(&header, Some(&[57, 56, 0]));
+        let lines: Vec<_> = cfg.lines().collect();
+        assert_eq!(lines[0], "comp 4 0 0 0 9 (hh hm ph pm n)");
+        assert_eq!(lines[1], "  0 const 160");
+        assert_eq!(lines[3], "  2 isse 10 1");
+        assert_eq!(lines[6], "  5 mix 8 1 4 24 255");
+        assert_eq!(lines[10], "hcomp");
+        assert_eq!(lines[11], "  (   0) a<<= 8");
+        assert_eq!(lines[12], "  (   2) d= 0");
+        assert_eq!(lines[13], "  (   4) *d=a");
+        assert!(cfg.ends_with("pcomp ;\n  (   0) out\n  (   1) halt\nend\n"));
+
+        let pass = disassemble_config(&header, None);
+        assert!(pass.ends_with("halt\npost\n  0\nend\n"));
+    }
+}
@@ -1,5 +1,6 @@
 use assert_cmd::Command;
 use predicates::prelude::*;
+use sha1::{Digest, Sha1};
 use std::fs;
 use tempfile::tempdir;
 
@@ -40,6 +41,21 @@ fn cli_roundtrip_file() {
     assert_eq!(out, data);
 }
 
+#[test]
+fn cli_password_options_do_not_describe_subcommands() {
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .arg("--help")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Password of an encrypted").not());
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args(["list", "--help"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Usage:"))
+        .stdout(predicate::str::contains("--password <PASSWORD>"));
+}
+
 #[test]
 fn cli_rejects_invalid_stream() {
     let dir = tempdir().expect("tempdir");
@@ -100,3 +116,830 @@ fn cli_compress_directory_as_tar_stream() {
         .expect("read restored file");
     assert_eq!(restored, b"hello directory compression");
 }
+
+#[test]
+fn cli_verify_zpaq_reports_sha1_mismatch() {
+    let dir = tempdir().expect("tempdir");
+    let archive = dir.path().join("bad.zpaq");
+
+    // One stored (-m0) block whose segment trailer holds the SHA-1 of other data.
+    let mut buf = vec![
+        0x37, 0x6b, 0x53, 0x74, 0xa0, 0x31, 0x83, 0xd3, 0x8c, 0xb2, 0x28, 0xb0, 0xd3, b'z', b'P',
+        b'Q', 2, 1, 7, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    buf.extend_from_slice(b"\x01f.txt\x00\x00\x00");
+    buf.extend_from_slice(&[0, 0, 0, 4, 0, b'a', b'b', b'c', 0, 0, 0, 0, 253]);
+    buf.extend_from_slice(&[0x11; 20]);
+    buf.push(255);
+    fs::write(&archive, &buf).expect("write archive");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args(["verify-zpaq", "-i", archive.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "block=0 file=\"f.txt\" sha1=mismatch",
+        ))
+        .stderr(predicate::str::contains("failed SHA-1 verification"));
+}
+
+#[test]
+fn cli_compress_zpaq_format_extracts_natively() {
+    let dir = tempdir().expect("tempdir");
+    let input_dir = dir.path().join("docs");
+    let archive = dir.path().join("docs.zpaq");
+    let restore_dir = dir.path().join("restored");
+
+    fs::create_dir_all(input_dir.join("sub")).expect("mkdir");
+    let text = b"modeled zpaq output from the compress subcommand\n".repeat(40);
+    fs::write(input_dir.join("a.txt"), &text).expect("write");
+    fs::write(input_dir.join("sub").join("b.bin"), b"\x00\x01\x02").expect("write");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "compress",
+            "-i",
+            input_dir.to_str().unwrap(),
+            "-o",
+            archive.to_str().unwrap(),
+            "--format",
+            "zpaq",
+            "--level",
+            "4",
+        ])
+        .assert()
+        .success();
+
+    // Both files share one block of the level 4 model.
+    let blocks = zpars::inspect_zpaq_file(&archive).expect("inspect");
+    assert_eq!(blocks.len(), 1);
+    assert!(blocks[0].level == 1 && blocks[0].n_components == 8);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "extract-zpaq",
+            "-i",
+            archive.to_str().unwrap(),
+            "-o",
+            restore_dir.to_str().unwrap(),
+            "--allow-reference-fallback",
+            "false",
+        ])
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(restore_dir.join("a.txt")).expect("a"), text);
+    assert_eq!(
+        fs::read(restore_dir.join("sub").join("b.bin")).expect("b"),
+        b"\x00\x01\x02"
+    );
+}
+
+#[test]
+fn cli_compile_zpaql_writes_header_and_pcomp() {
+    let dir = tempdir().expect("tempdir");
+    let config = dir.path().join("model.cfg");
+    let header = dir.path().join("header.bin");
+    let pcomp = dir.path().join("pcomp.bin");
+    let src = "comp 0 0 0 0 1\n  0 cm 9 $1\nhcomp\n  halt\npcomp ./pre 1 ;\n  a> 255 ifnot out endif\n  halt\nend\n";
+    fs::write(&config, src).expect("write config");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "compile-zpaql",
+            "-i",
+            config.to_str().unwrap(),
+            "-o",
+            header.to_str().unwrap(),
+            "--pcomp-output",
+            pcomp.to_str().unwrap(),
+            "--arg",
+            "20",
+        ])
+        .assert()
+        .success();
+
+    let model = zpars::compile_zpaql_with_args(src, &[20]).expect("compile");
+    let program = model.pcomp.clone().expect("pcomp");
+    assert_eq!(fs::read(&header).expect("header"), model.header_bytes());
+    let written = fs::read(&pcomp).expect("pcomp");
+    assert_eq!(written[0], 1);
+    assert_eq!(
+        usize::from(u16::from_le_bytes([written[1], written[2]])),
+        program.len()
+    );
+    assert_eq!(written[3..], program[..]);
+}
+
+fn stored_jdc_block(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut w = zpars::ZpaqWriter::new(Vec::new());
+    w.start_block().expect("block");
+    w.start_segment(name, "").expect("segment");
+    w.write_data(data).expect("data");
+    w.end_segment().expect("end");
+    w.end_block().expect("end block");
+    w.finish().expect("finish")
+}
+
+/// One journaling transaction: a single fragment `frag` shared by every file
+/// in `names`. `d_block` replaces the stored data block when given.
+fn journal_archive(frag: &[u8], names: &[&str], d_block: Option<Vec<u8>>) -> Vec<u8> {
+    let mut d = frag.to_vec();
+    d.extend_from_slice(&(frag.len() as u32).to_le_bytes());
+    d.extend_from_slice(&1u32.to_le_bytes());
+    d.extend_from_slice(&1u32.to_le_bytes());
+    let d_block = d_block.unwrap_or_else(|| stored_jdc_block("jDC20240102030405d0000000001", &d));
+    let mut h = (d_block.len() as u32).to_le_bytes().to_vec();
+    h.extend_from_slice(&Sha1::digest(frag));
+    h.extend_from_slice(&(frag.len() as u32).to_le_bytes());
+    let mut i = Vec::new();
+    for name in names {
+        i.extend_from_slice(&20240101000000u64.to_le_bytes());
+        i.extend_from_slice(name.as_bytes());
+        i.push(0);
+        i.extend_from_slice(&5u32.to_le_bytes());
+        i.extend_from_slice(b"u\xa4\x81\x00\x00");
+        i.extend_from_slice(&1u32.to_le_bytes());
+        i.extend_from_slice(&1u32.to_le_bytes());
+    }
+
+    let csize = d_block.len() as u64;
+    let mut archive = stored_jdc_block("jDC20240102030405c0000000001", &csize.to_le_bytes());
+    archive.extend(d_block);
+    archive.extend(stored_jdc_block("jDC20240102030405h0000000001", &h));
+    archive.extend(stored_jdc_block("jDC20240102030405i0000000001", &i));
+    archive
+}
+
+#[test]
+fn cli_extract_zpaq_rebuilds_journaling_file_tree() {
+    let dir = tempdir().expect("tempdir");
+    let archive = dir.path().join("journal.zpaq");
+    let out = dir.path().join("out");
+
+    // The last index record's path would escape the output directory.
+    let frag = b"journaled contents\n";
+    let names = ["docs/a.txt", "/abs/b.txt", "../evil.txt"];
+    fs::write(&archive, journal_archive(frag, &names, None)).expect("write archive");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "extract-zpaq",
+            "-i",
+            archive.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--allow-reference-fallback",
+            "false",
+        ])
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(out.join("docs").join("a.txt")).expect("a"), frag);
+    assert_eq!(fs::read(out.join("abs").join("b.txt")).expect("b"), frag);
+    assert!(!dir.path().join("evil.txt").exists());
+    assert!(!out.join("jDC20240102030405c0000000001").exists());
+}
+
+#[test]
+fn cli_extractors_keep_segment_names_inside_output_dir() {
+    let dir = tempdir().expect("tempdir");
+    let archive = dir.path().join("plain.zpaq");
+    let mut bytes = Vec::new();
+    for name in ["docs/a.txt", "/abs/b.txt", "../evil.txt", ""] {
+        bytes.extend(stored_jdc_block(name, b"segment data\n"));
+    }
+    fs::write(&archive, bytes).expect("write archive");
+
+    for (i, command) in ["extract-zpaq", "extract-zpaq-m0", "salvage"]
+        .iter()
+        .enumerate()
+    {
+        let out = dir.path().join(format!("out{i}"));
+        Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+            .args([
+                command,
+                "-i",
+                archive.to_str().unwrap(),
+                "-o",
+                out.to_str().unwrap(),
+            ])
+            .assert()
+            .success();
+
+        assert!(out.join("docs").join("a.txt").exists(), "{command}");
+        assert!(out.join("abs").join("b.txt").exists(), "{command}");
+        assert!(out.join("block3_segment.bin").exists(), "{command}");
+        assert!(!dir.path().join("evil.txt").exists(), "{command}");
+    }
+}
+
+#[test]
+fn cli_list_reads_index_without_decoding_data_blocks() {
+    let dir = tempdir().expect("tempdir");
+    let archive = dir.path().join("journal.zpaq");
+
+    // A modeled data block whose model (an ICM of 2^30 entries) is rejected;
+    // listing must not try to build it.
+    let mut d = vec![
+        0x37, 0x6b, 0x53, 0x74, 0xa0, 0x31, 0x83, 0xd3, 0x8c, 0xb2, 0x28, 0xb0, 0xd3, b'z', b'P',
+        b'Q', 1, 1, 9, 0, 0, 0, 0, 0, 1, 3, 30, 0, 0,
+    ];
+    d.extend_from_slice(b"\x01jDC20240102030405d0000000001\x00\x00\x00");
+    d.extend_from_slice(&[0x5a; 64]);
+    d.push(255);
+    let frag = b"0123456789";
+    let bytes = journal_archive(frag, &["docs/a.txt", "b.txt"], Some(d));
+    fs::write(&archive, bytes).expect("write archive");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args(["list", archive.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "      1  2024-01-02 03:04:05       2        0",
+        ))
+        .stdout(predicate::str::contains(
+            "2024-01-01 00:00:00  100644              10      1  docs/a.txt",
+        ))
+        .stdout(predicate::str::contains("2 files, 20 bytes"));
+
+    let out = Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args(["list", archive.to_str().unwrap(), "--json"])
+        .output()
+        .expect("run list");
+    assert!(out.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout).expect("json");
+    assert_eq!(json["versions"][0]["date"], "2024-01-02 03:04:05");
+    assert_eq!(json["versions"][0]["files"], 2);
+    assert_eq!(json["files"][1]["name"], "docs/a.txt");
+    assert_eq!(json["files"][1]["size"], 10);
+    assert_eq!(json["files"][1]["attr"], "100644");
+    assert_eq!(json["files"][1]["fragments"], 1);
+}
+
+#[test]
+fn cli_extract_zpaq_until_skips_later_data_blocks() {
+    let dir = tempdir().expect("tempdir");
+    let archive = dir.path().join("journal.zpaq");
+    let out = dir.path().join("out");
+
+    let mut bytes = journal_archive(b"first version\n", &["notes.txt"], None);
+    // A second transaction rewrites notes.txt from a data block whose model
+    // cannot be built; rolling back to version 1 must not decode it.
+    let mut d = vec![
+        0x37, 0x6b, 0x53, 0x74, 0xa0, 0x31, 0x83, 0xd3, 0x8c, 0xb2, 0x28, 0xb0, 0xd3, b'z', b'P',
+        b'Q', 1, 1, 9, 0, 0, 0, 0, 0, 1, 3, 30, 0, 0,
+    ];
+    d.extend_from_slice(b"\x01jDC20240105000000d0000000002\x00\x00\x00");
+    d.extend_from_slice(&[0x5a; 64]);
+    d.push(255);
+    bytes.extend(stored_jdc_block(
+        "jDC20240105000000c0000000002",
+        &(d.len() as u64).to_le_bytes(),
+    ));
+    bytes.extend_from_slice(&d);
+    let mut h = (d.len() as u32).to_le_bytes().to_vec();
+    h.extend_from_slice(&Sha1::digest(b"second"));
+    h.extend_from_slice(&6u32.to_le_bytes());
+    bytes.extend(stored_jdc_block("jDC20240105000000h0000000002", &h));
+    let mut i = 20240104000000u64.to_le_bytes().to_vec();
+    i.extend_from_slice(b"notes.txt\x00");
+    i.extend_from_slice(&0u32.to_le_bytes());
+    i.extend_from_slice(&1u32.to_le_bytes());
+    i.extend_from_slice(&2u32.to_le_bytes());
+    bytes.extend(stored_jdc_block("jDC20240105000000i0000000001", &i));
+    fs::write(&archive, bytes).expect("write archive");
+
+    for until in ["1", "2024-01-03"] {
+        let _ = fs::remove_dir_all(&out);
+        Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+            .args([
+                "extract-zpaq",
+                "-i",
+                archive.to_str().unwrap(),
+                "-o",
+                out.to_str().unwrap(),
+                "--until",
+                until,
+                "--allow-reference-fallback",
+                "false",
+            ])
+            .assert()
+            .success();
+        assert_eq!(
+            fs::read(out.join("notes.txt")).expect("notes"),
+            b"first version\n"
+        );
+    }
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "extract-zpaq",
+            "-i",
+            archive.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--until",
+            "2023-12-31",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("no version"));
+}
+
+#[test]
+fn cli_add_appends_transactions_for_changed_files() {
+    let dir = tempdir().expect("tempdir");
+    let docs = dir.path().join("docs");
+    fs::create_dir(&docs).expect("docs");
+    fs::write(docs.join("a.txt"), b"alpha\n").expect("a");
+    fs::write(docs.join("b.txt"), b"beta\n".repeat(1000)).expect("b");
+
+    let add = || {
+        Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+            .current_dir(dir.path())
+            .args(["add", "backup.zpaq", "docs"])
+            .assert()
+            .success();
+    };
+    let list = || {
+        let out = Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+            .current_dir(dir.path())
+            .args(["list", "backup.zpaq", "--json"])
+            .output()
+            .expect("list");
+        assert!(out.status.success());
+        serde_json::from_slice::<serde_json::Value>(&out.stdout).expect("json")
+    };
+
+    add();
+    let report = list();
+    assert_eq!(report["versions"].as_array().unwrap().len(), 1);
+    let names: Vec<_> = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["name"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(names, ["docs/", "docs/a.txt", "docs/b.txt"]);
+
+    fs::write(docs.join("a.txt"), b"alpha, revised\n").expect("a");
+    fs::remove_file(docs.join("b.txt")).expect("remove b");
+    fs::write(docs.join("c.txt"), b"gamma\n").expect("c");
+    add();
+    let report = list();
+    let versions = report["versions"].as_array().unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[1]["deleted"], 1);
+    assert!(versions[0]["date"].as_str() < versions[1]["date"].as_str());
+
+    // Nothing changed, so nothing is appended.
+    let size = fs::metadata(dir.path().join("backup.zpaq")).unwrap().len();
+    add();
+    assert_eq!(
+        fs::metadata(dir.path().join("backup.zpaq")).unwrap().len(),
+        size
+    );
+
+    let extract = |out: &str, until: Option<&str>| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("zpars"));
+        cmd.current_dir(dir.path()).args([
+            "extract-zpaq",
+            "-i",
+            "backup.zpaq",
+            "-o",
+            out,
+            "--allow-reference-fallback",
+            "false",
+        ]);
+        if let Some(until) = until {
+            cmd.args(["--until", until]);
+        }
+        cmd.assert().success();
+        dir.path().join(out).join("docs")
+    };
+    let latest = extract("latest", None);
+    assert_eq!(fs::read(latest.join("a.txt")).unwrap(), b"alpha, revised\n");
+    assert_eq!(fs::read(latest.join("c.txt")).unwrap(), b"gamma\n");
+    assert!(!latest.join("b.txt").exists());
+
+    let first = extract("first", Some("1"));
+    assert_eq!(fs::read(first.join("a.txt")).unwrap(), b"alpha\n");
+    assert_eq!(
+        fs::read(first.join("b.txt")).unwrap(),
+        b"beta\n".repeat(1000)
+    );
+    assert!(!first.join("c.txt").exists());
+}
+
+#[test]
+fn cli_add_stores_duplicate_content_once() {
+    let dir = tempdir().expect("tempdir");
+    let docs = dir.path().join("docs");
+    fs::create_dir(&docs).expect("docs");
+    let mut x = 12345u32;
+    let data: Vec<u8> = (0..300_000)
+        .map(|_| {
+            x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+            (x >> 24) as u8
+        })
+        .collect();
+    fs::write(docs.join("one.bin"), &data).expect("one");
+    fs::write(docs.join("two.bin"), &data).expect("two");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .current_dir(dir.path())
+        .args([
+            "--log-format",
+            "json",
+            "add",
+            "backup.zpaq",
+            "docs",
+            "--level",
+            "0",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"dedup_hits\":9"));
+
+    let size = fs::metadata(dir.path().join("backup.zpaq")).unwrap().len();
+    assert!(size < data.len() as u64 + 20_000, "archive is {size} bytes");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .current_dir(dir.path())
+        .args(["extract-zpaq", "-i", "backup.zpaq", "-o", "out"])
+        .assert()
+        .success();
+    assert_eq!(fs::read(dir.path().join("out/docs/two.bin")).unwrap(), data);
+}
+
+#[test]
+fn cli_reads_encrypted_journaling_archives() {
+    use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+
+    let dir = tempdir().expect("tempdir");
+    let archive = dir.path().join("secret.zpaq");
+    let password_file = dir.path().join("password.txt");
+    let out = dir.path().join("out");
+
+    // zpaq -key layout: a salt, then the archive under AES-256-CTR whose
+    // counter starts with the salt and counts 16-byte blocks of the file.
+    let salt: [u8; 32] = std::array::from_fn(|i| (i * 13 + 5) as u8);
+    let key = zpars::derive_zpaq_key(b"correct horse", &salt);
+    let mut iv = [0u8; 16];
+    iv[..8].copy_from_slice(&salt[..8]);
+    let mut cipher = ctr::Ctr64BE::<aes::Aes256>::new(&(*key).into(), &iv.into());
+    cipher.seek(32u64);
+    let mut data = journal_archive(b"top secret\n", &["notes.txt"], None);
+    cipher.apply_keystream(&mut data);
+    let mut bytes = salt.to_vec();
+    bytes.extend(data);
+    fs::write(&archive, bytes).expect("write archive");
+    fs::write(&password_file, "correct horse\n").expect("write password");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "list",
+            archive.to_str().unwrap(),
+            "--password",
+            "correct horse",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("notes.txt"));
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "extract-zpaq",
+            "-i",
+            archive.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--password-file",
+            password_file.to_str().unwrap(),
+            "--allow-reference-fallback",
+            "false",
+        ])
+        .assert()
+        .success();
+    assert_eq!(fs::read(out.join("notes.txt")).unwrap(), b"top secret\n");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "verify-zpaq",
+            "-i",
+            archive.to_str().unwrap(),
+            "--password",
+            "wrong horse",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("wrong password"));
+
+    // The password is never handed to the reference extractor's argv.
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "extract-zpaq",
+            "-i",
+            archive.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--password-file",
+            password_file.to_str().unwrap(),
+            "--reference",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--reference cannot extract encrypted archives",
+        ));
+}
+
+#[test]
+fn cli_add_creates_and_appends_encrypted_archives() {
+    let dir = tempdir().expect("tempdir");
+    let docs = dir.path().join("docs");
+    fs::create_dir(&docs).expect("docs");
+    fs::write(docs.join("plan.txt"), b"launch at dawn\n").expect("plan");
+
+    let zpars = |args: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("zpars"));
+        cmd.current_dir(dir.path()).args(args);
+        cmd
+    };
+    zpars(&["add", "vault.zpaq", "docs", "--password", "s3cret"])
+        .assert()
+        .success();
+    fs::write(docs.join("later.txt"), b"second transaction\n").expect("later");
+    zpars(&["add", "vault.zpaq", "docs", "--password", "s3cret"])
+        .assert()
+        .success();
+    zpars(&["add", "vault.zpaq", "docs", "--password", "guess"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("wrong password"));
+
+    let bytes = fs::read(dir.path().join("vault.zpaq")).expect("archive");
+    let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+    assert!(!contains(b"plan.txt") && !contains(b"launch at dawn") && !contains(b"zPQ"));
+
+    zpars(&["list", "vault.zpaq", "--password", "s3cret"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("docs/later.txt"));
+    zpars(&[
+        "extract-zpaq",
+        "-i",
+        "vault.zpaq",
+        "-o",
+        "out",
+        "--password",
+        "s3cret",
+        "--until",
+        "1",
+    ])
+    .assert()
+    .success();
+    assert_eq!(
+        fs::read(dir.path().join("out/docs/plan.txt")).unwrap(),
+        b"launch at dawn\n"
+    );
+    assert!(!dir.path().join("out/docs/later.txt").exists());
+}
+
+#[test]
+fn cli_add_writes_a_new_part_per_transaction() {
+    let dir = tempdir().expect("tempdir");
+    let docs = dir.path().join("docs");
+    fs::create_dir(&docs).expect("docs");
+    fs::write(docs.join("day1.txt"), b"monday\n").expect("day1");
+
+    let zpars = |args: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("zpars"));
+        cmd.current_dir(dir.path()).args(args);
+        cmd
+    };
+    for pattern in ["backup??.zpaq", "vault??.zpaq"] {
+        let password: &[&str] = if pattern.starts_with("vault") {
+            &["--password", "pw"]
+        } else {
+            &[]
+        };
+        let _ = fs::remove_file(docs.join("day2.txt"));
+        zpars(&[&["add", pattern, "docs"], password].concat())
+            .assert()
+            .success();
+        fs::write(docs.join("day2.txt"), b"tuesday\n").expect("day2");
+        zpars(&[&["add", pattern, "docs"], password].concat())
+            .assert()
+            .success();
+
+        let stem = pattern.trim_end_matches("??.zpaq");
+        let part1 = fs::read(dir.path().join(format!("{stem}01.zpaq"))).expect("part 1");
+        let part2 = fs::read(dir.path().join(format!("{stem}02.zpaq"))).expect("part 2");
+        assert!(!dir.path().join(format!("{stem}03.zpaq")).exists());
+        let contains = |part: &[u8], needle: &[u8]| part.windows(needle.len()).any(|w| w == needle);
+        if password.is_empty() {
+            // Index blocks are stored, so the file names are visible.
+            assert!(contains(&part1, b"day1.txt") && !contains(&part1, b"day2.txt"));
+            assert!(contains(&part2, b"day2.txt") && !contains(&part2, b"day1.txt"));
+        }
+
+        let out = format!("out-{stem}");
+        zpars(&[&["extract-zpaq", "-i", pattern, "-o", &out], password].concat())
+            .assert()
+            .success();
+        let restored = dir.path().join(&out).join("docs");
+        assert_eq!(fs::read(restored.join("day1.txt")).unwrap(), b"monday\n");
+        assert_eq!(fs::read(restored.join("day2.txt")).unwrap(), b"tuesday\n");
+    }
+
+    zpars(&["list", "backup??.zpaq", "--json"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"version\": 2"));
+    zpars(&["inspect-zpaq", "-i", "missing??.zpaq"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("no parts"));
+}
+
+#[test]
+fn cli_add_with_index_writes_parts_without_the_earlier_ones() {
+    let dir = tempdir().expect("tempdir");
+    let docs = dir.path().join("docs");
+    let offsite = dir.path().join("offsite");
+    fs::create_dir(&docs).expect("docs");
+    fs::create_dir(&offsite).expect("offsite");
+    let mut x = 5u32;
+    let big: Vec<u8> = (0..200_000)
+        .map(|_| {
+            x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+            (x >> 24) as u8
+        })
+        .collect();
+
+    let zpars = |args: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("zpars"));
+        cmd.current_dir(dir.path()).args(args);
+        cmd
+    };
+    for stem in ["backup", "vault"] {
+        let password: &[&str] = if stem == "vault" {
+            &["--password", "pw"]
+        } else {
+            &[]
+        };
+        let pattern = format!("{stem}??.zpaq");
+        let index = format!("{stem}-index.zpaq");
+        let add = || {
+            zpars(&[&["add", &pattern, "docs", "--index", &index], password].concat())
+                .assert()
+                .success();
+        };
+        let _ = fs::remove_file(docs.join("copy.bin"));
+        fs::write(docs.join("big.bin"), &big).expect("big");
+        add();
+
+        // Part 1 goes offsite; the next part only holds the new file, as the
+        // copy of big.bin dedups against the index.
+        let part1 = format!("{stem}01.zpaq");
+        fs::rename(dir.path().join(&part1), offsite.join(&part1)).expect("move part 1");
+        fs::write(docs.join("copy.bin"), &big).expect("copy");
+        fs::write(docs.join("new.txt"), b"written later\n").expect("new");
+        add();
+        let part2 = fs::metadata(dir.path().join(format!("{stem}02.zpaq"))).expect("part 2");
+        assert!(part2.len() < 10_000, "part 2 is {} bytes", part2.len());
+        let index_len = fs::metadata(dir.path().join(&index)).expect("index").len();
+        assert!(index_len < 10_000, "index is {index_len} bytes");
+
+        zpars(&[&["list", &index, "--json"], password].concat())
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("\"version\": 2"))
+            .stdout(predicate::str::contains("copy.bin"));
+
+        fs::rename(offsite.join(&part1), dir.path().join(&part1)).expect("restore part 1");
+        let out = format!("out-{stem}");
+        zpars(&[&["extract-zpaq", "-i", &pattern, "-o", &out], password].concat())
+            .assert()
+            .success();
+        let restored = dir.path().join(&out).join("docs");
+        assert_eq!(fs::read(restored.join("big.bin")).unwrap(), big);
+        assert_eq!(fs::read(restored.join("copy.bin")).unwrap(), big);
+        assert_eq!(
+            fs::read(restored.join("new.txt")).unwrap(),
+            b"written later\n"
+        );
+        fs::remove_file(docs.join("new.txt")).expect("remove new");
+    }
+
+    zpars(&["add", "single.zpaq", "docs", "--index", "single-index.zpaq"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("multi-part"));
+    zpars(&["add", "backup??.zpaq", "docs", "--index", "lost-index.zpaq"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("index lost-index.zpaq is missing"));
+}
+
+#[test]
+fn cli_salvage_recovers_files_around_damaged_blocks() {
+    let dir = tempdir().expect("tempdir");
+    let docs = dir.path().join("docs");
+    fs::create_dir(&docs).expect("docs");
+    let zpars = |args: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("zpars"));
+        cmd.current_dir(dir.path()).args(args);
+        cmd
+    };
+    fs::write(docs.join("a.txt"), b"kept safe\n".repeat(100)).expect("a");
+    zpars(&["add", "backup.zpaq", "docs", "--level", "0"])
+        .assert()
+        .success();
+    fs::write(docs.join("b.txt"), b"bit rot victim\n".repeat(100)).expect("b");
+    zpars(&["add", "backup.zpaq", "docs", "--level", "0"])
+        .assert()
+        .success();
+    fs::write(docs.join("c.txt"), b"added after\n".repeat(100)).expect("c");
+    zpars(&["add", "backup.zpaq", "docs", "--level", "0"])
+        .assert()
+        .success();
+
+    // Flip one bit inside b.txt's stored fragment.
+    let archive = dir.path().join("backup.zpaq");
+    let mut bytes = fs::read(&archive).expect("archive");
+    let at = bytes
+        .windows(14)
+        .position(|w| w == b"bit rot victim")
+        .expect("b.txt data");
+    bytes[at + 500] ^= 0x10;
+    fs::write(&archive, bytes).expect("damage");
+    zpars(&["extract-zpaq", "-i", "backup.zpaq", "-o", "out"])
+        .assert()
+        .failure();
+
+    zpars(&["salvage", "-i", "backup.zpaq", "-o", "salvaged"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("damaged bytes="))
+        .stdout(predicate::str::contains("d0000000002"))
+        .stdout(predicate::str::contains("lost file=\"docs/b.txt\""));
+    let restored = dir.path().join("salvaged/docs");
+    assert_eq!(
+        fs::read(restored.join("a.txt")).unwrap(),
+        b"kept safe\n".repeat(100)
+    );
+    assert_eq!(
+        fs::read(restored.join("c.txt")).unwrap(),
+        b"added after\n".repeat(100)
+    );
+    assert!(!restored.join("b.txt").exists());
+}
+
+#[test]
+fn cli_extract_zpaq_decodes_blocks_on_threads() {
+    let dir = tempdir().expect("tempdir");
+    let docs = dir.path().join("docs");
+    fs::create_dir(&docs).expect("docs");
+    let zpars = |args: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("zpars"));
+        cmd.current_dir(dir.path()).args(args);
+        cmd
+    };
+    for i in 0..4 {
+        fs::write(
+            docs.join(format!("day{i}.txt")),
+            format!("entry for day {i}\n").repeat(500),
+        )
+        .expect("write");
+        zpars(&["add", "backup.zpaq", "docs", "--level", "1"])
+            .assert()
+            .success();
+    }
+
+    for threads in ["4", "0"] {
+        let out = format!("out{threads}");
+        zpars(&[
+            "extract-zpaq",
+            "-i",
+            "backup.zpaq",
+            "-o",
+            &out,
+            "--threads",
+            threads,
+            "--allow-reference-fallback",
+            "false",
+        ])
+        .assert()
+        .success();
+        for i in 0..4 {
+            assert_eq!(
+                fs::read(dir.path().join(&out).join(format!("docs/day{i}.txt"))).unwrap(),
+                format!("entry for day {i}\n").repeat(500).as_bytes()
+            );
+        }
+    }
+}
@@ -178,3 +178,336 @@ fn auto_extract_falls_back_for_modeled_archives() {
     let restored = fs::read(out.join("src_m1.txt")).expect("read restored");
     assert_eq!(restored, payload);
 }
+
+#[test]
+fn reference_extracts_rust_written_stored_archive() {
+    ensure_ref_built();
+
+    let dir = tempdir().expect("tempdir");
+    let archive = dir.path().join("rust_m0.zpaq");
+    let out = dir.path().join("out");
+    fs::create_dir_all(&out).expect("mkdir out");
+
+    let payload = b"stored archive written by zpars\n".repeat(100);
+    let mut w = zpars::ZpaqWriter::new(Vec::new());
+    w.start_block().expect("start block");
+    w.start_segment("rust.txt", "").expect("start segment");
+    w.write_data(&payload).expect("write data");
+    w.end_segment().expect("end segment");
+    w.end_block().expect("end block");
+    fs::write(&archive, w.finish().expect("finish")).expect("write archive");
+
+    let status = StdCommand::new(ref_bin())
+        .current_dir(&out)
+        .args(["x", archive.to_str().unwrap(), "-t1"])
+        .status()
+        .expect("run zpaq extract");
+    assert!(status.success(), "zpaq extract failed");
+
+    let restored = fs::read(out.join("rust.txt")).expect("read restored");
+    assert_eq!(restored, payload);
+}
+
+#[test]
+fn reference_extracts_rust_modeled_archives() {
+    ensure_ref_built();
+
+    let dir = tempdir().expect("tempdir");
+    let src = dir.path().join("src.txt");
+    let payload = b"modeled archive written by zpars for zpaq x\n".repeat(200);
+    fs::write(&src, &payload).expect("write src");
+
+    for level in 1..=5 {
+        let archive = dir.path().join(format!("m{level}.zpaq"));
+        let out = dir.path().join(format!("out{level}"));
+        fs::create_dir_all(&out).expect("mkdir out");
+
+        Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+            .args([
+                "compress",
+                "-i",
+                src.to_str().unwrap(),
+                "-o",
+                archive.to_str().unwrap(),
+                "--format",
+                "zpaq",
+                "--level",
+                &level.to_string(),
+            ])
+            .assert()
+            .success();
+
+        let status = StdCommand::new(ref_bin())
+            .current_dir(&out)
+            .args(["x", archive.to_str().unwrap(), "-t1"])
+            .status()
+            .expect("run zpaq extract");
+        assert!(status.success(), "zpaq extract failed at level {level}");
+
+        let restored = fs::read(out.join("src.txt")).expect("read restored");
+        assert_eq!(restored, payload, "level {level}");
+    }
+}
+
+#[test]
+fn reference_extracts_rust_appended_transactions() {
+    ensure_ref_built();
+
+    let dir = tempdir().expect("tempdir");
+    let docs = dir.path().join("docs");
+    fs::create_dir_all(&docs).expect("mkdir docs");
+    fs::write(docs.join("a.txt"), b"first version\n".repeat(100)).expect("write a");
+    fs::write(docs.join("b.txt"), b"removed later\n").expect("write b");
+
+    let add = || {
+        Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+            .current_dir(dir.path())
+            .args(["add", "backup.zpaq", "docs", "--level", "2"])
+            .assert()
+            .success();
+    };
+    add();
+    fs::write(docs.join("a.txt"), b"second version\n".repeat(100)).expect("write a");
+    fs::remove_file(docs.join("b.txt")).expect("remove b");
+    add();
+
+    for (until, a, b_exists) in [
+        ("1", "first version\n", true),
+        ("2", "second version\n", false),
+    ] {
+        let out = dir.path().join(format!("out{until}"));
+        fs::create_dir_all(&out).expect("mkdir out");
+        let status = StdCommand::new(ref_bin())
+            .current_dir(&out)
+            .args([
+                "x",
+                dir.path().join("backup.zpaq").to_str().unwrap(),
+                "-until",
+                until,
+            ])
+            .status()
+            .expect("run zpaq extract");
+        assert!(status.success(), "zpaq extract failed at version {until}");
+        assert_eq!(
+            fs::read(out.join("docs/a.txt")).expect("read a"),
+            a.repeat(100).as_bytes()
+        );
+        assert_eq!(out.join("docs/b.txt").exists(), b_exists);
+    }
+}
+
+#[test]
+fn rust_reads_reference_encrypted_archive() {
+    ensure_ref_built();
+
+    let dir = tempdir().expect("tempdir");
+    let payload = b"encrypted by zpaq -key\n".repeat(50);
+    fs::write(dir.path().join("src.txt"), &payload).expect("write src");
+    let archive = dir.path().join("secret.zpaq");
+
+    let status = StdCommand::new(ref_bin())
+        .current_dir(dir.path())
+        .args([
+            "a",
+            archive.to_str().unwrap(),
+            "src.txt",
+            "-m1",
+            "-t1",
+            "-key",
+            "swordfish",
+        ])
+        .status()
+        .expect("run zpaq add");
+    assert!(status.success(), "zpaq add failed");
+
+    let out = dir.path().join("out");
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "extract-zpaq",
+            "-i",
+            archive.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--password",
+            "swordfish",
+            "--allow-reference-fallback",
+            "false",
+        ])
+        .assert()
+        .success();
+    assert_eq!(fs::read(out.join("src.txt")).expect("read"), payload);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .args([
+            "list",
+            archive.to_str().unwrap(),
+            "--password",
+            "swordfish!",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("wrong password"));
+}
+
+#[test]
+fn reference_extracts_rust_encrypted_archive() {
+    ensure_ref_built();
+
+    let dir = tempdir().expect("tempdir");
+    fs::create_dir_all(dir.path().join("docs")).expect("mkdir docs");
+    let payload = b"encrypted by zpars add\n".repeat(100);
+    fs::write(dir.path().join("docs/a.txt"), &payload).expect("write a");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .current_dir(dir.path())
+        .args(["add", "vault.zpaq", "docs", "--password", "swordfish"])
+        .assert()
+        .success();
+
+    let out = dir.path().join("out");
+    fs::create_dir_all(&out).expect("mkdir out");
+    let status = StdCommand::new(ref_bin())
+        .current_dir(&out)
+        .args([
+            "x",
+            dir.path().join("vault.zpaq").to_str().unwrap(),
+            "-key",
+            "swordfish",
+        ])
+        .status()
+        .expect("run zpaq extract");
+    assert!(status.success(), "zpaq extract failed");
+    assert_eq!(fs::read(out.join("docs/a.txt")).expect("read a"), payload);
+}
+
+#[test]
+fn multi_part_archives_interoperate() {
+    ensure_ref_built();
+
+    let dir = tempdir().expect("tempdir");
+    fs::create_dir_all(dir.path().join("docs")).expect("mkdir docs");
+    fs::write(dir.path().join("docs/a.txt"), b"first part\n").expect("write a");
+
+    // Reference parts 1 and 2, then a third part from zpars.
+    let zpaq_add = || {
+        let status = StdCommand::new(ref_bin())
+            .current_dir(dir.path())
+            .args(["a", "b??.zpaq", "docs", "-m1", "-t1"])
+            .status()
+            .expect("run zpaq add");
+        assert!(status.success(), "zpaq add failed");
+    };
+    zpaq_add();
+    fs::write(dir.path().join("docs/b.txt"), b"second part\n").expect("write b");
+    zpaq_add();
+    fs::write(dir.path().join("docs/c.txt"), b"third part\n").expect("write c");
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .current_dir(dir.path())
+        .args(["add", "b??.zpaq", "docs"])
+        .assert()
+        .success();
+    assert!(dir.path().join("b03.zpaq").exists());
+
+    let out = dir.path().join("out");
+    fs::create_dir_all(&out).expect("mkdir out");
+    let status = StdCommand::new(ref_bin())
+        .current_dir(&out)
+        .args(["x", dir.path().join("b??.zpaq").to_str().unwrap()])
+        .status()
+        .expect("run zpaq extract");
+    assert!(status.success(), "zpaq extract failed");
+    for (name, data) in [("a", "first"), ("b", "second"), ("c", "third")] {
+        assert_eq!(
+            fs::read(out.join(format!("docs/{name}.txt"))).expect("read"),
+            format!("{data} part\n").as_bytes()
+        );
+    }
+}
+
+#[test]
+fn reference_extracts_parts_written_with_an_index() {
+    ensure_ref_built();
+
+    let dir = tempdir().expect("tempdir");
+    fs::create_dir_all(dir.path().join("docs")).expect("mkdir docs");
+    fs::write(dir.path().join("docs/a.txt"), b"first part\n").expect("write a");
+
+    let add = || {
+        Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+            .current_dir(dir.path())
+            .args(["add", "b??.zpaq", "docs", "--index", "b00.zpaq"])
+            .assert()
+            .success();
+    };
+    add();
+    fs::write(dir.path().join("docs/b.txt"), b"second part\n").expect("write b");
+    add();
+
+    // zpaq's own index, b00.zpaq, lists the same versions.
+    let status = StdCommand::new(ref_bin())
+        .current_dir(dir.path())
+        .args(["l", "b00.zpaq"])
+        .status()
+        .expect("run zpaq list");
+    assert!(status.success(), "zpaq list of the index failed");
+
+    let out = dir.path().join("out");
+    fs::create_dir_all(&out).expect("mkdir out");
+    let status = StdCommand::new(ref_bin())
+        .current_dir(&out)
+        .args(["x", dir.path().join("b??.zpaq").to_str().unwrap()])
+        .status()
+        .expect("run zpaq extract");
+    assert!(status.success(), "zpaq extract failed");
+    for (name, data) in [("a", "first"), ("b", "second")] {
+        assert_eq!(
+            fs::read(out.join(format!("docs/{name}.txt"))).expect("read"),
+            format!("{data} part\n").as_bytes()
+        );
+    }
+}
+
+#[test]
+fn add_cuts_fragments_where_reference_does() {
+    ensure_ref_built();
+
+    let dir = tempdir().expect("tempdir");
+    // Random bytes interleaved with a predictable cycle, so boundaries come
+    // from both the mispredicted and the predicted multipliers.
+    let mut x = 12345u32;
+    let data: Vec<u8> = (0..3_000_000u32)
+        .map(|i| {
+            x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+            if (i / 4096) % 2 == 0 {
+                (x >> 24) as u8
+            } else {
+                (i * 7 % 251) as u8
+            }
+        })
+        .collect();
+    fs::write(dir.path().join("data.bin"), &data).expect("write data");
+
+    let status = StdCommand::new(ref_bin())
+        .current_dir(dir.path())
+        .args(["a", "ref.zpaq", "data.bin", "-m0", "-t1"])
+        .status()
+        .expect("run zpaq add");
+    assert!(status.success(), "zpaq add failed");
+    Command::new(assert_cmd::cargo::cargo_bin!("zpars"))
+        .current_dir(dir.path())
+        .args(["add", "rust.zpaq", "data.bin", "--level", "0"])
+        .assert()
+        .success();
+
+    let sizes = |name: &str| {
+        let file = fs::File::open(dir.path().join(name)).expect("open archive");
+        let journal = zpars::Journal::read(file).expect("journal");
+        journal.fragments[1..]
+            .iter()
+            .map(|f| f.size)
+            .collect::<Vec<_>>()
+    };
+    let reference = sizes("ref.zpaq");
+    assert!(reference.len() > 10, "{} fragments", reference.len());
+    assert_eq!(sizes("rust.zpaq"), reference);
+}